@@ -0,0 +1,82 @@
+//! Times alpha conversion on deeply nested lambdas and long application chains, at doubling sizes.
+//! The time per node stays about the same as the size grows when conversion is linear.
+//!
+//! `cargo bench --bench alpha`
+
+use std::time::{Duration, Instant};
+
+use simply_typed_lambda_calculus_compiler::{
+    alpha::AlphaConvEnv,
+    ast::{Expr, Operator, Span, Variable},
+};
+
+fn var(name: String) -> Variable {
+    Variable {
+        name,
+        id: 0,
+        span: Span::default(),
+    }
+}
+
+/// `\x0. \x1. ... \x(n-1). x0 + x(n-1)`, where every binder has a name of its own.
+fn nested_lambdas(n: usize) -> Expr {
+    let body = Expr::BOp(
+        Operator::Add,
+        Box::new(Expr::Var(var("x0".to_owned()))),
+        Box::new(Expr::Var(var(format!("x{}", n - 1)))),
+    );
+    (0..n).rev().fold(body, |body, i| {
+        Expr::Abs(var(format!("x{}", i)), None, Box::new(body))
+    })
+}
+
+/// `\f. \x. f x x ... x` with `n` arguments.
+fn application_chain(n: usize) -> Expr {
+    let app = (0..n).fold(Expr::Var(var("f".to_owned())), |f, _| {
+        Expr::App(Box::new(f), Box::new(Expr::Var(var("x".to_owned()))))
+    });
+    let body = Expr::Abs(var("x".to_owned()), None, Box::new(app));
+    Expr::Abs(var("f".to_owned()), None, Box::new(body))
+}
+
+/// The fastest of a few runs, to leave out the noise of the others.
+fn time(expr: &Expr) -> Duration {
+    (0..5)
+        .map(|_| {
+            let expr = expr.clone();
+            let start = Instant::now();
+            let converted = AlphaConvEnv::new().alpha_conversion(expr);
+            let elapsed = start.elapsed();
+            assert!(converted.is_ok());
+            // dropping the converted term is not part of the conversion
+            drop(converted);
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn bench(name: &str, make: fn(usize) -> Expr) {
+    println!("{}", name);
+    for n in [2_500, 5_000, 10_000, 20_000] {
+        let elapsed = time(&make(n));
+        println!(
+            "  n = {:>6}: {:>10.3?} {:>8.1} ns per node",
+            n,
+            elapsed,
+            elapsed.as_nanos() as f64 / n as f64
+        );
+    }
+}
+
+fn main() {
+    // the passes recurse once per nested node
+    let thread = std::thread::Builder::new()
+        .stack_size(1 << 30)
+        .spawn(|| {
+            bench("nested lambdas", nested_lambdas);
+            bench("application chain", application_chain);
+        })
+        .unwrap();
+    thread.join().unwrap();
+}
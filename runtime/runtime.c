@@ -0,0 +1,35 @@
+/* Runtime functions called by programs compiled with the LLVM backend. The JIT uses the versions
+ * in src/runtime.rs instead, both must print the same text.
+ *
+ * Build a native executable with:
+ *   simply_typed_lambda_calculus_compiler --object program.o '...'
+ *   cc program.o runtime/runtime.c */
+#include <inttypes.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+void stlc_print_int(int64_t n) { printf("%" PRId64, n); }
+
+void stlc_print_newline(void) { putchar('\n'); }
+
+/* codes as in RuntimeError::code */
+void stlc_runtime_error(int64_t code) {
+    fflush(stdout);
+    switch (code) {
+    case 1:
+        fputs("runtime error: division by zero\n", stderr);
+        break;
+    case 2:
+        fputs("runtime error: integer overflow\n", stderr);
+        break;
+    case 3:
+        fputs("runtime error: match failure\n", stderr);
+        break;
+    case 4:
+        fputs("runtime error: uncaught exception\n", stderr);
+        break;
+    default:
+        fprintf(stderr, "runtime error: %" PRId64 "\n", code);
+    }
+    exit(2);
+}
@@ -1,89 +1,615 @@
-use std::{cell::RefCell, rc::Rc};
+use core::{fmt, mem};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
-use crate::ast::{Expr, Variable};
+use crate::{
+    ast::{Decl, Expr, Pattern, Prim, Program, Span, TypeDecl, TypeExpr, Variable},
+    stack::grow,
+};
 
-#[derive(Debug, Clone, PartialEq)]
-enum AlphaConvMap {
-    Nil,
-    Cons(String, usize, Box<AlphaConvMap>),
+/// The names in scope, each bound to a stack of ids with the innermost binding on top. Leaving a
+/// scope undoes its bindings, so binding and looking up a name take constant time however deep the
+/// scopes are nested.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct AlphaConvMap {
+    ids: HashMap<String, Vec<usize>>,
+    /// every name bound so far in binding order, to undo the bindings of a scope
+    trail: Vec<String>,
 }
 
 impl AlphaConvMap {
     fn search(&self, value: &str) -> Option<usize> {
+        self.ids.get(value)?.last().copied()
+    }
+
+    fn bind(&mut self, name: String, id: usize) {
+        self.ids.entry(name.clone()).or_default().push(id);
+        self.trail.push(name);
+    }
+
+    /// Marks the start of a scope, to `restore` when it ends.
+    fn mark(&self) -> usize {
+        self.trail.len()
+    }
+
+    fn restore(&mut self, mark: usize) {
+        for name in self.trail.drain(mark..).rev() {
+            let ids = self.ids.get_mut(&name).expect("a bound name has ids");
+            ids.pop();
+            if ids.is_empty() {
+                self.ids.remove(&name);
+            }
+        }
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.ids.keys().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlphaError {
+    /// `suggestions` are the names in scope closest to `name`, the closest first
+    Unbound {
+        name: String,
+        span: Span,
+        suggestions: Vec<String>,
+    },
+    /// a variable bound twice by the same pattern, `span` is the second binder
+    DuplicateBinder { name: String, span: Span },
+}
+
+impl AlphaError {
+    pub fn span(&self) -> Span {
+        match self {
+            AlphaError::Unbound { span, .. } | AlphaError::DuplicateBinder { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for AlphaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AlphaConvMap::Nil => None,
-            AlphaConvMap::Cons(name, id, env) => {
-                if name == value {
-                    Some(*id)
-                } else {
-                    env.search(value)
+            AlphaError::Unbound {
+                name, suggestions, ..
+            } => {
+                write!(f, "unbound variable {}", name)?;
+                for (i, suggestion) in suggestions.iter().enumerate() {
+                    match i {
+                        0 => write!(f, ", did you mean {}", suggestion)?,
+                        _ if i + 1 == suggestions.len() => write!(f, " or {}", suggestion)?,
+                        _ => write!(f, ", {}", suggestion)?,
+                    }
+                }
+                if !suggestions.is_empty() {
+                    write!(f, "?")?;
                 }
+                Ok(())
+            }
+            AlphaError::DuplicateBinder { name, .. } => {
+                write!(f, "{} is bound several times in the pattern", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AlphaError {}
+
+/// Reported only when enabled with `AlphaConvEnv::enable_warnings`. Names starting with `_` are
+/// exempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlphaWarning {
+    /// a binder hiding a local variable of the same name, top-level names may be redefined
+    Shadowed { name: String, span: Span },
+    /// a parameter of a lambda or of a function declared by `let`
+    Unused { name: String, span: Span },
+}
+
+impl AlphaWarning {
+    pub fn span(&self) -> Span {
+        match self {
+            AlphaWarning::Shadowed { span, .. } | AlphaWarning::Unused { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for AlphaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlphaWarning::Shadowed { name, .. } => {
+                write!(f, "{} shadows a variable of the same name", name)
             }
+            AlphaWarning::Unused { name, .. } => write!(f, "parameter {} is never used", name),
         }
     }
 }
 
+/// The number of single character insertions, deletions and substitutions between the strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The top-level names a module declares, in declaration order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespace {
+    /// each `let` with its id
+    pub values: Vec<(String, usize)>,
+    /// each type with the name it is declared under, qualified by the module
+    pub types: Vec<(String, String)>,
+    /// the same for constructors, exceptions included
+    pub constructors: Vec<(String, String)>,
+}
+
+/// Clones share the scopes and everything else, so a binding made through one is seen by all.
 #[derive(Debug, Clone, PartialEq)]
 pub struct AlphaConvEnv {
-    map: AlphaConvMap,
+    map: Rc<RefCell<AlphaConvMap>>,
     id: Rc<RefCell<usize>>,
+    /// the namespace of each converted module
+    modules: Rc<RefCell<HashMap<String, Namespace>>>,
+    /// the name each type in scope is declared under
+    types: Rc<RefCell<HashMap<String, String>>>,
+    /// the name each constructor in scope is declared under
+    constructors: Rc<RefCell<HashMap<String, String>>>,
+    /// the name each top-level `let` declares by its id
+    globals: Rc<RefCell<HashMap<usize, String>>>,
+    /// the top-level `let` each use of one refers to
+    instances: Rc<RefCell<HashMap<usize, usize>>>,
+    /// the ids of the variables used so far
+    used: Rc<RefCell<HashSet<usize>>>,
+    /// `None` unless warnings are enabled
+    warnings: Option<Rc<RefCell<Vec<AlphaWarning>>>>,
 }
 
 impl AlphaConvEnv {
     pub fn new() -> AlphaConvEnv {
         AlphaConvEnv {
-            map: AlphaConvMap::Nil,
+            map: Rc::new(RefCell::new(AlphaConvMap::default())),
             id: Rc::new(RefCell::new(0)),
+            modules: Rc::new(RefCell::new(HashMap::new())),
+            types: Rc::new(RefCell::new(HashMap::new())),
+            constructors: Rc::new(RefCell::new(HashMap::new())),
+            globals: Rc::new(RefCell::new(HashMap::new())),
+            instances: Rc::new(RefCell::new(HashMap::new())),
+            used: Rc::new(RefCell::new(HashSet::new())),
+            warnings: None,
+        }
+    }
+
+    /// An environment where the top-level names of a module compiled on its own are bound, as if
+    /// every program imported it. New ids start at `next_id`, after the ones the module used.
+    pub fn with_globals(next_id: usize, namespace: &Namespace) -> AlphaConvEnv {
+        let env = AlphaConvEnv::new();
+        *env.id.borrow_mut() = next_id;
+        for (name, id) in &namespace.values {
+            env.map.borrow_mut().bind(name.clone(), *id);
+            env.globals.borrow_mut().insert(*id, name.clone());
         }
+        env.types.borrow_mut().extend(namespace.types.clone());
+        env.constructors
+            .borrow_mut()
+            .extend(namespace.constructors.clone());
+        env
+    }
+
+    /// Reports shadowed binders and unused parameters from now on, see `warnings`.
+    pub fn enable_warnings(&mut self) {
+        self.warnings.get_or_insert_with(Default::default);
     }
 
     pub fn id(&self) -> usize {
         *self.id.borrow()
     }
 
+    /// Maps each use of a top-level `let` to its declaration. A use has an id of its own, so a
+    /// polymorphic function can have a different type at each one.
+    pub fn instances(&self) -> HashMap<usize, usize> {
+        self.instances.borrow().clone()
+    }
+
+    /// The names a converted module declares.
+    pub fn namespace(&self, module: &str) -> Namespace {
+        self.modules.borrow()[module].clone()
+    }
+
+    /// The warnings of every conversion so far, in the order they were found.
+    pub fn warnings(&self) -> Vec<AlphaWarning> {
+        self.warnings
+            .as_ref()
+            .map_or(Vec::new(), |warnings| warnings.borrow().clone())
+    }
+
+    fn warn(&self, warning: AlphaWarning) {
+        if let Some(warnings) = &self.warnings {
+            warnings.borrow_mut().push(warning);
+        }
+    }
+
     fn get_new_id(&self) -> usize {
         let new_id = *self.id.borrow();
         *self.id.borrow_mut() += 1;
         new_id
     }
 
-    fn add_variable(&self, var_name: String) -> AlphaConvEnv {
+    /// Binds the variable to a new id until the enclosing `scoped` ends, and returns it renamed.
+    fn add_variable(&self, var: &Variable) -> Variable {
+        if let Some(id) = self.map.borrow().search(&var.name) {
+            if !self.globals.borrow().contains_key(&id) && !var.name.starts_with('_') {
+                self.warn(AlphaWarning::Shadowed {
+                    name: var.name.clone(),
+                    span: var.span,
+                });
+            }
+        }
         let new_id = self.get_new_id();
-        AlphaConvEnv {
-            map: AlphaConvMap::Cons(var_name, new_id, Box::new(self.map.clone())),
-            id: Rc::clone(&self.id),
+        self.map.borrow_mut().bind(var.name.clone(), new_id);
+        Variable {
+            id: new_id,
+            ..var.clone()
         }
     }
 
-    pub fn alpha_conversion(&self, expr: Expr) -> Option<Expr> {
-        match expr {
-            Expr::Var(var) => {
-                let id = self.map.search(&var.name)?;
-                Some(Expr::Var(Variable { name: var.name, id }))
+    /// Runs `f` in a scope of its own, the variables it binds are unbound afterwards.
+    fn scoped<T>(&self, f: impl FnOnce() -> Result<T, AlphaError>) -> Result<T, AlphaError> {
+        let mark = self.map.borrow().mark();
+        let result = f();
+        self.map.borrow_mut().restore(mark);
+        result
+    }
+
+    /// Converts the declarations in order, each `let` is in scope of the ones after it. Imported
+    /// modules have to be converted first.
+    pub fn program_conversion(&self, program: Program) -> Result<Program, AlphaError> {
+        self.declarations_conversion(program, None)
+            .map(|(program, _)| program)
+    }
+
+    /// Converts a module like a program, and records the names it declares for the modules
+    /// importing it. Its types and constructors are qualified by `name`, so that they are distinct
+    /// from the ones other modules declare, and the importers refer to its values as `name.f`.
+    pub fn module_conversion(&self, name: &str, program: Program) -> Result<Program, AlphaError> {
+        let (program, names) = self.declarations_conversion(program, Some(name))?;
+        self.modules.borrow_mut().insert(name.to_owned(), names);
+        Ok(program)
+    }
+
+    /// The declarations are in scope of the main expression only, the modules importing this one
+    /// bind its names again.
+    fn declarations_conversion(
+        &self,
+        mut program: Program,
+        module: Option<&str>,
+    ) -> Result<(Program, Namespace), AlphaError> {
+        let types = self.types.borrow().clone();
+        let constructors = self.constructors.borrow().clone();
+        let result = self.scoped(|| {
+            let mut names = self.declare_types(&program, module);
+            let mut decls = Vec::new();
+            for decl in mem::take(&mut program.decls) {
+                let decl = match decl {
+                    Decl::Let(var, expr) => {
+                        let expr = self.alpha_conversion(expr)?;
+                        let var = self.add_variable(&var);
+                        names.values.push((var.name.clone(), var.id));
+                        self.globals.borrow_mut().insert(var.id, var.name.clone());
+                        Decl::Let(var, expr)
+                    }
+                    Decl::LetRec(var, expr) => {
+                        let var = self.add_variable(&var);
+                        names.values.push((var.name.clone(), var.id));
+                        self.globals.borrow_mut().insert(var.id, var.name.clone());
+                        let expr = self.alpha_conversion(expr)?;
+                        Decl::LetRec(var, expr)
+                    }
+                    // the values of an imported module keep their ids, qualified by its name so that
+                    // two modules may declare the same one, they are not re-exported
+                    Decl::Import(module) => {
+                        let modules = self.modules.borrow();
+                        for (name, id) in &modules[&module].values {
+                            self.map
+                                .borrow_mut()
+                                .bind(format!("{}.{}", module, name), *id);
+                        }
+                        Decl::Import(module)
+                    }
+                    Decl::Type(decl) => Decl::Type(TypeDecl {
+                        name: self.type_name(decl.name),
+                        constructors: decl
+                            .constructors
+                            .into_iter()
+                            .map(|(name, fields)| {
+                                (self.constructor_name(name), self.types_conversion(fields))
+                            })
+                            .collect(),
+                    }),
+                    Decl::Exception(name, fields) => {
+                        Decl::Exception(self.constructor_name(name), self.types_conversion(fields))
+                    }
+                };
+                decls.push(decl);
             }
-            Expr::Abs(var, expr) => {
-                let new_alpha_conv_env = self.add_variable(var.name.clone());
-                let id = new_alpha_conv_env.map.search(&var.name);
-                let expr = new_alpha_conv_env.alpha_conversion(*expr)?;
-                Some(Expr::Abs(
-                    Variable {
-                        name: var.name,
-                        id: id?,
-                    },
-                    Box::new(expr),
-                ))
+            let main = match program.main.take() {
+                Some(main) => Some(self.alpha_conversion(main)?),
+                None => None,
+            };
+            Ok((Program { decls, main }, names))
+        });
+        *self.types.borrow_mut() = types;
+        *self.constructors.borrow_mut() = constructors;
+        result
+    }
+
+    /// Brings the types and constructors of the imported modules into scope, then the ones the
+    /// program declares, which may refer to each other in any order. Returns the namespace with
+    /// the ones it declares.
+    fn declare_types(&self, program: &Program, module: Option<&str>) -> Namespace {
+        let qualified = |name: &str| match module {
+            Some(module) => format!("{}.{}", module, name),
+            None => name.to_owned(),
+        };
+        let mut names = Namespace::default();
+        for decl in &program.decls {
+            match decl {
+                Decl::Import(module) => {
+                    let modules = self.modules.borrow();
+                    let namespace = modules
+                        .get(module)
+                        .expect("imported modules are converted first");
+                    self.types.borrow_mut().extend(namespace.types.clone());
+                    self.constructors
+                        .borrow_mut()
+                        .extend(namespace.constructors.clone());
+                }
+                Decl::Type(decl) => {
+                    names.types.push((decl.name.clone(), qualified(&decl.name)));
+                    for (name, _) in &decl.constructors {
+                        names.constructors.push((name.clone(), qualified(name)));
+                    }
+                }
+                Decl::Exception(name, _) => {
+                    names.constructors.push((name.clone(), qualified(name)));
+                }
+                Decl::Let(..) | Decl::LetRec(..) => {}
             }
-            Expr::App(expr1, expr2) => {
-                let expr1 = self.alpha_conversion(*expr1)?;
-                let expr2 = self.alpha_conversion(*expr2)?;
-                Some(Expr::App(Box::new(expr1), Box::new(expr2)))
+        }
+        self.types.borrow_mut().extend(names.types.clone());
+        self.constructors
+            .borrow_mut()
+            .extend(names.constructors.clone());
+        names
+    }
+
+    /// The name the type is declared under, an unknown one is left for type inference to report.
+    fn type_name(&self, name: String) -> String {
+        self.types.borrow().get(&name).cloned().unwrap_or(name)
+    }
+
+    fn constructor_name(&self, name: String) -> String {
+        self.constructors
+            .borrow()
+            .get(&name)
+            .cloned()
+            .unwrap_or(name)
+    }
+
+    fn type_conversion(&self, ty: TypeExpr) -> TypeExpr {
+        grow(|| match ty {
+            TypeExpr::Name(name) => TypeExpr::Name(self.type_name(name)),
+            TypeExpr::Arrow(t1, t2) => TypeExpr::Arrow(
+                Box::new(self.type_conversion(*t1)),
+                Box::new(self.type_conversion(*t2)),
+            ),
+            TypeExpr::Product(ts) => TypeExpr::Product(self.types_conversion(ts)),
+            TypeExpr::Sum(t1, t2) => TypeExpr::Sum(
+                Box::new(self.type_conversion(*t1)),
+                Box::new(self.type_conversion(*t2)),
+            ),
+            TypeExpr::Ref(t) => TypeExpr::Ref(Box::new(self.type_conversion(*t))),
+            TypeExpr::Int | TypeExpr::Unit => ty,
+        })
+    }
+
+    fn types_conversion(&self, ts: Vec<TypeExpr>) -> Vec<TypeExpr> {
+        ts.into_iter().map(|t| self.type_conversion(t)).collect()
+    }
+
+    /// The names in scope within a few edits of `name`, at most three and the closest first.
+    fn suggestions(&self, name: &str) -> Vec<String> {
+        let max = (name.chars().count() / 3).max(1);
+        let map = self.map.borrow();
+        let mut candidates: Vec<_> = map
+            .names()
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|&(distance, _)| distance <= max)
+            .collect();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .take(3)
+            .map(|(_, candidate)| candidate.to_owned())
+            .collect()
+    }
+
+    pub fn alpha_conversion(&self, expr: Expr) -> Result<Expr, AlphaError> {
+        grow(|| {
+            match expr {
+                Expr::Var(var) => match self.map.borrow().search(&var.name) {
+                    // a use is named like the declaration, `f` for `m.f`
+                    Some(id) if self.globals.borrow().contains_key(&id) => {
+                        self.used.borrow_mut().insert(id);
+                        let instance = self.get_new_id();
+                        self.instances.borrow_mut().insert(instance, id);
+                        Ok(Expr::Var(Variable {
+                            id: instance,
+                            name: self.globals.borrow()[&id].clone(),
+                            ..var
+                        }))
+                    }
+                    Some(id) => {
+                        self.used.borrow_mut().insert(id);
+                        Ok(Expr::Var(Variable { id, ..var }))
+                    }
+                    None => match Prim::from_name(&var.name) {
+                        Some(prim) => Ok(Expr::Prim(prim)),
+                        None => Err(AlphaError::Unbound {
+                            suggestions: self.suggestions(&var.name),
+                            name: var.name,
+                            span: var.span,
+                        }),
+                    },
+                },
+                Expr::Abs(var, annotation, expr) => {
+                    let (var, expr) = self.scoped(|| {
+                        let var = self.add_variable(&var);
+                        Ok((var, self.alpha_conversion(*expr)?))
+                    })?;
+                    if !self.used.borrow().contains(&var.id) && !var.name.starts_with('_') {
+                        self.warn(AlphaWarning::Unused {
+                            name: var.name.clone(),
+                            span: var.span,
+                        });
+                    }
+                    let annotation = annotation.map(|t| self.type_conversion(t));
+                    Ok(Expr::Abs(var, annotation, Box::new(expr)))
+                }
+                Expr::App(expr1, expr2) => {
+                    let expr1 = self.alpha_conversion(*expr1)?;
+                    let expr2 = self.alpha_conversion(*expr2)?;
+                    Ok(Expr::App(Box::new(expr1), Box::new(expr2)))
+                }
+                Expr::BOp(op, expr1, expr2) => {
+                    let expr1 = self.alpha_conversion(*expr1)?;
+                    let expr2 = self.alpha_conversion(*expr2)?;
+                    Ok(Expr::BOp(op, Box::new(expr1), Box::new(expr2)))
+                }
+                Expr::Number(n) => Ok(Expr::Number(n)),
+                Expr::Unit => Ok(Expr::Unit),
+                Expr::Prim(prim) => Ok(Expr::Prim(prim)),
+                Expr::Seq(expr1, expr2) => {
+                    let expr1 = self.alpha_conversion(*expr1)?;
+                    let expr2 = self.alpha_conversion(*expr2)?;
+                    Ok(Expr::Seq(Box::new(expr1), Box::new(expr2)))
+                }
+                Expr::Tuple(exprs) => Ok(Expr::Tuple(
+                    exprs
+                        .into_iter()
+                        .map(|expr| self.alpha_conversion(expr))
+                        .collect::<Result<_, _>>()?,
+                )),
+                Expr::Project(expr, index) => {
+                    let expr = self.alpha_conversion(*expr)?;
+                    Ok(Expr::Project(Box::new(expr), index))
+                }
+                Expr::Inl(expr) => Ok(Expr::Inl(Box::new(self.alpha_conversion(*expr)?))),
+                Expr::Inr(expr) => Ok(Expr::Inr(Box::new(self.alpha_conversion(*expr)?))),
+                Expr::Case(expr, var1, expr1, var2, expr2) => {
+                    let expr = self.alpha_conversion(*expr)?;
+                    let (var1, expr1) = self.scoped(|| {
+                        let var1 = self.add_variable(&var1);
+                        Ok((var1, self.alpha_conversion(*expr1)?))
+                    })?;
+                    let (var2, expr2) = self.scoped(|| {
+                        let var2 = self.add_variable(&var2);
+                        Ok((var2, self.alpha_conversion(*expr2)?))
+                    })?;
+                    Ok(Expr::Case(
+                        Box::new(expr),
+                        var1,
+                        Box::new(expr1),
+                        var2,
+                        Box::new(expr2),
+                    ))
+                }
+                Expr::Try(expr, var, handler) => {
+                    let expr = self.alpha_conversion(*expr)?;
+                    let (var, handler) = self.scoped(|| {
+                        let var = self.add_variable(&var);
+                        Ok((var, self.alpha_conversion(*handler)?))
+                    })?;
+                    Ok(Expr::Try(Box::new(expr), var, Box::new(handler)))
+                }
+                Expr::Constr(name, exprs) => Ok(Expr::Constr(
+                    self.constructor_name(name),
+                    exprs
+                        .into_iter()
+                        .map(|expr| self.alpha_conversion(expr))
+                        .collect::<Result<_, _>>()?,
+                )),
+                Expr::Match(expr, arms) => {
+                    let expr = self.alpha_conversion(*expr)?;
+                    let mut new_arms = Vec::new();
+                    for (pattern, expr) in arms {
+                        new_arms.push(self.scoped(|| {
+                            let pattern = self.pattern_conversion(pattern, &mut HashSet::new())?;
+                            Ok((pattern, self.alpha_conversion(expr)?))
+                        })?);
+                    }
+                    Ok(Expr::Match(Box::new(expr), new_arms))
+                }
+                Expr::Ref(expr) => Ok(Expr::Ref(Box::new(self.alpha_conversion(*expr)?))),
+                Expr::Deref(expr) => Ok(Expr::Deref(Box::new(self.alpha_conversion(*expr)?))),
+                Expr::Raise(expr, _) => Ok(Expr::Raise(
+                    Box::new(self.alpha_conversion(*expr)?),
+                    self.get_new_id(),
+                )),
+                Expr::Assign(expr1, expr2) => {
+                    let expr1 = self.alpha_conversion(*expr1)?;
+                    let expr2 = self.alpha_conversion(*expr2)?;
+                    Ok(Expr::Assign(Box::new(expr1), Box::new(expr2)))
+                }
+                Expr::Annot(expr, annotation) => Ok(Expr::Annot(
+                    Box::new(self.alpha_conversion(*expr)?),
+                    self.type_conversion(annotation),
+                )),
             }
-            Expr::BOp(op, expr1, expr2) => {
-                let expr1 = self.alpha_conversion(*expr1)?;
-                let expr2 = self.alpha_conversion(*expr2)?;
-                Some(Expr::BOp(op, Box::new(expr1), Box::new(expr2)))
+        })
+    }
+
+    /// Binds the variables of the pattern from left to right, `bound` has the names bound so far
+    /// by the whole pattern.
+    fn pattern_conversion(
+        &self,
+        pattern: Pattern,
+        bound: &mut HashSet<String>,
+    ) -> Result<Pattern, AlphaError> {
+        match pattern {
+            Pattern::Wildcard => Ok(Pattern::Wildcard),
+            Pattern::Var(var) => {
+                if !bound.insert(var.name.clone()) {
+                    return Err(AlphaError::DuplicateBinder {
+                        name: var.name,
+                        span: var.span,
+                    });
+                }
+                Ok(Pattern::Var(self.add_variable(&var)))
             }
-            Expr::Number(n) => Some(Expr::Number(n)),
+            Pattern::Tuple(patterns) => Ok(Pattern::Tuple(
+                patterns
+                    .into_iter()
+                    .map(|pattern| self.pattern_conversion(pattern, bound))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Pattern::Constr(name, patterns) => Ok(Pattern::Constr(
+                self.constructor_name(name),
+                patterns
+                    .into_iter()
+                    .map(|pattern| self.pattern_conversion(pattern, bound))
+                    .collect::<Result<_, _>>()?,
+            )),
         }
     }
 }
@@ -1,11 +1,19 @@
-use core::fmt;
-use std::collections::HashSet;
+use core::{fmt, mem};
+use std::collections::{HashMap, HashSet};
 
-use crate::ast::{Expr, Operator, Variable};
+use crate::{
+    ast::{Decl, Expr, Operator, Pattern, Prim, Program, Span, Variable},
+    pattern::{compile_match, Access, DecisionTree, MatchWarning},
+    runtime::export_symbol,
+    stack::grow,
+    typeinfer::{DataTypes, Type},
+};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Value {
     Number(i64),
+    /// the only value of type unit, represented like the int 0
+    Unit,
     Var(Variable),
     Global(Variable),
 }
@@ -14,6 +22,7 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Number(n) => write!(f, "{}", n),
+            Value::Unit => write!(f, "()"),
             Value::Var(var) => write!(f, "{}", var),
             Value::Global(var) => write!(f, "@{}", var),
         }
@@ -27,47 +36,174 @@ pub enum ANF {
     BOp(Variable, Operator, Value, Value),
     Tuple(Variable, Vec<Value>),
     Project(Variable, Variable, usize),
+    /// Overwrites a field of a tuple and binds unit.
+    Store(Variable, Variable, usize, Value),
+    /// Calls the runtime function of a primitive, which takes `Prim::arity` arguments.
+    Prim(Variable, Prim, Vec<Value>),
+    /// Runs the branch selected by the tag and binds the value it ends with.
+    Switch(Variable, Value, Vec<ANFs>),
+    /// Aborts the program with a match failure, the variable only gives the failing branch a value
+    /// of the right type.
+    Trap(Variable),
+    /// Passes the exception to the innermost handler, the variable is never bound at runtime.
+    Raise(Variable, Value),
+    /// Runs the body and binds its value, or binds the exception it raises and runs the handler.
+    Try(Variable, ANFs, Variable, ANFs),
 }
 
-impl fmt::Display for ANF {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ANF::Fun(var, args, anfs) => {
-                write!(f, "{}(", var)?;
-                for i in 0..args.len() - 1 {
-                    write!(f, "{}, ", args[i])?;
+fn fmt_binder(
+    f: &mut fmt::Formatter<'_>,
+    var: &Variable,
+    types: Option<&HashMap<usize, Type>>,
+) -> fmt::Result {
+    write!(f, "{}", var)?;
+    if let Some(ty) = types.and_then(|types| types.get(&var.id)) {
+        write!(f, ": {}", ty)?;
+    }
+    Ok(())
+}
+
+fn fmt_params(
+    f: &mut fmt::Formatter<'_>,
+    args: &[Variable],
+    types: Option<&HashMap<usize, Type>>,
+) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, arg) in args.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        fmt_binder(f, arg, types)?;
+    }
+    write!(f, ")")
+}
+
+fn fmt_values(f: &mut fmt::Formatter<'_>, values: &[Value]) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, value) in values.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", value)?;
+    }
+    write!(f, ")")
+}
+
+impl ANF {
+    fn fmt_with(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        types: Option<&HashMap<usize, Type>>,
+    ) -> fmt::Result {
+        grow(|| {
+            match self {
+                ANF::Fun(var, args, anfs) => {
+                    write!(f, "{}", var)?;
+                    fmt_params(f, args, types)?;
+                    write!(f, " = ")?;
+                    anfs.fmt_with(f, types)?;
                 }
-                write!(f, "{}) = ", args[args.len() - 1])?;
-                write!(f, "{}", anfs)?;
-            }
-            ANF::App(var, func, args) => {
-                write!(f, "{} = {}(", var, func)?;
-                for i in 0..args.len() - 1 {
-                    write!(f, "{}, ", args[i])?;
+                ANF::App(var, func, args) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = {}", func)?;
+                    fmt_values(f, args)?;
                 }
-                write!(f, "{})", args[args.len() - 1])?;
-            }
-            ANF::BOp(var, op, val1, val2) => {
-                write!(f, "{}_{} = ", var.name, var.id)?;
-                match op {
-                    Operator::Add => write!(f, "{} + {}", val1, val2)?,
-                    Operator::Sub => write!(f, "{} - {}", val1, val2)?,
-                    Operator::Mul => write!(f, "{} * {}", val1, val2)?,
-                    Operator::Div => write!(f, "{} / {}", val1, val2)?,
+                ANF::BOp(var, op, val1, val2) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = ")?;
+                    match op {
+                        Operator::Add => write!(f, "{} + {}", val1, val2)?,
+                        Operator::Sub => write!(f, "{} - {}", val1, val2)?,
+                        Operator::Mul => write!(f, "{} * {}", val1, val2)?,
+                        Operator::Div => write!(f, "{} / {}", val1, val2)?,
+                        Operator::Mod => write!(f, "{} % {}", val1, val2)?,
+                        Operator::And => write!(f, "{} land {}", val1, val2)?,
+                        Operator::Or => write!(f, "{} lor {}", val1, val2)?,
+                        Operator::Xor => write!(f, "{} lxor {}", val1, val2)?,
+                        Operator::Shl => write!(f, "{} << {}", val1, val2)?,
+                        Operator::Shr => write!(f, "{} >> {}", val1, val2)?,
+                        Operator::UShr => write!(f, "{} >>> {}", val1, val2)?,
+                    }
                 }
-            }
-            ANF::Tuple(var, tuple) => {
-                write!(f, "{} = (", var)?;
-                for i in 0..tuple.len() - 1 {
-                    write!(f, "{}, ", tuple[i])?;
+                ANF::Tuple(var, tuple) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = ")?;
+                    fmt_values(f, tuple)?;
+                }
+                ANF::Project(var, tuple, index) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = {}[{}]", tuple, index)?;
+                }
+                ANF::Store(var, tuple, index, value) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = {}[{}] <- {}", tuple, index, value)?;
+                }
+                ANF::Prim(var, prim, args) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = {}", prim)?;
+                    fmt_values(f, args)?;
+                }
+                ANF::Switch(var, tag, branches) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = switch {}", tag)?;
+                    for (i, branch) in branches.iter().enumerate() {
+                        writeln!(f)?;
+                        for _ in 1..branch.level {
+                            write!(f, "  ")?;
+                        }
+                        write!(f, "| {} ->", i)?;
+                        if branch.anfs.is_empty() {
+                            writeln!(f)?;
+                        }
+                        branch.fmt_with(f, types)?;
+                    }
+                    writeln!(f)?;
+                    for _ in 1..branches.first().map_or(1, |branch| branch.level) {
+                        write!(f, "  ")?;
+                    }
+                    write!(f, "end")?;
+                }
+                ANF::Trap(var) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = trap")?;
+                }
+                ANF::Raise(var, value) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = raise {}", value)?;
+                }
+                ANF::Try(var, body, exn, handler) => {
+                    fmt_binder(f, var, types)?;
+                    write!(f, " = try")?;
+                    if body.anfs.is_empty() {
+                        writeln!(f)?;
+                    }
+                    body.fmt_with(f, types)?;
+                    writeln!(f)?;
+                    for _ in 1..body.level {
+                        write!(f, "  ")?;
+                    }
+                    write!(f, "with ")?;
+                    fmt_binder(f, exn, types)?;
+                    write!(f, " ->")?;
+                    if handler.anfs.is_empty() {
+                        writeln!(f)?;
+                    }
+                    handler.fmt_with(f, types)?;
+                    writeln!(f)?;
+                    for _ in 1..body.level {
+                        write!(f, "  ")?;
+                    }
+                    write!(f, "end")?;
                 }
-                write!(f, "{})", tuple[tuple.len() - 1])?;
-            }
-            ANF::Project(var, tuple, index) => {
-                write!(f, "{}_{} = {}[{}]", var.name, var.id, tuple, index)?;
             }
-        }
-        Ok(())
+            Ok(())
+        })
+    }
+}
+
+impl fmt::Display for ANF {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, None)
     }
 }
 
@@ -78,91 +214,159 @@ pub struct ANFs {
     pub level: usize,
 }
 
+fn use_var(var: &Variable, bound_vars: &mut HashSet<usize>, free_vars: &mut Vec<Variable>) {
+    // a free variable is marked as bound once recorded, so it is captured only once
+    if bound_vars.insert(var.id) {
+        free_vars.push(var.clone());
+    }
+}
+
+fn use_value(value: &Value, bound_vars: &mut HashSet<usize>, free_vars: &mut Vec<Variable>) {
+    if let Value::Var(var) = value {
+        use_var(var, bound_vars, free_vars);
+    }
+}
+
 impl ANFs {
+    /// Returns each free variable once, in order of first occurrence.
     pub fn free_vars(&self, bound_vars: &mut HashSet<usize>) -> Vec<Variable> {
         let mut free_vars = Vec::new();
-        for anf in &self.anfs {
-            match anf {
-                ANF::Fun(var, args, body) => {
-                    bound_vars.insert(var.id);
-                    for arg in args {
-                        bound_vars.insert(arg.id);
-                    }
-                    let mut body_free_vars = body.free_vars(bound_vars);
-                    free_vars.append(&mut body_free_vars);
-                }
-                ANF::App(var1, var2, args) => {
-                    bound_vars.insert(var1.id);
-                    if !bound_vars.contains(&var2.id) {
-                        free_vars.push(var2.clone());
-                    }
-                    for arg in args {
-                        match arg {
-                            Value::Var(var) => {
-                                if !bound_vars.contains(&var.id) {
-                                    free_vars.push(var.clone());
-                                }
-                            }
-                            _ => (),
+        self.collect_free_vars(bound_vars, &mut free_vars);
+        free_vars
+    }
+
+    fn collect_free_vars(&self, bound_vars: &mut HashSet<usize>, free_vars: &mut Vec<Variable>) {
+        grow(|| {
+            for anf in &self.anfs {
+                match anf {
+                    ANF::Fun(var, args, body) => {
+                        bound_vars.insert(var.id);
+                        for arg in args {
+                            bound_vars.insert(arg.id);
                         }
+                        body.collect_free_vars(bound_vars, free_vars);
                     }
-                }
-                ANF::BOp(var, _, val1, val2) => {
-                    bound_vars.insert(var.id);
-                    match val1 {
-                        Value::Var(var) => {
-                            if !bound_vars.contains(&var.id) {
-                                free_vars.push(var.clone());
-                            }
+                    ANF::App(var1, var2, args) => {
+                        bound_vars.insert(var1.id);
+                        use_var(var2, bound_vars, free_vars);
+                        for arg in args {
+                            use_value(arg, bound_vars, free_vars);
                         }
-                        _ => (),
                     }
-                    match val2 {
-                        Value::Var(var) => {
-                            if !bound_vars.contains(&var.id) {
-                                free_vars.push(var.clone());
-                            }
+                    ANF::BOp(var, _, val1, val2) => {
+                        bound_vars.insert(var.id);
+                        use_value(val1, bound_vars, free_vars);
+                        use_value(val2, bound_vars, free_vars);
+                    }
+                    ANF::Tuple(var, tuple) => {
+                        bound_vars.insert(var.id);
+                        for val in tuple {
+                            use_value(val, bound_vars, free_vars);
                         }
-                        _ => (),
                     }
-                }
-                ANF::Tuple(_, _) => unreachable!(),
-                ANF::Project(_, _, _) => unreachable!(),
-            }
-        }
-        if let Some(value) = &self.value {
-            match value {
-                Value::Var(var) => {
-                    if !bound_vars.contains(&var.id) {
-                        free_vars.push(var.clone());
+                    ANF::Project(var, tuple, _) => {
+                        bound_vars.insert(var.id);
+                        use_var(tuple, bound_vars, free_vars);
+                    }
+                    ANF::Store(var, tuple, _, value) => {
+                        bound_vars.insert(var.id);
+                        use_var(tuple, bound_vars, free_vars);
+                        use_value(value, bound_vars, free_vars);
+                    }
+                    ANF::Prim(var, _, args) => {
+                        bound_vars.insert(var.id);
+                        for arg in args {
+                            use_value(arg, bound_vars, free_vars);
+                        }
+                    }
+                    ANF::Switch(var, tag, branches) => {
+                        use_value(tag, bound_vars, free_vars);
+                        for branch in branches {
+                            branch.collect_free_vars(bound_vars, free_vars);
+                        }
+                        bound_vars.insert(var.id);
+                    }
+                    ANF::Trap(var) => {
+                        bound_vars.insert(var.id);
+                    }
+                    ANF::Raise(var, value) => {
+                        bound_vars.insert(var.id);
+                        use_value(value, bound_vars, free_vars);
+                    }
+                    ANF::Try(var, body, exn, handler) => {
+                        body.collect_free_vars(bound_vars, free_vars);
+                        bound_vars.insert(exn.id);
+                        handler.collect_free_vars(bound_vars, free_vars);
+                        bound_vars.insert(var.id);
                     }
                 }
-                _ => (),
             }
-        }
-        free_vars
+            if let Some(value) = &self.value {
+                use_value(value, bound_vars, free_vars);
+            }
+        })
     }
 }
 
-impl fmt::Display for ANFs {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.anfs.len() != 0 {
-            write!(f, "\n")?;
+impl ANFs {
+    /// The value the block ends with, unit when it has none.
+    pub fn result(&self) -> Value {
+        self.value.clone().unwrap_or(Value::Unit)
+    }
+
+    /// Whether the last binding is an application or a switch whose result is returned as is.
+    pub fn ends_in_tail_position(&self) -> bool {
+        match (self.anfs.last(), &self.value) {
+            (Some(ANF::App(var, _, _) | ANF::Switch(var, _, _)), Some(Value::Var(value))) => {
+                var == value
+            }
+            _ => false,
         }
-        for anf in &self.anfs {
+    }
+}
+
+impl ANFs {
+    fn fmt_with(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        types: Option<&HashMap<usize, Type>>,
+    ) -> fmt::Result {
+        grow(|| {
+            if !self.anfs.is_empty() {
+                writeln!(f)?;
+            }
+            for anf in &self.anfs {
+                for _ in 0..self.level {
+                    write!(f, "  ")?;
+                }
+                write!(f, "let ")?;
+                anf.fmt_with(f, types)?;
+                writeln!(f, " in")?;
+            }
             for _ in 0..self.level {
                 write!(f, "  ")?;
             }
-            write!(f, "let {} in\n", anf)?;
-        }
-        for _ in 0..self.level {
-            write!(f, "  ")?;
-        }
-        match &self.value {
-            Some(val) => write!(f, "{}", val)?,
-            None => write!(f, "return ()")?,
-        }
-        Ok(())
+            match &self.value {
+                Some(val) => write!(f, "{}", val)?,
+                None => write!(f, "return ()")?,
+            }
+            Ok(())
+        })
+    }
+}
+
+impl fmt::Display for ANFs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, None)
+    }
+}
+
+/// Displays an a-normal form with the type of every binder.
+pub struct Typed<'a>(pub &'a ANFs, pub &'a HashMap<usize, Type>);
+
+impl fmt::Display for Typed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_with(f, Some(self.1))
     }
 }
 
@@ -170,159 +374,966 @@ impl fmt::Display for ANFs {
 pub struct HoistedANFs {
     pub fun_defs: Vec<(Variable, Vec<Variable>, ANFs)>,
     pub main: ANFs,
+    pub types: HashMap<usize, Type>,
+    /// code of the top-level functions by symbol, see `runtime::export_symbol`, which the backends
+    /// export as functions taking the closure as their first argument
+    pub exports: Vec<(String, Variable)>,
+}
+
+impl HoistedANFs {
+    pub fn value_type(&self, value: &Value) -> Type {
+        value_type(&self.types, value)
+    }
+
+    /// Whether the result of the program is an int, which every backend prints on a line of its
+    /// own once the program is done. Other results have no text to agree on, so they are dropped.
+    pub fn prints_result(&self) -> bool {
+        self.value_type(&self.main.result()).simplify() == Type::Int
+    }
 }
 
 impl fmt::Display for HoistedANFs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for (var, args, body) in &self.fun_defs {
-            write!(f, "let {}(", var)?;
-            for i in 0..args.len() - 1 {
-                write!(f, "{}, ", args[i])?;
-            }
-            write!(f, "{}) ={}\n\n", args[args.len() - 1], body)?;
+            write!(f, "let {}", var)?;
+            fmt_params(f, args, Some(&self.types))?;
+            write!(f, " =")?;
+            body.fmt_with(f, Some(&self.types))?;
+            write!(f, "\n\n")?;
+        }
+        for (name, var) in &self.exports {
+            writeln!(f, "export {} = {}", name, var)?;
         }
-        write!(f, "let main() ={}", self.main)
+        if !self.exports.is_empty() {
+            writeln!(f)?;
+        }
+        write!(f, "let main() =")?;
+        self.main.fmt_with(f, Some(&self.types))
+    }
+}
+
+pub fn value_type(types: &HashMap<usize, Type>, value: &Value) -> Type {
+    match value {
+        Value::Number(_) => Type::Int,
+        Value::Unit => Type::Unit,
+        Value::Var(var) | Value::Global(var) => types[&var.id].clone(),
     }
 }
 
+/// How the body of a match arm is reached from the leaves of the decision tree.
+enum Action {
+    /// converted in place by the only leaf that uses it
+    Inline(Option<Expr>),
+    /// a function taking the variables of the pattern, shared by several leaves
+    Join(Variable, Vec<Variable>),
+}
+
+/// The parts of the scrutinee projected so far on the current path through a decision tree.
+#[derive(Clone)]
+struct Occurrences {
+    values: HashMap<Access, Value>,
+    /// types of constructor fields, which can not be read off the type of the data value
+    types: HashMap<Access, Type>,
+}
+
+struct MatchLowering {
+    actions: Vec<Action>,
+    /// binders whose type is the result type of the match, which is known after the first arm
+    pending: Vec<Variable>,
+    result_type: Option<Type>,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ANFConverter {
     pub next_var: usize,
+    pub types: HashMap<usize, Type>,
+    pub data_types: DataTypes,
+    pub warnings: Vec<MatchWarning>,
+    /// pattern variables bound directly to a part of the scrutinee, and top-level `let`s of values
+    aliases: HashMap<usize, Value>,
+    /// the top-level `let` each use of one refers to, see `AlphaConvEnv::instances`
+    pub instances: HashMap<usize, usize>,
+    /// the code of each function declared by a top-level `let`, see `specialize`
+    pub functions: HashMap<usize, ANF>,
+    /// the function each copy made by `specialize` is a copy of
+    pub origins: HashMap<usize, usize>,
+    /// the copies made so far, with the function and the type they were made for
+    specializations: Vec<(usize, Type, Variable)>,
+    /// copies to bind in front of the declaration being converted
+    copies: Vec<ANF>,
+    /// the symbol of each function declared by a top-level `let`
+    top_level: HashMap<usize, String>,
+    /// the symbol and code of each exported function
+    exports: Vec<(String, Variable)>,
 }
 
 impl ANFConverter {
-    pub fn new(next_var: usize) -> Self {
-        Self { next_var }
+    /// `env` is the type environment left by `TypeInfer`, indexed by variable id.
+    pub fn new(next_var: usize, env: &[Type], data_types: &DataTypes) -> Self {
+        Self {
+            next_var,
+            types: env
+                .iter()
+                .take(next_var)
+                .enumerate()
+                .map(|(id, t)| (id, t.simplify()))
+                .collect(),
+            data_types: data_types.clone(),
+            warnings: Vec::new(),
+            aliases: HashMap::new(),
+            instances: HashMap::new(),
+            functions: HashMap::new(),
+            origins: HashMap::new(),
+            specializations: Vec::new(),
+            copies: Vec::new(),
+            top_level: HashMap::new(),
+            exports: Vec::new(),
+        }
     }
 
-    fn fresh_var(&mut self, name: &str) -> Variable {
+    fn fresh_var(&mut self, name: &str, ty: Type) -> Variable {
         let var = Variable {
             name: name.to_owned(),
             id: self.next_var,
+            span: Span::default(),
         };
+        self.types.insert(var.id, ty);
         self.next_var += 1;
         var
     }
 
-    pub fn convert(&mut self, expr: Expr, anfs: &mut ANFs) {
-        match expr {
-            Expr::Var(var) => {
-                anfs.value = Some(Value::Var(var));
+    fn value_type(&self, value: &Value) -> Type {
+        value_type(&self.types, value)
+    }
+
+    /// Converts the `let` declarations and the main expression into one sequence. Functions keep
+    /// the name of their declaration, so closure conversion can export their code.
+    pub fn convert_program(&mut self, program: Program, anfs: &mut ANFs) {
+        self.convert_declarations(program, None, anfs)
+    }
+
+    /// Converts an imported module in front of the program, its functions are exported as
+    /// `module.name`.
+    pub fn convert_module(&mut self, name: &str, program: Program, anfs: &mut ANFs) {
+        self.convert_declarations(program, Some(name), anfs)
+    }
+
+    fn convert_declarations(
+        &mut self,
+        mut program: Program,
+        module: Option<&str>,
+        anfs: &mut ANFs,
+    ) {
+        for decl in mem::take(&mut program.decls) {
+            let start = anfs.anfs.len();
+            match decl {
+                Decl::Let(var, Expr::Abs(arg, _, body))
+                | Decl::LetRec(var, Expr::Abs(arg, _, body)) => {
+                    let symbol = match module {
+                        Some(module) => export_symbol(&format!("{}.{}", module, var.name)),
+                        None => export_symbol(&var.name),
+                    };
+                    self.top_level.insert(var.id, symbol);
+                    self.convert_abs(var.clone(), arg, *body, anfs);
+                    let fun = anfs.anfs.last().unwrap().clone();
+                    self.functions.insert(var.id, fun);
+                }
+                Decl::Let(var, expr) => {
+                    self.convert(expr, anfs);
+                    let value = anfs.value.clone().unwrap();
+                    self.aliases.insert(var.id, value);
+                }
+                Decl::LetRec(_, expr) => unreachable!("let rec of {:?}", expr),
+                Decl::Type(_) | Decl::Exception(_, _) | Decl::Import(_) => {}
             }
-            Expr::Abs(var, expr) => {
-                let f = self.fresh_var("f");
-                let mut anf = ANFs {
-                    anfs: Vec::new(),
-                    value: None,
-                    level: anfs.level + 1,
-                };
-                self.convert(*expr, &mut anf);
-                anfs.anfs.push(ANF::Fun(f.clone(), vec![var], anf));
-                anfs.value = Some(Value::Var(f));
+            self.bind_copies(start, anfs);
+        }
+        let start = anfs.anfs.len();
+        match program.main.take() {
+            Some(main) => self.convert(main, anfs),
+            None => anfs.value = Some(Value::Unit),
+        }
+        self.bind_copies(start, anfs);
+    }
+
+    /// Binds the copies made while converting a declaration in front of it, after the functions
+    /// they copy.
+    fn bind_copies(&mut self, start: usize, anfs: &mut ANFs) {
+        let copies = mem::take(&mut self.copies);
+        anfs.anfs.splice(start..start, copies);
+    }
+
+    /// Returns a copy of the top-level function `def` at `ty`, the type of one of its uses, made
+    /// the first time the function is used at that type. The backends lay out values by their
+    /// type, so a use at another type can not call the code of the function itself.
+    ///
+    /// The copy binds a fresh variable for each binder of the function, at its type with the type
+    /// variables of the function replaced. The copies the function uses are copied again at the
+    /// new types.
+    fn specialize(&mut self, def: usize, ty: Type) -> Variable {
+        let made = self
+            .specializations
+            .iter()
+            .find(|(fun, t, _)| *fun == def && *t == ty);
+        if let Some((_, _, copy)) = made {
+            return copy.clone();
+        }
+        let mut subst = HashMap::new();
+        self.types[&def].match_instance(&ty, &mut subst);
+        let fun = self.functions[&def].clone();
+        let copy = self.copy_anf(&fun, &subst, &mut HashMap::new());
+        let var = match &copy {
+            ANF::Fun(var, _, _) => var.clone(),
+            anf => unreachable!("copying {}", anf),
+        };
+        self.types.insert(var.id, ty.clone());
+        self.specializations.push((def, ty, var.clone()));
+        self.origins.insert(var.id, def);
+        self.copies.push(copy);
+        var
+    }
+
+    fn copy_binder(
+        &mut self,
+        var: &Variable,
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> Variable {
+        let ty = self.types[&var.id].substitute(subst);
+        let copy = self.fresh_var(&var.name, ty);
+        renaming.insert(var.id, copy.clone());
+        copy
+    }
+
+    fn copy_var(
+        &mut self,
+        var: &Variable,
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> Variable {
+        if let Some(copy) = renaming.get(&var.id) {
+            return copy.clone();
+        }
+        match self.origins.get(&var.id) {
+            Some(&def) => {
+                let ty = self.types[&var.id].substitute(subst);
+                self.specialize(def, ty)
+            }
+            // bound outside the function
+            None => var.clone(),
+        }
+    }
+
+    fn copy_value(
+        &mut self,
+        value: &Value,
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> Value {
+        match value {
+            Value::Var(var) => Value::Var(self.copy_var(var, subst, renaming)),
+            value => value.clone(),
+        }
+    }
+
+    fn copy_values(
+        &mut self,
+        values: &[Value],
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> Vec<Value> {
+        values
+            .iter()
+            .map(|value| self.copy_value(value, subst, renaming))
+            .collect()
+    }
+
+    fn copy_anfs(
+        &mut self,
+        anfs: &ANFs,
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> ANFs {
+        grow(|| {
+            let copies = anfs
+                .anfs
+                .iter()
+                .map(|anf| self.copy_anf(anf, subst, renaming))
+                .collect();
+            let value = anfs
+                .value
+                .as_ref()
+                .map(|value| self.copy_value(value, subst, renaming));
+            ANFs {
+                anfs: copies,
+                value,
+                level: anfs.level,
+            }
+        })
+    }
+
+    fn copy_anf(
+        &mut self,
+        anf: &ANF,
+        subst: &HashMap<usize, Type>,
+        renaming: &mut HashMap<usize, Variable>,
+    ) -> ANF {
+        match anf {
+            ANF::Fun(var, args, body) => {
+                let var = self.copy_binder(var, subst, renaming);
+                let args = args
+                    .iter()
+                    .map(|arg| self.copy_binder(arg, subst, renaming))
+                    .collect();
+                let body = self.copy_anfs(body, subst, renaming);
+                ANF::Fun(var, args, body)
+            }
+            ANF::App(var, fun, args) => {
+                let fun = self.copy_var(fun, subst, renaming);
+                let args = self.copy_values(args, subst, renaming);
+                ANF::App(self.copy_binder(var, subst, renaming), fun, args)
+            }
+            ANF::BOp(var, op, val1, val2) => {
+                let val1 = self.copy_value(val1, subst, renaming);
+                let val2 = self.copy_value(val2, subst, renaming);
+                ANF::BOp(
+                    self.copy_binder(var, subst, renaming),
+                    op.clone(),
+                    val1,
+                    val2,
+                )
+            }
+            ANF::Tuple(var, tuple) => {
+                let tuple = self.copy_values(tuple, subst, renaming);
+                ANF::Tuple(self.copy_binder(var, subst, renaming), tuple)
+            }
+            ANF::Project(var, tuple, index) => {
+                let tuple = self.copy_var(tuple, subst, renaming);
+                ANF::Project(self.copy_binder(var, subst, renaming), tuple, *index)
+            }
+            ANF::Store(var, tuple, index, value) => {
+                let tuple = self.copy_var(tuple, subst, renaming);
+                let value = self.copy_value(value, subst, renaming);
+                ANF::Store(self.copy_binder(var, subst, renaming), tuple, *index, value)
             }
-            Expr::App(expr1, expr2) => {
-                self.convert(*expr1, anfs);
-                let f = anfs.value.clone();
-                self.convert(*expr2, anfs);
-                let x = anfs.value.clone();
-                match f {
-                    Some(Value::Var(f)) => {
-                        let y = self.fresh_var("y");
-                        anfs.anfs.push(ANF::App(y.clone(), f, vec![x.unwrap()]));
-                        anfs.value = Some(Value::Var(y));
+            ANF::Prim(var, prim, args) => {
+                let args = self.copy_values(args, subst, renaming);
+                ANF::Prim(self.copy_binder(var, subst, renaming), *prim, args)
+            }
+            ANF::Switch(var, tag, branches) => {
+                let tag = self.copy_value(tag, subst, renaming);
+                let branches = branches
+                    .iter()
+                    .map(|branch| self.copy_anfs(branch, subst, renaming))
+                    .collect();
+                ANF::Switch(self.copy_binder(var, subst, renaming), tag, branches)
+            }
+            ANF::Trap(var) => ANF::Trap(self.copy_binder(var, subst, renaming)),
+            ANF::Raise(var, value) => {
+                let value = self.copy_value(value, subst, renaming);
+                ANF::Raise(self.copy_binder(var, subst, renaming), value)
+            }
+            ANF::Try(var, body, exn, handler) => {
+                let body = self.copy_anfs(body, subst, renaming);
+                let exn = self.copy_binder(exn, subst, renaming);
+                let handler = self.copy_anfs(handler, subst, renaming);
+                ANF::Try(self.copy_binder(var, subst, renaming), body, exn, handler)
+            }
+        }
+    }
+
+    pub fn convert(&mut self, expr: Expr, anfs: &mut ANFs) {
+        grow(|| {
+            match expr {
+                Expr::Var(var) => {
+                    let def = match self.instances.get(&var.id) {
+                        Some(&id) => Variable { id, ..var.clone() },
+                        None => var.clone(),
+                    };
+                    let value = self
+                        .aliases
+                        .get(&def.id)
+                        .cloned()
+                        .unwrap_or(Value::Var(def));
+                    anfs.value = Some(match value {
+                        // a polymorphic function used at another type, see `specialize`
+                        Value::Var(fun) if self.types[&fun.id] != self.types[&var.id] => {
+                            Value::Var(self.specialize(fun.id, self.types[&var.id].clone()))
+                        }
+                        value => value,
+                    });
+                }
+                Expr::Abs(var, _, expr) => {
+                    // the type is filled in once the body has been converted
+                    let f = self.fresh_var("f", Type::Int);
+                    self.convert_abs(f, var, *expr, anfs);
+                }
+                Expr::App(expr1, expr2) => {
+                    let expr1 = match *expr1 {
+                        Expr::Prim(prim) => return self.convert_prim_app(prim, *expr2, anfs),
+                        expr1 => expr1,
+                    };
+                    self.convert(expr1, anfs);
+                    let f = anfs.value.clone();
+                    self.convert(*expr2, anfs);
+                    let x = anfs.value.clone();
+                    match f {
+                        Some(Value::Var(f)) => {
+                            let ty = match self.types[&f.id].simplify() {
+                                Type::Arrow(_, ret) => *ret,
+                                t => unreachable!("applying a value of type {}", t),
+                            };
+                            let y = self.fresh_var("y", ty);
+                            anfs.anfs.push(ANF::App(y.clone(), f, vec![x.unwrap()]));
+                            anfs.value = Some(Value::Var(y));
+                        }
+                        _ => panic!("Must be named value!"),
+                    }
+                }
+                Expr::Number(n) => {
+                    anfs.value = Some(Value::Number(n));
+                }
+                Expr::Unit => {
+                    anfs.value = Some(Value::Unit);
+                }
+                // a primitive that is not applied directly is wrapped in a function
+                Expr::Prim(prim) => {
+                    let (arg_type, ret_type) = match Type::of_prim(prim) {
+                        Type::Arrow(arg, ret) => (*arg, *ret),
+                        t => unreachable!("primitive of type {}", t),
+                    };
+                    let f = self.fresh_var("f", Type::of_prim(prim));
+                    let x = self.fresh_var("x", arg_type);
+                    let y = self.fresh_var("y", ret_type);
+                    let args = vec![Value::Var(x.clone()); prim.arity()];
+                    let body = ANFs {
+                        anfs: vec![ANF::Prim(y.clone(), prim, args)],
+                        value: Some(Value::Var(y)),
+                        level: anfs.level + 1,
+                    };
+                    anfs.anfs.push(ANF::Fun(f.clone(), vec![x], body));
+                    anfs.value = Some(Value::Var(f));
+                }
+                // the value of the first expression is dropped, its bindings stay
+                Expr::Seq(expr1, expr2) => {
+                    self.convert(*expr1, anfs);
+                    self.convert(*expr2, anfs);
+                }
+                Expr::BOp(op, expr1, expr2) => {
+                    self.convert(*expr1, anfs);
+                    let x = anfs.value.clone();
+                    self.convert(*expr2, anfs);
+                    let y = anfs.value.clone();
+                    let z = self.fresh_var("z", Type::Int);
+                    anfs.anfs
+                        .push(ANF::BOp(z.clone(), op, x.unwrap(), y.unwrap()));
+                    anfs.value = Some(Value::Var(z));
+                }
+                Expr::Tuple(exprs) => {
+                    let mut tuple = Vec::new();
+                    for expr in exprs {
+                        self.convert(expr, anfs);
+                        tuple.push(anfs.value.clone().unwrap());
+                    }
+                    let ty = Type::Product(tuple.iter().map(|val| self.value_type(val)).collect());
+                    let t = self.fresh_var("t", ty);
+                    anfs.anfs.push(ANF::Tuple(t.clone(), tuple));
+                    anfs.value = Some(Value::Var(t));
+                }
+                Expr::Project(expr, index) => {
+                    self.convert(*expr, anfs);
+                    match anfs.value.clone() {
+                        Some(Value::Var(tuple)) => {
+                            let ty = match self.types[&tuple.id].simplify() {
+                                Type::Product(ts) => ts[index].clone(),
+                                t => unreachable!("projecting a value of type {}", t),
+                            };
+                            let p = self.fresh_var("p", ty);
+                            anfs.anfs.push(ANF::Project(p.clone(), tuple, index));
+                            anfs.value = Some(Value::Var(p));
+                        }
+                        _ => panic!("Must be named value!"),
+                    }
+                }
+                Expr::Inl(expr) => self.convert_injection(0, *expr, anfs),
+                Expr::Inr(expr) => self.convert_injection(1, *expr, anfs),
+                Expr::Case(expr, var1, expr1, var2, expr2) => {
+                    self.convert(*expr, anfs);
+                    let sum = match anfs.value.clone() {
+                        Some(Value::Var(sum)) => sum,
+                        _ => panic!("Must be named value!"),
+                    };
+                    let tag = self.fresh_var("tag", Type::Int);
+                    anfs.anfs.push(ANF::Project(tag.clone(), sum.clone(), 0));
+                    let mut branches = Vec::new();
+                    for (var, expr) in [(var1, expr1), (var2, expr2)] {
+                        let mut branch = ANFs {
+                            anfs: vec![ANF::Project(var, sum.clone(), 1)],
+                            value: None,
+                            level: anfs.level + 1,
+                        };
+                        self.convert(*expr, &mut branch);
+                        branches.push(branch);
+                    }
+                    let ty = self.value_type(branches[0].value.as_ref().unwrap());
+                    let r = self.fresh_var("r", ty);
+                    anfs.anfs
+                        .push(ANF::Switch(r.clone(), Value::Var(tag), branches));
+                    anfs.value = Some(Value::Var(r));
+                }
+                // a cell is a tuple with a single field
+                Expr::Ref(expr) => {
+                    self.convert(*expr, anfs);
+                    let value = anfs.value.clone().unwrap();
+                    let ty = Type::Ref(Box::new(self.value_type(&value)));
+                    let cell = self.fresh_var("cell", ty);
+                    anfs.anfs.push(ANF::Tuple(cell.clone(), vec![value]));
+                    anfs.value = Some(Value::Var(cell));
+                }
+                Expr::Deref(expr) => {
+                    let cell = self.convert_cell(*expr, anfs);
+                    let ty = match self.types[&cell.id].simplify() {
+                        Type::Ref(t) => *t,
+                        t => unreachable!("dereferencing a value of type {}", t),
+                    };
+                    let p = self.fresh_var("p", ty);
+                    anfs.anfs.push(ANF::Project(p.clone(), cell, 0));
+                    anfs.value = Some(Value::Var(p));
+                }
+                Expr::Raise(expr, id) => {
+                    let ty = self.types[&id].clone();
+                    self.convert(*expr, anfs);
+                    let value = anfs.value.clone().unwrap();
+                    let r = self.fresh_var("r", ty);
+                    anfs.anfs.push(ANF::Raise(r.clone(), value));
+                    anfs.value = Some(Value::Var(r));
+                }
+                Expr::Try(expr, var, handler) => {
+                    let mut body = ANFs {
+                        anfs: Vec::new(),
+                        value: None,
+                        level: anfs.level + 1,
+                    };
+                    self.convert(*expr, &mut body);
+                    let mut handler_anfs = ANFs {
+                        anfs: Vec::new(),
+                        value: None,
+                        level: anfs.level + 1,
+                    };
+                    self.convert(*handler, &mut handler_anfs);
+                    let ty = self.value_type(body.value.as_ref().unwrap());
+                    let r = self.fresh_var("r", ty);
+                    anfs.anfs.push(ANF::Try(r.clone(), body, var, handler_anfs));
+                    anfs.value = Some(Value::Var(r));
+                }
+                Expr::Assign(expr1, expr2) => {
+                    let cell = self.convert_cell(*expr1, anfs);
+                    self.convert(*expr2, anfs);
+                    let value = anfs.value.clone().unwrap();
+                    let u = self.fresh_var("u", Type::Unit);
+                    anfs.anfs.push(ANF::Store(u.clone(), cell, 0, value));
+                    anfs.value = Some(Value::Var(u));
+                }
+                // annotations only matter to type inference
+                Expr::Annot(expr, _) => self.convert(*expr, anfs),
+                Expr::Constr(name, exprs) => {
+                    let constructor = self.data_types.constructor(&name).unwrap().clone();
+                    let mut fields = vec![Value::Number(constructor.tag as i64)];
+                    for expr in exprs {
+                        self.convert(expr, anfs);
+                        fields.push(anfs.value.clone().unwrap());
+                    }
+                    let c = self.fresh_var(
+                        &name.to_lowercase(),
+                        Type::Data(constructor.data_type.clone()),
+                    );
+                    anfs.anfs.push(ANF::Tuple(c.clone(), fields));
+                    anfs.value = Some(Value::Var(c));
+                }
+                Expr::Match(expr, arms) => {
+                    self.convert(*expr, anfs);
+                    let scrutinee = anfs.value.clone().unwrap();
+                    let patterns = arms
+                        .iter()
+                        .map(|(pattern, _)| pattern.clone())
+                        .collect::<Vec<_>>();
+                    let (tree, warnings) = compile_match(&self.data_types, &patterns);
+                    self.warnings.extend(warnings);
+                    let mut uses = vec![0; arms.len()];
+                    self.count_leaves(&tree, &mut uses);
+                    let mut lowering = MatchLowering {
+                        actions: Vec::new(),
+                        pending: Vec::new(),
+                        result_type: None,
+                    };
+                    for ((pattern, expr), uses) in arms.into_iter().zip(uses) {
+                        let action = if uses > 1 {
+                            self.convert_join_point(pattern, expr, &mut lowering, anfs)
+                        } else {
+                            Action::Inline(Some(expr))
+                        };
+                        lowering.actions.push(action);
+                    }
+                    let mut occurrences = Occurrences {
+                        values: HashMap::from([(Vec::new(), scrutinee)]),
+                        types: HashMap::new(),
+                    };
+                    self.lower_tree(&tree, &mut lowering, &mut occurrences, anfs);
+                    let result_type = lowering.result_type.unwrap();
+                    for var in lowering.pending {
+                        self.types.insert(var.id, result_type.clone());
+                    }
+                }
+            }
+        })
+    }
+
+    /// Counts how often the lowered tree reaches each arm, a default is copied into every
+    /// constructor it stands for.
+    fn count_leaves(&self, tree: &DecisionTree, uses: &mut [usize]) {
+        match tree {
+            DecisionTree::Fail => (),
+            DecisionTree::Leaf(action, _) => uses[*action] += 1,
+            DecisionTree::Switch(_, data_type, cases, default) => {
+                for constructor in self.data_types.constructors(data_type) {
+                    match cases.iter().find(|(tag, _)| *tag == constructor.tag) {
+                        Some((_, tree)) => self.count_leaves(tree, uses),
+                        None => self.count_leaves(default.as_ref().unwrap(), uses),
                     }
-                    _ => panic!("Must be named value!"),
                 }
             }
-            Expr::Number(n) => {
-                anfs.value = Some(Value::Number(n));
+        }
+    }
+
+    /// Converts an arm reached from several leaves into a function of the variables of its
+    /// pattern, packed into a tuple when there is more than one.
+    fn convert_join_point(
+        &mut self,
+        pattern: Pattern,
+        expr: Expr,
+        lowering: &mut MatchLowering,
+        anfs: &mut ANFs,
+    ) -> Action {
+        let vars = pattern.variables();
+        let mut body = ANFs {
+            anfs: Vec::new(),
+            value: None,
+            level: anfs.level + 1,
+        };
+        let param = match vars.as_slice() {
+            [] => self.fresh_var("u", Type::Int),
+            [var] => var.clone(),
+            _ => {
+                let ty =
+                    Type::Product(vars.iter().map(|var| self.types[&var.id].clone()).collect());
+                let param = self.fresh_var("args", ty);
+                for (i, var) in vars.iter().enumerate() {
+                    body.anfs.push(ANF::Project(var.clone(), param.clone(), i));
+                }
+                param
             }
-            Expr::BOp(op, expr1, expr2) => {
-                self.convert(*expr1, anfs);
-                let x = anfs.value.clone();
-                self.convert(*expr2, anfs);
-                let y = anfs.value.clone();
-                let z = self.fresh_var("z");
+        };
+        self.convert(expr, &mut body);
+        let ret = self.value_type(body.value.as_ref().unwrap());
+        lowering.result_type.get_or_insert(ret.clone());
+        let ty = Type::Arrow(Box::new(self.types[&param.id].clone()), Box::new(ret));
+        let k = self.fresh_var("k", ty);
+        anfs.anfs.push(ANF::Fun(k.clone(), vec![param], body));
+        Action::Join(k, vars)
+    }
+
+    /// Returns the part of the scrutinee at `access`, projecting it if this path has not yet.
+    fn access(
+        &mut self,
+        access: &[usize],
+        occurrences: &mut Occurrences,
+        anfs: &mut ANFs,
+    ) -> Value {
+        if let Some(value) = occurrences.values.get(access) {
+            return value.clone();
+        }
+        let (index, parent) = access.split_last().unwrap();
+        let parent = match self.access(parent, occurrences, anfs) {
+            Value::Var(parent) => parent,
+            _ => panic!("Must be named value!"),
+        };
+        let ty = match occurrences.types.get(access) {
+            Some(ty) => ty.clone(),
+            None => match self.types[&parent.id].simplify() {
+                Type::Product(ts) => ts[*index].clone(),
+                t => unreachable!("projecting a value of type {}", t),
+            },
+        };
+        let o = self.fresh_var("o", ty);
+        anfs.anfs.push(ANF::Project(o.clone(), parent, *index));
+        occurrences
+            .values
+            .insert(access.to_vec(), Value::Var(o.clone()));
+        Value::Var(o)
+    }
+
+    fn lower_tree(
+        &mut self,
+        tree: &DecisionTree,
+        lowering: &mut MatchLowering,
+        occurrences: &mut Occurrences,
+        anfs: &mut ANFs,
+    ) {
+        match tree {
+            DecisionTree::Fail => {
+                let r = self.fresh_var("r", Type::Int);
+                lowering.pending.push(r.clone());
+                anfs.anfs.push(ANF::Trap(r.clone()));
+                anfs.value = Some(Value::Var(r));
+            }
+            DecisionTree::Leaf(action, bindings) => match &mut lowering.actions[*action] {
+                Action::Inline(expr) => {
+                    for (var, access) in bindings {
+                        let value = self.access(access, occurrences, anfs);
+                        self.aliases.insert(var.id, value);
+                    }
+                    self.convert(expr.take().unwrap(), anfs);
+                    let ty = self.value_type(anfs.value.as_ref().unwrap());
+                    lowering.result_type.get_or_insert(ty);
+                }
+                Action::Join(k, vars) => {
+                    let (k, vars) = (k.clone(), vars.clone());
+                    let mut args = Vec::new();
+                    for var in &vars {
+                        let (_, access) = bindings.iter().find(|(bound, _)| bound == var).unwrap();
+                        args.push(self.access(access, occurrences, anfs));
+                    }
+                    let arg = match args.len() {
+                        0 => Value::Number(0),
+                        1 => args.pop().unwrap(),
+                        _ => {
+                            let ty = Type::Product(
+                                args.iter().map(|arg| self.value_type(arg)).collect(),
+                            );
+                            let t = self.fresh_var("t", ty);
+                            anfs.anfs.push(ANF::Tuple(t.clone(), args));
+                            Value::Var(t)
+                        }
+                    };
+                    let ty = match self.types[&k.id].simplify() {
+                        Type::Arrow(_, ret) => *ret,
+                        t => unreachable!("applying a value of type {}", t),
+                    };
+                    let y = self.fresh_var("y", ty);
+                    anfs.anfs.push(ANF::App(y.clone(), k, vec![arg]));
+                    anfs.value = Some(Value::Var(y));
+                }
+            },
+            DecisionTree::Switch(access, data_type, cases, default) => {
+                let scrutinee = match self.access(access, occurrences, anfs) {
+                    Value::Var(scrutinee) => scrutinee,
+                    _ => panic!("Must be named value!"),
+                };
+                let tag = self.fresh_var("tag", Type::Int);
+                anfs.anfs.push(ANF::Project(tag.clone(), scrutinee, 0));
+                let mut branches = Vec::new();
+                for constructor in self.data_types.constructors(data_type).to_vec() {
+                    let tree = match cases.iter().find(|(tag, _)| *tag == constructor.tag) {
+                        Some((_, tree)) => tree,
+                        None => default.as_ref().unwrap(),
+                    };
+                    let mut occurrences = occurrences.clone();
+                    for (i, ty) in constructor.fields.into_iter().enumerate() {
+                        let mut field = access.clone();
+                        field.push(i + 1);
+                        occurrences.types.insert(field, ty);
+                    }
+                    let mut branch = ANFs {
+                        anfs: Vec::new(),
+                        value: None,
+                        level: anfs.level + 1,
+                    };
+                    self.lower_tree(tree, lowering, &mut occurrences, &mut branch);
+                    branches.push(branch);
+                }
+                let r = self.fresh_var("r", Type::Int);
+                lowering.pending.push(r.clone());
                 anfs.anfs
-                    .push(ANF::BOp(z.clone(), op, x.unwrap(), y.unwrap()));
-                anfs.value = Some(Value::Var(z));
+                    .push(ANF::Switch(r.clone(), Value::Var(tag), branches));
+                anfs.value = Some(Value::Var(r));
             }
         }
     }
 
-    pub fn closure_conversion(&mut self, anfs: ANFs) -> ANFs {
-        let mut new_anfs = ANFs {
+    /// Binds the function `\var. body` to `f`.
+    fn convert_abs(&mut self, f: Variable, var: Variable, body: Expr, anfs: &mut ANFs) {
+        let mut anf = ANFs {
             anfs: Vec::new(),
             value: None,
-            level: anfs.level,
+            level: anfs.level + 1,
+        };
+        self.convert(body, &mut anf);
+        let ty = Type::Arrow(
+            Box::new(self.types[&var.id].clone()),
+            Box::new(self.value_type(anf.value.as_ref().unwrap())),
+        );
+        self.types.insert(f.id, ty);
+        anfs.anfs.push(ANF::Fun(f.clone(), vec![var], anf));
+        anfs.value = Some(Value::Var(f));
+    }
+
+    fn convert_cell(&mut self, expr: Expr, anfs: &mut ANFs) -> Variable {
+        self.convert(expr, anfs);
+        match anfs.value.clone() {
+            Some(Value::Var(cell)) => cell,
+            _ => panic!("Must be named value!"),
+        }
+    }
+
+    /// Calls the runtime directly instead of going through the function wrapping the primitive.
+    fn convert_prim_app(&mut self, prim: Prim, expr: Expr, anfs: &mut ANFs) {
+        self.convert(expr, anfs);
+        let args = match prim.arity() {
+            0 => Vec::new(),
+            _ => vec![anfs.value.clone().unwrap()],
         };
-        for anf in anfs.anfs {
-            match anf {
-                ANF::Fun(var, args, funbody_anfs) => {
-                    let env_var = self.fresh_var("env");
-                    let new_funname = self.fresh_var(&var.name);
-                    let free_vars =
-                        funbody_anfs.free_vars(&mut args.iter().map(|x| x.id).collect());
-                    let mut funbody_anfs = self.closure_conversion(funbody_anfs);
-                    for i in 0..free_vars.len() {
-                        funbody_anfs.anfs.insert(
-                            0,
-                            ANF::Project(free_vars[i].clone(), env_var.clone(), i + 1),
+        let ret_type = match Type::of_prim(prim) {
+            Type::Arrow(_, ret) => *ret,
+            t => unreachable!("primitive of type {}", t),
+        };
+        let y = self.fresh_var("y", ret_type);
+        anfs.anfs.push(ANF::Prim(y.clone(), prim, args));
+        anfs.value = Some(Value::Var(y));
+    }
+
+    /// A sum is a heap allocated tuple of its tag and payload.
+    fn convert_injection(&mut self, tag: i64, expr: Expr, anfs: &mut ANFs) {
+        self.convert(expr, anfs);
+        let payload = anfs.value.clone().unwrap();
+        let ty = Type::Product(vec![Type::Int, self.value_type(&payload)]);
+        let s = self.fresh_var("s", ty);
+        anfs.anfs
+            .push(ANF::Tuple(s.clone(), vec![Value::Number(tag), payload]));
+        anfs.value = Some(Value::Var(s));
+    }
+
+    pub fn closure_conversion(&mut self, anfs: ANFs) -> ANFs {
+        grow(|| {
+            let mut new_anfs = ANFs {
+                anfs: Vec::new(),
+                value: None,
+                level: anfs.level,
+            };
+            for anf in anfs.anfs {
+                match anf {
+                    ANF::Fun(var, args, funbody_anfs) => {
+                        let mut free_vars =
+                            funbody_anfs.free_vars(&mut args.iter().map(|x| x.id).collect());
+                        // a recursive function finds itself in its environment, which is its closure
+                        let recursive = free_vars.iter().any(|x| x.id == var.id);
+                        free_vars.retain(|x| x.id != var.id);
+                        let code_type = Type::Code(
+                            args.iter().map(|arg| self.types[&arg.id].clone()).collect(),
+                            Box::new(self.value_type(funbody_anfs.value.as_ref().unwrap())),
                         );
+                        let mut env_layout = vec![code_type.clone()];
+                        env_layout.extend(free_vars.iter().map(|x| self.types[&x.id].clone()));
+                        let env_var = if recursive {
+                            var.clone()
+                        } else {
+                            self.fresh_var("env", Type::Product(env_layout))
+                        };
+                        let new_funname = self.fresh_var(&var.name, code_type);
+                        if let Some(symbol) = self.top_level.get(&var.id) {
+                            // a later declaration of the same name shadows the earlier one,
+                            // names that differ have different symbols
+                            self.exports.retain(|(name, _)| name != symbol);
+                            self.exports.push((symbol.clone(), new_funname.clone()));
+                        }
+                        let mut funbody_anfs = self.closure_conversion(funbody_anfs);
+                        for i in 0..free_vars.len() {
+                            funbody_anfs.anfs.insert(
+                                0,
+                                ANF::Project(free_vars[i].clone(), env_var.clone(), i + 1),
+                            );
+                        }
+                        let mut new_args = args;
+                        new_args.insert(0, env_var);
+                        new_anfs
+                            .anfs
+                            .push(ANF::Fun(new_funname.clone(), new_args, funbody_anfs));
+                        let mut free_vars: Vec<Value> =
+                            free_vars.into_iter().map(|x| Value::Var(x)).collect();
+                        free_vars.insert(0, Value::Global(new_funname));
+                        new_anfs.anfs.push(ANF::Tuple(var, free_vars))
+                    }
+                    ANF::App(var, func_var, args) => {
+                        let code_type = match self.types[&func_var.id].simplify() {
+                            Type::Arrow(arg, ret) => Type::Code(vec![*arg], ret),
+                            t => unreachable!("applying a value of type {}", t),
+                        };
+                        let ptr = self.fresh_var(&func_var.name, code_type);
+                        new_anfs
+                            .anfs
+                            .push(ANF::Project(ptr.clone(), func_var.clone(), 0));
+                        let mut new_args = args;
+                        new_args.insert(0, Value::Var(func_var));
+                        new_anfs.anfs.push(ANF::App(var, ptr, new_args))
+                    }
+                    ANF::Switch(var, tag, branches) => {
+                        let branches = branches
+                            .into_iter()
+                            .map(|branch| self.closure_conversion(branch))
+                            .collect();
+                        new_anfs.anfs.push(ANF::Switch(var, tag, branches))
                     }
-                    let mut new_args = args;
-                    new_args.insert(0, env_var);
-                    new_anfs
-                        .anfs
-                        .push(ANF::Fun(new_funname.clone(), new_args, funbody_anfs));
-                    let mut free_vars: Vec<Value> =
-                        free_vars.into_iter().map(|x| Value::Var(x)).collect();
-                    free_vars.insert(0, Value::Global(new_funname));
-                    new_anfs.anfs.push(ANF::Tuple(var, free_vars))
-                }
-                ANF::App(var, func_var, args) => {
-                    let ptr = self.fresh_var(&func_var.name);
-                    new_anfs
-                        .anfs
-                        .push(ANF::Project(ptr.clone(), func_var.clone(), 0));
-                    let mut new_args = args;
-                    new_args.insert(0, Value::Var(func_var));
-                    new_anfs.anfs.push(ANF::App(var, ptr, new_args))
-                }
-                _ => new_anfs.anfs.push(anf),
+                    ANF::Try(var, body, exn, handler) => {
+                        let body = self.closure_conversion(body);
+                        let handler = self.closure_conversion(handler);
+                        new_anfs.anfs.push(ANF::Try(var, body, exn, handler))
+                    }
+                    _ => new_anfs.anfs.push(anf),
+                }
             }
-        }
-        new_anfs.value = anfs.value;
-        new_anfs
+            new_anfs.value = anfs.value;
+            new_anfs
+        })
     }
 
     pub fn hoisting(&mut self, anfs: ANFs, hoisted_anfs: &mut HoistedANFs) {
-        for anf in anfs.anfs {
-            match anf {
-                ANF::Fun(var, args, body) => {
-                    self.hoisting(body.clone(), hoisted_anfs);
-                    hoisted_anfs.fun_defs.push((
-                        var,
-                        args,
-                        ANFs {
-                            anfs: body
-                                .anfs
-                                .into_iter()
-                                .filter(|anf| !matches!(anf, ANF::Fun(_, _, _)))
-                                .collect(),
-                            value: body.value,
-                            level: 1,
-                        },
-                    ));
-                }
-                _ if anfs.level == 0 => {
-                    hoisted_anfs.main.anfs.push(anf);
-                }
-                _ => continue,
+        let main = self.hoist_funs(anfs, hoisted_anfs.main.level, hoisted_anfs);
+        hoisted_anfs.main.anfs = main.anfs;
+        hoisted_anfs.main.value = main.value;
+        hoisted_anfs.types = self.types.clone();
+        hoisted_anfs.exports = self.exports.clone();
+    }
+
+    /// Moves every function definition, including those inside switch branches and handlers, to the
+    /// top level.
+    fn hoist_funs(&mut self, anfs: ANFs, level: usize, hoisted_anfs: &mut HoistedANFs) -> ANFs {
+        grow(|| {
+            let mut new_anfs = ANFs {
+                anfs: Vec::new(),
+                value: anfs.value,
+                level,
+            };
+            for anf in anfs.anfs {
+                match anf {
+                    ANF::Fun(var, args, body) => {
+                        let body = self.hoist_funs(body, 1, hoisted_anfs);
+                        hoisted_anfs.fun_defs.push((var, args, body));
+                    }
+                    ANF::Switch(var, tag, branches) => {
+                        let branches = branches
+                            .into_iter()
+                            .map(|branch| self.hoist_funs(branch, level + 1, hoisted_anfs))
+                            .collect();
+                        new_anfs.anfs.push(ANF::Switch(var, tag, branches));
+                    }
+                    ANF::Try(var, body, exn, handler) => {
+                        let body = self.hoist_funs(body, level + 1, hoisted_anfs);
+                        let handler = self.hoist_funs(handler, level + 1, hoisted_anfs);
+                        new_anfs.anfs.push(ANF::Try(var, body, exn, handler));
+                    }
+                    _ => new_anfs.anfs.push(anf),
+                }
             }
-        }
-        if anfs.level == 0 {
-            hoisted_anfs.main.value = anfs.value;
-        }
+            new_anfs
+        })
     }
 }
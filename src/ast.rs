@@ -1,12 +1,370 @@
-use core::fmt;
+use core::{
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+use crate::stack::grow;
+
+/// Dropping a deeply nested expression recurses once per level, unless it is part of a `Program`,
+/// whose `Drop` takes it apart iteratively. `Expr` can not implement `Drop` itself, since the
+/// passes take expressions apart by moving out of their variants.
+#[derive(Eq)]
 pub enum Expr {
     Var(Variable),
-    Abs(Variable, Box<Expr>),
+    /// The binder may be annotated with its type.
+    Abs(Variable, Option<TypeExpr>, Box<Expr>),
     App(Box<Expr>, Box<Expr>),
     Number(i64),
+    Unit,
+    /// A primitive referred to by a name that is not bound by the program.
+    Prim(Prim),
+    /// Evaluates the first expression only for its effects.
+    Seq(Box<Expr>, Box<Expr>),
     BOp(Operator, Box<Expr>, Box<Expr>),
+    Tuple(Vec<Expr>),
+    Project(Box<Expr>, usize),
+    Inl(Box<Expr>),
+    Inr(Box<Expr>),
+    Case(Box<Expr>, Variable, Box<Expr>, Variable, Box<Expr>),
+    /// A constructor of a declared data type applied to all of its fields.
+    Constr(String, Vec<Expr>),
+    Match(Box<Expr>, Vec<(Pattern, Expr)>),
+    Annot(Box<Expr>, TypeExpr),
+    /// Allocates a mutable cell holding the value.
+    Ref(Box<Expr>),
+    Deref(Box<Expr>),
+    Assign(Box<Expr>, Box<Expr>),
+    /// The id is given by alpha conversion, the type the `raise` takes from its context is
+    /// recorded under it like the type of a variable.
+    Raise(Box<Expr>, usize),
+    /// `try e with x -> h`
+    Try(Box<Expr>, Variable, Box<Expr>),
+}
+
+impl Expr {
+    /// Moves the subexpressions right below `self` to `exprs`, leaving units in their place.
+    fn take_children(&mut self, exprs: &mut Vec<Expr>) {
+        let mut take = |expr: &mut Box<Expr>| exprs.push(mem::replace(&mut **expr, Expr::Unit));
+        match self {
+            Expr::Var(_) | Expr::Number(_) | Expr::Unit | Expr::Prim(_) => {}
+            Expr::Abs(_, _, e)
+            | Expr::Project(e, _)
+            | Expr::Inl(e)
+            | Expr::Inr(e)
+            | Expr::Annot(e, _)
+            | Expr::Ref(e)
+            | Expr::Deref(e)
+            | Expr::Raise(e, _) => take(e),
+            Expr::App(e1, e2)
+            | Expr::Seq(e1, e2)
+            | Expr::BOp(_, e1, e2)
+            | Expr::Assign(e1, e2)
+            | Expr::Try(e1, _, e2) => {
+                take(e1);
+                take(e2);
+            }
+            Expr::Case(e, _, e1, _, e2) => {
+                take(e);
+                take(e1);
+                take(e2);
+            }
+            Expr::Tuple(es) | Expr::Constr(_, es) => exprs.append(es),
+            Expr::Match(e, arms) => {
+                take(e);
+                exprs.extend(arms.drain(..).map(|(_, e)| e));
+            }
+        }
+    }
+}
+
+// the derived implementations would recurse without `grow` on deeply nested expressions
+impl Clone for Expr {
+    fn clone(&self) -> Self {
+        grow(|| match self {
+            Expr::Var(var) => Expr::Var(var.clone()),
+            Expr::Abs(var, t, e) => Expr::Abs(var.clone(), t.clone(), e.clone()),
+            Expr::App(e1, e2) => Expr::App(e1.clone(), e2.clone()),
+            Expr::Number(n) => Expr::Number(*n),
+            Expr::Unit => Expr::Unit,
+            Expr::Prim(prim) => Expr::Prim(*prim),
+            Expr::Seq(e1, e2) => Expr::Seq(e1.clone(), e2.clone()),
+            Expr::BOp(op, e1, e2) => Expr::BOp(op.clone(), e1.clone(), e2.clone()),
+            Expr::Tuple(es) => Expr::Tuple(es.clone()),
+            Expr::Project(e, index) => Expr::Project(e.clone(), *index),
+            Expr::Inl(e) => Expr::Inl(e.clone()),
+            Expr::Inr(e) => Expr::Inr(e.clone()),
+            Expr::Case(e, var1, e1, var2, e2) => Expr::Case(
+                e.clone(),
+                var1.clone(),
+                e1.clone(),
+                var2.clone(),
+                e2.clone(),
+            ),
+            Expr::Constr(name, es) => Expr::Constr(name.clone(), es.clone()),
+            Expr::Match(e, arms) => Expr::Match(e.clone(), arms.clone()),
+            Expr::Annot(e, t) => Expr::Annot(e.clone(), t.clone()),
+            Expr::Ref(e) => Expr::Ref(e.clone()),
+            Expr::Deref(e) => Expr::Deref(e.clone()),
+            Expr::Assign(e1, e2) => Expr::Assign(e1.clone(), e2.clone()),
+            Expr::Raise(e, id) => Expr::Raise(e.clone(), *id),
+            Expr::Try(e, var, h) => Expr::Try(e.clone(), var.clone(), h.clone()),
+        })
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        grow(|| match self {
+            Expr::Var(var) => f.debug_tuple("Var").field(var).finish(),
+            Expr::Abs(var, t, e) => f.debug_tuple("Abs").field(var).field(t).field(e).finish(),
+            Expr::App(e1, e2) => f.debug_tuple("App").field(e1).field(e2).finish(),
+            Expr::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            Expr::Unit => write!(f, "Unit"),
+            Expr::Prim(prim) => f.debug_tuple("Prim").field(prim).finish(),
+            Expr::Seq(e1, e2) => f.debug_tuple("Seq").field(e1).field(e2).finish(),
+            Expr::BOp(op, e1, e2) => f.debug_tuple("BOp").field(op).field(e1).field(e2).finish(),
+            Expr::Tuple(es) => f.debug_tuple("Tuple").field(es).finish(),
+            Expr::Project(e, index) => f.debug_tuple("Project").field(e).field(index).finish(),
+            Expr::Inl(e) => f.debug_tuple("Inl").field(e).finish(),
+            Expr::Inr(e) => f.debug_tuple("Inr").field(e).finish(),
+            Expr::Case(e, var1, e1, var2, e2) => f
+                .debug_tuple("Case")
+                .field(e)
+                .field(var1)
+                .field(e1)
+                .field(var2)
+                .field(e2)
+                .finish(),
+            Expr::Constr(name, es) => f.debug_tuple("Constr").field(name).field(es).finish(),
+            Expr::Match(e, arms) => f.debug_tuple("Match").field(e).field(arms).finish(),
+            Expr::Annot(e, t) => f.debug_tuple("Annot").field(e).field(t).finish(),
+            Expr::Ref(e) => f.debug_tuple("Ref").field(e).finish(),
+            Expr::Deref(e) => f.debug_tuple("Deref").field(e).finish(),
+            Expr::Assign(e1, e2) => f.debug_tuple("Assign").field(e1).field(e2).finish(),
+            Expr::Raise(e, id) => f.debug_tuple("Raise").field(e).field(id).finish(),
+            Expr::Try(e, var, h) => f.debug_tuple("Try").field(e).field(var).field(h).finish(),
+        })
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        grow(|| match (self, other) {
+            (Expr::Var(var1), Expr::Var(var2)) => var1 == var2,
+            (Expr::Abs(var1, t1, e1), Expr::Abs(var2, t2, e2)) => {
+                var1 == var2 && t1 == t2 && e1 == e2
+            }
+            (Expr::App(e1, e2), Expr::App(f1, f2))
+            | (Expr::Seq(e1, e2), Expr::Seq(f1, f2))
+            | (Expr::Assign(e1, e2), Expr::Assign(f1, f2)) => e1 == f1 && e2 == f2,
+            (Expr::Number(n), Expr::Number(m)) => n == m,
+            (Expr::Unit, Expr::Unit) => true,
+            (Expr::Prim(prim1), Expr::Prim(prim2)) => prim1 == prim2,
+            (Expr::BOp(op1, e1, e2), Expr::BOp(op2, f1, f2)) => op1 == op2 && e1 == f1 && e2 == f2,
+            (Expr::Tuple(es), Expr::Tuple(fs)) => es == fs,
+            (Expr::Project(e, i), Expr::Project(f, j)) => i == j && e == f,
+            (Expr::Inl(e), Expr::Inl(f))
+            | (Expr::Inr(e), Expr::Inr(f))
+            | (Expr::Ref(e), Expr::Ref(f))
+            | (Expr::Deref(e), Expr::Deref(f)) => e == f,
+            (Expr::Raise(e, id1), Expr::Raise(f, id2)) => id1 == id2 && e == f,
+            (Expr::Case(e, x1, e1, y1, e2), Expr::Case(f, x2, f1, y2, f2)) => {
+                e == f && x1 == x2 && e1 == f1 && y1 == y2 && e2 == f2
+            }
+            (Expr::Constr(name1, es), Expr::Constr(name2, fs)) => name1 == name2 && es == fs,
+            (Expr::Match(e, arms1), Expr::Match(f, arms2)) => e == f && arms1 == arms2,
+            (Expr::Annot(e, t1), Expr::Annot(f, t2)) => e == f && t1 == t2,
+            (Expr::Try(e, x1, h1), Expr::Try(f, x2, h2)) => e == f && x1 == x2 && h1 == h2,
+            _ => false,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Pattern {
+    Wildcard,
+    Var(Variable),
+    Tuple(Vec<Pattern>),
+    Constr(String, Vec<Pattern>),
+}
+
+impl Pattern {
+    /// The variables bound by the pattern, from left to right.
+    pub fn variables(&self) -> Vec<Variable> {
+        let mut vars = Vec::new();
+        self.collect_variables(&mut vars);
+        vars
+    }
+
+    /// Whether the pattern can be a constructor argument without parentheses.
+    fn is_atomic(&self) -> bool {
+        match self {
+            Pattern::Wildcard | Pattern::Var(_) => true,
+            Pattern::Tuple(_) => false,
+            Pattern::Constr(_, patterns) => patterns.is_empty(),
+        }
+    }
+
+    fn collect_variables(&self, vars: &mut Vec<Variable>) {
+        match self {
+            Pattern::Wildcard => (),
+            Pattern::Var(var) => vars.push(var.clone()),
+            Pattern::Tuple(patterns) | Pattern::Constr(_, patterns) => {
+                for pattern in patterns {
+                    pattern.collect_variables(vars);
+                }
+            }
+        }
+    }
+}
+
+fn fmt_patterns(f: &mut fmt::Formatter<'_>, patterns: &[Pattern]) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, pattern) in patterns.iter().enumerate() {
+        if i != 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", pattern)?;
+    }
+    write!(f, ")")
+}
+
+/// Prints the pattern as it is written in the source.
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Var(var) => write!(f, "{}", var.name),
+            Pattern::Tuple(patterns) => fmt_patterns(f, patterns),
+            Pattern::Constr(name, patterns) => match patterns.as_slice() {
+                [] => write!(f, "{}", name),
+                [pattern] if pattern.is_atomic() => write!(f, "{} {}", name, pattern),
+                _ => {
+                    write!(f, "{} ", name)?;
+                    fmt_patterns(f, patterns)
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TypeExpr {
+    Int,
+    Unit,
+    Name(String),
+    Arrow(Box<TypeExpr>, Box<TypeExpr>),
+    Product(Vec<TypeExpr>),
+    Sum(Box<TypeExpr>, Box<TypeExpr>),
+    Ref(Box<TypeExpr>),
+}
+
+/// `type name = C1 of t1 * t2 | C2 | ...`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TypeDecl {
+    pub name: String,
+    pub constructors: Vec<(String, Vec<TypeExpr>)>,
+}
+
+/// A declaration before the main expression.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Decl {
+    Type(TypeDecl),
+    /// `exception C of t1 * t2` adds a constructor to `exn`
+    Exception(String, Vec<TypeExpr>),
+    /// `let x = e`, visible in the declarations after it
+    Let(Variable, Expr),
+    /// `let rec f x = e`, where the function is also visible in its own body
+    LetRec(Variable, Expr),
+    /// `import "path.stlc"` brings the `let`s of a module into scope as `m.f`, where `m` is the
+    /// file stem, the path is replaced by the name of the module once it is loaded
+    Import(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Program {
+    pub decls: Vec<Decl>,
+    /// a program without one only defines functions, and evaluates to `()`
+    pub main: Option<Expr>,
+}
+
+// the derived drop glue would recurse once per level of a deeply nested program
+impl Drop for Program {
+    fn drop(&mut self) {
+        let mut exprs: Vec<Expr> = self.main.take().into_iter().collect();
+        for decl in self.decls.drain(..) {
+            if let Decl::Let(_, expr) | Decl::LetRec(_, expr) = decl {
+                exprs.push(expr);
+            }
+        }
+        while let Some(mut expr) = exprs.pop() {
+            expr.take_children(&mut exprs);
+        }
+    }
+}
+
+impl Program {
+    pub fn types(&self) -> Vec<TypeDecl> {
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Type(t) => Some(t.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn exceptions(&self) -> Vec<(String, Vec<TypeExpr>)> {
+        self.decls
+            .iter()
+            .filter_map(|decl| match decl {
+                Decl::Exception(name, fields) => Some((name.clone(), fields.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Functions implemented by the runtime instead of the program.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Prim {
+    PrintInt,
+    PrintNewline,
+}
+
+impl Prim {
+    pub fn from_name(name: &str) -> Option<Prim> {
+        match name {
+            "print_int" => Some(Prim::PrintInt),
+            "print_newline" => Some(Prim::PrintNewline),
+            _ => None,
+        }
+    }
+
+    /// The name of the runtime function, which takes no argument for a unit parameter.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Prim::PrintInt => "stlc_print_int",
+            Prim::PrintNewline => "stlc_print_newline",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Prim::PrintInt => 1,
+            Prim::PrintNewline => 0,
+        }
+    }
+}
+
+impl fmt::Display for Prim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prim::PrintInt => write!(f, "print_int"),
+            Prim::PrintNewline => write!(f, "print_newline"),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -15,12 +373,45 @@ pub enum Operator {
     Sub,
     Mul,
     Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    /// arithmetic shift right, `>>`
+    Shr,
+    /// logical shift right, `>>>`
+    UShr,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+/// Byte offsets of a part of the source, empty for what the compiler makes up.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
     pub id: usize,
+    /// where the variable is written, not part of its identity
+    pub span: Span,
+}
+
+impl PartialEq for Variable {
+    fn eq(&self, other: &Variable) -> bool {
+        self.name == other.name && self.id == other.id
+    }
+}
+
+impl Eq for Variable {}
+
+impl Hash for Variable {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.id.hash(state);
+    }
 }
 
 impl fmt::Display for Variable {
@@ -1,27 +1,41 @@
 use std::collections::HashMap;
 
 use inkwell::{
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
-    module::Module,
-    types::{FunctionType, IntType, PointerType},
-    values::IntValue,
-    AddressSpace,
+    module::{Linkage, Module},
+    types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, IntType, PointerType},
+    values::{BasicMetadataValueEnum, BasicValue, BasicValueEnum, FunctionValue, IntValue},
+    AddressSpace, IntPredicate,
 };
 
 use crate::{
-    anf::{HoistedANFs, Value, ANF},
-    ast::Operator,
+    anf::{value_type, ANFs, HoistedANFs, Value, ANF},
+    ast::{Operator, Prim, Variable},
+    runtime::RuntimeError,
+    stack::grow,
+    typeinfer::Type,
 };
 
+/// With `tailcc` on both sides, llvm guarantees that a call marked `tail` and followed by a `ret`
+/// of its result becomes a jump. `musttail` would have llvm check that it does, but the C API of
+/// llvm 16 can only mark calls `tail`.
+const TAILCC: u32 = 18;
+
+/// Globals holding the jump buffer of the innermost handler and the exception being raised.
+const HANDLER: &str = "stlc_handler";
+const EXN: &str = "stlc_exn";
+
 #[derive(Debug)]
 pub struct LLVMCompiler<'a, 'ctx> {
     pub context: &'ctx Context,
     pub module: &'a Module<'ctx>,
     pub builder: &'a Builder<'ctx>,
     i64_type: IntType<'ctx>,
-    fn_type: FunctionType<'ctx>,
-    fn_ptr_type: PointerType<'ctx>,
+    ptr_type: PointerType<'ctx>,
+    /// abort on overflowing `+`, `-` and `*` instead of wrapping around
+    checked_arith: bool,
 }
 
 impl<'a, 'ctx> LLVMCompiler<'a, 'ctx> {
@@ -29,192 +43,792 @@ impl<'a, 'ctx> LLVMCompiler<'a, 'ctx> {
         context: &'ctx Context,
         builder: &'a Builder<'ctx>,
         module: &'a Module<'ctx>,
+        checked_arith: bool,
     ) -> Self {
         let i64_type = context.i64_type();
-        let fn_type = i64_type.fn_type(&[i64_type.into(), i64_type.into()], false);
-        let fn_ptr_type = fn_type.ptr_type(AddressSpace::from(0));
-        let malloc_type = i64_type
-            .ptr_type(AddressSpace::from(0))
-            .fn_type(&[i64_type.into()], false);
+        let ptr_type = context.i8_type().ptr_type(AddressSpace::from(0));
+        let malloc_type = ptr_type.fn_type(&[i64_type.into()], false);
         module.add_function("malloc", malloc_type, None);
+        // runtime functions, see runtime/runtime.c
+        let print_int_type = context.void_type().fn_type(&[i64_type.into()], false);
+        module.add_function(Prim::PrintInt.symbol(), print_int_type, None);
+        let print_newline_type = context.void_type().fn_type(&[], false);
+        module.add_function(Prim::PrintNewline.symbol(), print_newline_type, None);
+        let runtime_error_type = context.void_type().fn_type(&[i64_type.into()], false);
+        module.add_function(RuntimeError::SYMBOL, runtime_error_type, None);
+        let overflow_type = context
+            .struct_type(&[i64_type.into(), context.bool_type().into()], false)
+            .fn_type(&[i64_type.into(), i64_type.into()], false);
+        for intrinsic in [
+            "llvm.sadd.with.overflow.i64",
+            "llvm.ssub.with.overflow.i64",
+            "llvm.smul.with.overflow.i64",
+        ] {
+            module.add_function(intrinsic, overflow_type, None);
+        }
+        // exceptions, the handler is the jump buffer of the innermost `try` or null
+        let handler = module.add_global(ptr_type, None, HANDLER);
+        handler.set_initializer(&ptr_type.const_null());
+        let exn = module.add_global(i64_type, None, EXN);
+        exn.set_initializer(&i64_type.const_zero());
+        let i32_type = context.i32_type();
+        let frameaddress_type = ptr_type.fn_type(&[i32_type.into()], false);
+        module.add_function("llvm.frameaddress.p0", frameaddress_type, None);
+        module.add_function("llvm.stacksave", ptr_type.fn_type(&[], false), None);
+        let setjmp_type = i32_type.fn_type(&[ptr_type.into()], false);
+        module.add_function("llvm.eh.sjlj.setjmp", setjmp_type, None);
+        let longjmp_type = context.void_type().fn_type(&[ptr_type.into()], false);
+        module.add_function("llvm.eh.sjlj.longjmp", longjmp_type, None);
         Self {
             context,
             module,
             builder,
             i64_type,
-            fn_type,
-            fn_ptr_type,
+            ptr_type,
+            checked_arith,
+        }
+    }
+
+    /// Ints and unit are kept unboxed, everything else is a pointer to a heap allocated tuple or code.
+    /// Type variables left unresolved are never inspected, so they are treated as ints.
+    fn llvm_type(&self, ty: &Type) -> BasicTypeEnum<'ctx> {
+        match ty.simplify() {
+            Type::Int | Type::Unit | Type::TVar(_, _) => self.i64_type.into(),
+            _ => self.ptr_type.into(),
+        }
+    }
+
+    fn fn_type(
+        &self,
+        args: &[BasicTypeEnum<'ctx>],
+        ret: BasicTypeEnum<'ctx>,
+    ) -> FunctionType<'ctx> {
+        let args = args
+            .iter()
+            .map(|&arg| arg.into())
+            .collect::<Vec<BasicMetadataTypeEnum>>();
+        ret.fn_type(&args, false)
+    }
+
+    /// The parameter and result types of code of type `ty`, which takes its closure first. A use of
+    /// a polymorphic function at another type calls a copy of its code, see
+    /// `ANFConverter::specialize`, so a call always agrees with the code it calls.
+    fn code_signature(&self, ty: &Type) -> (Vec<BasicTypeEnum<'ctx>>, BasicTypeEnum<'ctx>) {
+        match ty.simplify() {
+            Type::Code(args, ret) => {
+                let mut params: Vec<BasicTypeEnum> = vec![self.ptr_type.into()];
+                params.extend(args.iter().map(|arg| self.llvm_type(arg)));
+                (params, self.llvm_type(&ret))
+            }
+            t => unreachable!("calling a value of type {}", t),
         }
     }
 
     pub fn compile(&self, hoisted_anfs: HoistedANFs) {
-        for (fun_name, args, body) in hoisted_anfs.fun_defs {
-            let fun = self
-                .module
-                .add_function(&fun_name.to_string(), self.fn_type, None);
+        let prints_result = hoisted_anfs.prints_result();
+        let HoistedANFs {
+            fun_defs,
+            main,
+            types,
+            exports,
+        } = hoisted_anfs;
+        for (fun_name, _, _) in &fun_defs {
+            let (params, ret) = self.code_signature(&types[&fun_name.id]);
+            let fun =
+                self.module
+                    .add_function(&fun_name.to_string(), self.fn_type(&params, ret), None);
+            fun.set_call_conventions(TAILCC);
+            fun.set_linkage(Linkage::Internal);
+        }
+        for (fun_name, args, body) in fun_defs {
+            let fun = self.module.get_function(&fun_name.to_string()).unwrap();
             let entry_basic_block = self.context.append_basic_block(fun, "entry");
             self.builder.position_at_end(entry_basic_block);
 
-            let mut env: HashMap<String, IntValue> = HashMap::new();
+            let mut env: HashMap<String, BasicValueEnum> = HashMap::new();
             for (i, arg) in args.into_iter().enumerate() {
                 let arg_ir = fun.get_nth_param(i as u32).unwrap();
-                env.insert(arg.to_string(), arg_ir.into_int_value());
-            }
-            for anf in body.anfs {
-                self.compile_anf(anf, &mut env);
+                env.insert(arg.to_string(), arg_ir);
             }
-            let ret = self.compile_value(body.value.unwrap(), &mut env);
-            self.builder.build_return(Some(&ret)).unwrap();
+            let ret_type = fun.get_type().get_return_type().unwrap();
+            self.compile_return(body, &mut env, &types, ret_type, true);
         }
-        let main_fn_type = self.i64_type.fn_type(&[], false);
+        let i32_type = self.context.i32_type();
+        let main_fn_type = i32_type.fn_type(&[], false);
         let main_fn = self.module.add_function("main", main_fn_type, None);
 
         let entry_basic_block = self.context.append_basic_block(main_fn, "entry");
         self.builder.position_at_end(entry_basic_block);
 
-        let mut env: HashMap<String, IntValue> = HashMap::new();
-        for anf in hoisted_anfs.main.anfs {
-            self.compile_anf(anf, &mut env);
+        let mut env: HashMap<String, BasicValueEnum> = HashMap::new();
+        // main keeps the c calling convention, so its calls can not be tail calls
+        let result = main.result();
+        for anf in main.anfs {
+            self.compile_anf(anf, &mut env, &types, false);
         }
-        let ret = self.compile_value(hoisted_anfs.main.value.unwrap(), &mut env);
-        self.builder.build_return(Some(&ret)).unwrap();
-    }
-
-    fn compile_anf(&self, anf: ANF, env: &mut HashMap<String, IntValue<'ctx>>) {
-        match anf {
-            ANF::Fun(_, _, _) => unreachable!(),
-            ANF::App(var, fun_var, args) => {
-                let fun = env.get(&fun_var.to_string()).unwrap();
-                let fun = self
-                    .builder
-                    .build_int_to_ptr(*fun, self.fn_ptr_type, "ptr")
-                    .unwrap();
-                let args = args
-                    .into_iter()
-                    .map(|arg| self.compile_value(arg, env).into())
-                    .collect::<Vec<_>>();
-                let var_ir = self
-                    .builder
-                    .build_indirect_call(self.fn_type, fun, &args, &var.to_string())
-                    .unwrap();
-                env.insert(
-                    var.to_string(),
-                    var_ir.try_as_basic_value().unwrap_left().into_int_value(),
-                );
-            }
-            ANF::BOp(var, op, val1, val2) => {
-                let val1 = self.compile_value(val1, env);
-                let val2 = self.compile_value(val2, env);
-                let var_ir = match op {
-                    Operator::Add => self.builder.build_int_add(val1, val2, &var.to_string()),
-                    Operator::Sub => self.builder.build_int_sub(val1, val2, &var.to_string()),
-                    Operator::Mul => self.builder.build_int_mul(val1, val2, &var.to_string()),
-                    Operator::Div => {
-                        self.builder
-                            .build_int_signed_div(val1, val2, &var.to_string())
+        if prints_result {
+            let result = self.compile_value(result, &env);
+            let result = self.coerce(result, self.i64_type.into());
+            let print_int = self.module.get_function(Prim::PrintInt.symbol()).unwrap();
+            self.builder
+                .build_call(print_int, &[result.into()], "")
+                .unwrap();
+            let print_newline = self
+                .module
+                .get_function(Prim::PrintNewline.symbol())
+                .unwrap();
+            self.builder.build_call(print_newline, &[], "").unwrap();
+        }
+        self.builder
+            .build_return(Some(&i32_type.const_zero()))
+            .unwrap();
+        for (symbol, fun_name) in exports {
+            let code = self.module.get_function(&fun_name.to_string()).unwrap();
+            self.build_export(&symbol, code);
+        }
+    }
+
+    /// Exports `code` under `symbol` with the C calling convention, taking the closure as a pointer
+    /// and the other arguments and the result as i64 words, which are converted to the types of the
+    /// code.
+    fn build_export(&self, symbol: &str, code: FunctionValue<'ctx>) {
+        let mut params: Vec<BasicMetadataTypeEnum> = vec![self.ptr_type.into()];
+        params.resize(code.count_params() as usize, self.i64_type.into());
+        let fun = self
+            .module
+            .add_function(symbol, self.i64_type.fn_type(&params, false), None);
+        let entry_basic_block = self.context.append_basic_block(fun, "entry");
+        self.builder.position_at_end(entry_basic_block);
+        let args = fun
+            .get_param_iter()
+            .zip(code.get_param_iter())
+            .map(|(param, code_param)| self.coerce(param, code_param.get_type()).into())
+            .collect::<Vec<BasicMetadataValueEnum>>();
+        let result = self.builder.build_call(code, &args, "result").unwrap();
+        result.set_call_convention(TAILCC);
+        let result = result.try_as_basic_value().unwrap_left();
+        let result = self.coerce(result, self.i64_type.into());
+        self.builder.build_return(Some(&result)).unwrap();
+    }
+
+    /// Compiles `body` and returns its value from the current function.
+    fn compile_return(
+        &self,
+        body: ANFs,
+        env: &mut HashMap<String, BasicValueEnum<'ctx>>,
+        types: &HashMap<usize, Type>,
+        ret_type: BasicTypeEnum<'ctx>,
+        tail_calls: bool,
+    ) {
+        grow(|| {
+            let tail = tail_calls && body.ends_in_tail_position();
+            let len = body.anfs.len();
+            for (i, anf) in body.anfs.into_iter().enumerate() {
+                match anf {
+                    // every branch returns on its own, so calls in their tail position stay tail
+                    // calls
+                    ANF::Switch(_, tag, branches) if tail && i + 1 == len => {
+                        let blocks = self.build_switch(tag, branches.len(), env);
+                        for (branch, block) in branches.into_iter().zip(blocks) {
+                            self.builder.position_at_end(block);
+                            self.compile_return(
+                                branch,
+                                &mut env.clone(),
+                                types,
+                                ret_type,
+                                tail_calls,
+                            );
+                        }
+                        return;
                     }
+                    anf => self.compile_anf(anf, env, types, tail && i + 1 == len),
                 }
-                .unwrap();
-                env.insert(var.to_string(), var_ir);
             }
-            ANF::Tuple(var, tuple) => {
-                let tuple = tuple
-                    .into_iter()
-                    .map(|val| self.compile_value(val, env))
-                    .collect::<Vec<_>>();
-                let malloc = self.module.get_function("malloc").unwrap();
-                let tuple_ptr = self
-                    .builder
-                    .build_call(
-                        malloc,
-                        &[self
-                            .i64_type
-                            .const_int(8 * tuple.len() as u64, false)
-                            .into()],
-                        &var.to_string(),
-                    )
-                    .unwrap();
-                for i in 0..tuple.len() {
+            let ret = self.compile_value(body.result(), env);
+            let ret = self.coerce(ret, ret_type);
+            self.builder.build_return(Some(&ret)).unwrap();
+        })
+    }
+
+    /// Branches on the tag and returns one block per branch, the last branch is the fallback.
+    fn build_switch(
+        &self,
+        tag: Value,
+        branch_count: usize,
+        env: &HashMap<String, BasicValueEnum<'ctx>>,
+    ) -> Vec<BasicBlock<'ctx>> {
+        let tag = self.compile_value(tag, env);
+        let tag = self.coerce(tag, self.i64_type.into()).into_int_value();
+        let fun = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let blocks = (0..branch_count)
+            .map(|_| self.context.append_basic_block(fun, "case"))
+            .collect::<Vec<_>>();
+        let cases = blocks[..branch_count - 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &block)| (self.i64_type.const_int(i as u64, false), block))
+            .collect::<Vec<_>>();
+        self.builder
+            .build_switch(tag, blocks[branch_count - 1], &cases)
+            .unwrap();
+        blocks
+    }
+
+    /// Reinterprets a value whose static type was too general, e.g. an unresolved type variable.
+    fn coerce(&self, value: BasicValueEnum<'ctx>, ty: BasicTypeEnum<'ctx>) -> BasicValueEnum<'ctx> {
+        match (value, ty) {
+            (BasicValueEnum::IntValue(value), BasicTypeEnum::PointerType(ty)) => self
+                .builder
+                .build_int_to_ptr(value, ty, "ptr")
+                .unwrap()
+                .into(),
+            (BasicValueEnum::PointerValue(value), BasicTypeEnum::IntType(ty)) => self
+                .builder
+                .build_ptr_to_int(value, ty, "int")
+                .unwrap()
+                .into(),
+            (value, _) => value,
+        }
+    }
+
+    /// Calls the runtime error routine when `cond` holds and continues in a new block otherwise.
+    fn build_check(&self, cond: IntValue<'ctx>, error: RuntimeError) {
+        let fun = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let error_block = self.context.append_basic_block(fun, "error");
+        let ok_block = self.context.append_basic_block(fun, "ok");
+        self.builder
+            .build_conditional_branch(cond, error_block, ok_block)
+            .unwrap();
+        self.builder.position_at_end(error_block);
+        self.build_runtime_error(error);
+        self.builder.position_at_end(ok_block);
+    }
+
+    /// Calls the runtime error routine, which does not return.
+    fn build_runtime_error(&self, error: RuntimeError) {
+        let runtime_error = self.module.get_function(RuntimeError::SYMBOL).unwrap();
+        let code = self.i64_type.const_int(error.code() as u64, false);
+        self.builder
+            .build_call(runtime_error, &[code.into()], "")
+            .unwrap();
+        self.builder.build_unreachable().unwrap();
+    }
+
+    /// Calls one of the `llvm.s*.with.overflow` intrinsics and checks its overflow bit.
+    fn build_checked(
+        &self,
+        intrinsic: &str,
+        val1: IntValue<'ctx>,
+        val2: IntValue<'ctx>,
+        name: &str,
+    ) -> IntValue<'ctx> {
+        let fun = self.module.get_function(intrinsic).unwrap();
+        let result = self
+            .builder
+            .build_call(fun, &[val1.into(), val2.into()], "checked")
+            .unwrap()
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_struct_value();
+        let overflow = self
+            .builder
+            .build_extract_value(result, 1, "overflow")
+            .unwrap()
+            .into_int_value();
+        self.build_check(overflow, RuntimeError::Overflow);
+        self.builder
+            .build_extract_value(result, 0, name)
+            .unwrap()
+            .into_int_value()
+    }
+
+    /// Division is always checked, since `sdiv` is undefined for a zero divisor and for the
+    /// quotient of `i64::MIN` and -1.
+    fn build_div(&self, val1: IntValue<'ctx>, val2: IntValue<'ctx>, name: &str) -> IntValue<'ctx> {
+        let zero = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, val2, self.i64_type.const_zero(), "zero")
+            .unwrap();
+        self.build_check(zero, RuntimeError::DivisionByZero);
+        let min = self
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                val1,
+                self.i64_type.const_int(i64::MIN as u64, true),
+                "min",
+            )
+            .unwrap();
+        let minus_one = self
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                val2,
+                self.i64_type.const_all_ones(),
+                "minus_one",
+            )
+            .unwrap();
+        let overflow = self.builder.build_and(min, minus_one, "overflow").unwrap();
+        self.build_check(overflow, RuntimeError::Overflow);
+        self.builder.build_int_signed_div(val1, val2, name).unwrap()
+    }
+
+    /// Compiles a branch that jumps to `merge_block` and returns its value with the block it comes
+    /// from, since nested branches leave the builder in a different block than it started in.
+    fn compile_branch(
+        &self,
+        branch: ANFs,
+        env: &mut HashMap<String, BasicValueEnum<'ctx>>,
+        types: &HashMap<usize, Type>,
+        ty: BasicTypeEnum<'ctx>,
+        merge_block: BasicBlock<'ctx>,
+    ) -> (BasicValueEnum<'ctx>, BasicBlock<'ctx>) {
+        for anf in branch.anfs {
+            self.compile_anf(anf, env, types, false);
+        }
+        let value = self.compile_value(branch.result(), env);
+        let value = self.coerce(value, ty);
+        let block = self.builder.get_insert_block().unwrap();
+        self.builder
+            .build_unconditional_branch(merge_block)
+            .unwrap();
+        (value, block)
+    }
+
+    /// Continues in a block nothing jumps to, binding `var` so the rest of the branch still compiles.
+    fn build_dead_block(
+        &self,
+        var: &Variable,
+        env: &mut HashMap<String, BasicValueEnum<'ctx>>,
+        types: &HashMap<usize, Type>,
+    ) {
+        let fun = self
+            .builder
+            .get_insert_block()
+            .unwrap()
+            .get_parent()
+            .unwrap();
+        let block = self.context.append_basic_block(fun, "dead");
+        self.builder.position_at_end(block);
+        let var_ir = match self.llvm_type(&types[&var.id]) {
+            BasicTypeEnum::IntType(ty) => ty.get_undef().into(),
+            ty => ty.into_pointer_type().get_undef().into(),
+        };
+        env.insert(var.to_string(), var_ir);
+    }
+
+    /// `srem` is undefined for a zero divisor and for `i64::MIN % -1`, whose result is 0 as for
+    /// a divisor of 1.
+    fn build_rem(&self, val1: IntValue<'ctx>, val2: IntValue<'ctx>, name: &str) -> IntValue<'ctx> {
+        let zero = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, val2, self.i64_type.const_zero(), "zero")
+            .unwrap();
+        self.build_check(zero, RuntimeError::DivisionByZero);
+        let minus_one = self
+            .builder
+            .build_int_compare(
+                IntPredicate::EQ,
+                val2,
+                self.i64_type.const_all_ones(),
+                "minus_one",
+            )
+            .unwrap();
+        let divisor = self
+            .builder
+            .build_select(
+                minus_one,
+                self.i64_type.const_int(1, false),
+                val2,
+                "divisor",
+            )
+            .unwrap()
+            .into_int_value();
+        self.builder
+            .build_int_signed_rem(val1, divisor, name)
+            .unwrap()
+    }
+
+    /// Shifting by 64 or more is poison in llvm, so the amount is taken modulo 64 as in wasm.
+    fn shift_amount(&self, amount: IntValue<'ctx>) -> IntValue<'ctx> {
+        self.builder
+            .build_and(amount, self.i64_type.const_int(63, false), "amount")
+            .unwrap()
+    }
+
+    fn compile_anf(
+        &self,
+        anf: ANF,
+        env: &mut HashMap<String, BasicValueEnum<'ctx>>,
+        types: &HashMap<usize, Type>,
+        tail: bool,
+    ) {
+        grow(|| {
+            match anf {
+                ANF::Fun(_, _, _) => unreachable!(),
+                ANF::App(var, fun_var, args) => {
+                    let fun = *env.get(&fun_var.to_string()).unwrap();
+                    let fun = self.coerce(fun, self.ptr_type.into()).into_pointer_value();
+                    let (params, ret) = self.code_signature(&types[&fun_var.id]);
+                    let args = args
+                        .into_iter()
+                        .zip(&params)
+                        .map(|(arg, &ty)| {
+                            let arg = self.compile_value(arg, env);
+                            self.coerce(arg, ty).into()
+                        })
+                        .collect::<Vec<BasicMetadataValueEnum>>();
+                    let var_ir = self
+                        .builder
+                        .build_indirect_call(
+                            self.fn_type(&params, ret),
+                            fun,
+                            &args,
+                            &var.to_string(),
+                        )
+                        .unwrap();
+                    var_ir.set_call_convention(TAILCC);
+                    var_ir.set_tail_call(tail);
+                    env.insert(var.to_string(), var_ir.try_as_basic_value().unwrap_left());
+                }
+                ANF::BOp(var, op, val1, val2) => {
+                    let val1 = self.compile_value(val1, env);
+                    let val1 = self.coerce(val1, self.i64_type.into()).into_int_value();
+                    let val2 = self.compile_value(val2, env);
+                    let val2 = self.coerce(val2, self.i64_type.into()).into_int_value();
+                    let name = var.to_string();
+                    let var_ir = match op {
+                        Operator::Add if self.checked_arith => {
+                            self.build_checked("llvm.sadd.with.overflow.i64", val1, val2, &name)
+                        }
+                        Operator::Sub if self.checked_arith => {
+                            self.build_checked("llvm.ssub.with.overflow.i64", val1, val2, &name)
+                        }
+                        Operator::Mul if self.checked_arith => {
+                            self.build_checked("llvm.smul.with.overflow.i64", val1, val2, &name)
+                        }
+                        Operator::Add => self.builder.build_int_add(val1, val2, &name).unwrap(),
+                        Operator::Sub => self.builder.build_int_sub(val1, val2, &name).unwrap(),
+                        Operator::Mul => self.builder.build_int_mul(val1, val2, &name).unwrap(),
+                        Operator::Div => self.build_div(val1, val2, &name),
+                        Operator::Mod => self.build_rem(val1, val2, &name),
+                        Operator::And => self.builder.build_and(val1, val2, &name).unwrap(),
+                        Operator::Or => self.builder.build_or(val1, val2, &name).unwrap(),
+                        Operator::Xor => self.builder.build_xor(val1, val2, &name).unwrap(),
+                        Operator::Shl => {
+                            let amount = self.shift_amount(val2);
+                            self.builder.build_left_shift(val1, amount, &name).unwrap()
+                        }
+                        Operator::Shr => {
+                            let amount = self.shift_amount(val2);
+                            self.builder
+                                .build_right_shift(val1, amount, true, &name)
+                                .unwrap()
+                        }
+                        Operator::UShr => {
+                            let amount = self.shift_amount(val2);
+                            self.builder
+                                .build_right_shift(val1, amount, false, &name)
+                                .unwrap()
+                        }
+                    };
+                    env.insert(var.to_string(), var_ir.into());
+                }
+                ANF::Tuple(var, tuple) => {
+                    // a struct of the types of the fields, each of which takes 8 bytes, int or
+                    // pointer, where `Project` expects it
+                    let fields = tuple
+                        .iter()
+                        .map(|val| self.llvm_type(&value_type(types, val)))
+                        .collect::<Vec<_>>();
+                    let tuple_type = self.context.struct_type(&fields, false);
+                    let tuple = tuple
+                        .into_iter()
+                        .zip(&fields)
+                        .map(|(val, &ty)| {
+                            let val = self.compile_value(val, env);
+                            self.coerce(val, ty)
+                        })
+                        .collect::<Vec<_>>();
+                    let malloc = self.module.get_function("malloc").unwrap();
+                    let tuple_ptr = self
+                        .builder
+                        .build_call(
+                            malloc,
+                            &[tuple_type.size_of().unwrap().into()],
+                            &var.to_string(),
+                        )
+                        .unwrap()
+                        .try_as_basic_value()
+                        .unwrap_left()
+                        .into_pointer_value();
+                    for (i, val) in tuple.into_iter().enumerate() {
+                        let ptr = self
+                            .builder
+                            .build_struct_gep(tuple_type, tuple_ptr, i as u32, "ptr")
+                            .unwrap();
+                        self.builder.build_store(ptr, val).unwrap();
+                    }
+                    env.insert(var.to_string(), tuple_ptr.into());
+                }
+                ANF::Project(var, tuple, index) => {
+                    let tuple = *env.get(&tuple.to_string()).unwrap();
+                    let tuple_ptr = self
+                        .coerce(tuple, self.ptr_type.into())
+                        .into_pointer_value();
                     let ptr = unsafe {
                         self.builder
                             .build_gep(
                                 self.i64_type,
-                                tuple_ptr
-                                    .try_as_basic_value()
-                                    .unwrap_left()
-                                    .into_pointer_value(),
-                                &[self.context.i32_type().const_int(i as u64, false).into()],
+                                tuple_ptr,
+                                &[self.i64_type.const_int(index as u64, false)],
                                 "ptr",
                             )
                             .unwrap()
                     };
-                    self.builder.build_store(ptr, tuple[i]).unwrap();
+                    let var_ir = self
+                        .builder
+                        .build_load(self.llvm_type(&types[&var.id]), ptr, &var.to_string())
+                        .unwrap();
+                    env.insert(var.to_string(), var_ir);
                 }
-
-                let tuple_ptr = self
-                    .builder
-                    .build_ptr_to_int(
-                        tuple_ptr
-                            .try_as_basic_value()
-                            .unwrap_left()
-                            .into_pointer_value(),
-                        self.i64_type,
-                        "ptr",
-                    )
-                    .unwrap();
-                env.insert(var.to_string(), tuple_ptr);
-            }
-            ANF::Project(var, tuple, index) => {
-                let tuple = env.get(&tuple.to_string()).unwrap().clone();
-                let tuple_ptr = self
-                    .builder
-                    .build_int_to_ptr(tuple, self.i64_type.ptr_type(AddressSpace::from(0)), "ptr")
-                    .unwrap();
-                let ptr = unsafe {
+                ANF::Store(var, tuple, index, value) => {
+                    let tuple = *env.get(&tuple.to_string()).unwrap();
+                    let tuple_ptr = self
+                        .coerce(tuple, self.ptr_type.into())
+                        .into_pointer_value();
+                    let ptr = unsafe {
+                        self.builder
+                            .build_gep(
+                                self.i64_type,
+                                tuple_ptr,
+                                &[self.i64_type.const_int(index as u64, false)],
+                                "ptr",
+                            )
+                            .unwrap()
+                    };
+                    let value = self.compile_value(value, env);
+                    self.builder.build_store(ptr, value).unwrap();
+                    env.insert(var.to_string(), self.i64_type.const_zero().into());
+                }
+                ANF::Prim(var, prim, args) => {
+                    let fun = self.module.get_function(prim.symbol()).unwrap();
+                    let args = args
+                        .into_iter()
+                        .map(|arg| {
+                            let arg = self.compile_value(arg, env);
+                            self.coerce(arg, self.i64_type.into()).into()
+                        })
+                        .collect::<Vec<BasicMetadataValueEnum>>();
+                    self.builder.build_call(fun, &args, "").unwrap();
+                    // every primitive returns unit
+                    env.insert(var.to_string(), self.i64_type.const_zero().into());
+                }
+                ANF::Switch(var, tag, branches) => {
+                    let ty = self.llvm_type(&types[&var.id]);
+                    let blocks = self.build_switch(tag, branches.len(), env);
+                    let fun = blocks[0].get_parent().unwrap();
+                    let merge_block = self.context.append_basic_block(fun, "merge");
+                    let mut incoming = Vec::new();
+                    for (branch, block) in branches.into_iter().zip(blocks) {
+                        self.builder.position_at_end(block);
+                        incoming.push(self.compile_branch(
+                            branch,
+                            &mut env.clone(),
+                            types,
+                            ty,
+                            merge_block,
+                        ));
+                    }
+                    self.builder.position_at_end(merge_block);
+                    let phi = self.builder.build_phi(ty, &var.to_string()).unwrap();
+                    for (value, block) in &incoming {
+                        phi.add_incoming(&[(value as &dyn BasicValue, *block)]);
+                    }
+                    env.insert(var.to_string(), phi.as_basic_value());
+                }
+                ANF::Trap(var) => {
+                    self.build_runtime_error(RuntimeError::MatchFailure);
+                    self.build_dead_block(&var, env, types);
+                }
+                ANF::Raise(var, value) => {
+                    let value = self.compile_value(value, env);
+                    let value = self.coerce(value, self.i64_type.into());
+                    let exn = self.module.get_global(EXN).unwrap().as_pointer_value();
+                    self.builder.build_store(exn, value).unwrap();
+                    let handler = self.module.get_global(HANDLER).unwrap().as_pointer_value();
+                    let handler = self
+                        .builder
+                        .build_load(self.ptr_type, handler, "handler")
+                        .unwrap()
+                        .into_pointer_value();
+                    let uncaught = self.builder.build_is_null(handler, "uncaught").unwrap();
+                    self.build_check(uncaught, RuntimeError::UncaughtException);
+                    let longjmp = self.module.get_function("llvm.eh.sjlj.longjmp").unwrap();
                     self.builder
-                        .build_gep(
-                            self.i64_type,
-                            tuple_ptr,
-                            &[self
-                                .context
-                                .i32_type()
-                                .const_int(index as u64, false)
-                                .into()],
-                            "ptr",
+                        .build_call(longjmp, &[handler.into()], "")
+                        .unwrap();
+                    self.builder.build_unreachable().unwrap();
+                    self.build_dead_block(&var, env, types);
+                }
+                ANF::Try(var, body, exn, handler) => {
+                    let ty = self.llvm_type(&types[&var.id]);
+                    let handler_global =
+                        self.module.get_global(HANDLER).unwrap().as_pointer_value();
+                    let prev = self
+                        .builder
+                        .build_load(self.ptr_type, handler_global, "prev")
+                        .unwrap();
+                    // llvm.eh.sjlj.setjmp expects the frame address at 0 and the stack pointer at
+                    // 2, it fills in the rest
+                    let buf = self
+                        .builder
+                        .build_alloca(self.ptr_type.array_type(5), "jmp_buf")
+                        .unwrap();
+                    let frameaddress = self.module.get_function("llvm.frameaddress.p0").unwrap();
+                    let frame = self
+                        .builder
+                        .build_call(
+                            frameaddress,
+                            &[self.context.i32_type().const_zero().into()],
+                            "frame",
+                        )
+                        .unwrap()
+                        .try_as_basic_value()
+                        .unwrap_left();
+                    self.builder.build_store(buf, frame).unwrap();
+                    let stacksave = self.module.get_function("llvm.stacksave").unwrap();
+                    let sp = self
+                        .builder
+                        .build_call(stacksave, &[], "sp")
+                        .unwrap()
+                        .try_as_basic_value()
+                        .unwrap_left();
+                    let sp_slot = unsafe {
+                        self.builder
+                            .build_gep(
+                                self.ptr_type,
+                                buf,
+                                &[self.i64_type.const_int(2, false)],
+                                "sp_slot",
+                            )
+                            .unwrap()
+                    };
+                    self.builder.build_store(sp_slot, sp).unwrap();
+                    self.builder.build_store(handler_global, buf).unwrap();
+                    let setjmp = self.module.get_function("llvm.eh.sjlj.setjmp").unwrap();
+                    let raised = self
+                        .builder
+                        .build_call(setjmp, &[buf.into()], "raised")
+                        .unwrap()
+                        .try_as_basic_value()
+                        .unwrap_left()
+                        .into_int_value();
+                    let raised = self
+                        .builder
+                        .build_int_compare(
+                            IntPredicate::NE,
+                            raised,
+                            self.context.i32_type().const_zero(),
+                            "raised",
                         )
+                        .unwrap();
+                    let fun = self
+                        .builder
+                        .get_insert_block()
                         .unwrap()
-                };
-                let var_ir = self
-                    .builder
-                    .build_load(self.i64_type, ptr, &var.to_string())
-                    .unwrap();
-                env.insert(var.to_string(), var_ir.into_int_value());
+                        .get_parent()
+                        .unwrap();
+                    let body_block = self.context.append_basic_block(fun, "try");
+                    let handler_block = self.context.append_basic_block(fun, "with");
+                    let merge_block = self.context.append_basic_block(fun, "merge");
+                    self.builder
+                        .build_conditional_branch(raised, handler_block, body_block)
+                        .unwrap();
+                    // both ways out of the body restore the enclosing handler
+                    self.builder.position_at_end(body_block);
+                    let mut body_env = env.clone();
+                    let result = body.result();
+                    for anf in body.anfs {
+                        self.compile_anf(anf, &mut body_env, types, false);
+                    }
+                    self.builder.build_store(handler_global, prev).unwrap();
+                    let value = self.compile_value(result, &body_env);
+                    let mut incoming = vec![(
+                        self.coerce(value, ty),
+                        self.builder.get_insert_block().unwrap(),
+                    )];
+                    self.builder
+                        .build_unconditional_branch(merge_block)
+                        .unwrap();
+                    self.builder.position_at_end(handler_block);
+                    self.builder.build_store(handler_global, prev).unwrap();
+                    let exn_global = self.module.get_global(EXN).unwrap().as_pointer_value();
+                    let exn_value = self
+                        .builder
+                        .build_load(self.i64_type, exn_global, &exn.to_string())
+                        .unwrap();
+                    let mut handler_env = env.clone();
+                    handler_env.insert(
+                        exn.to_string(),
+                        self.coerce(exn_value, self.llvm_type(&types[&exn.id])),
+                    );
+                    incoming.push(self.compile_branch(
+                        handler,
+                        &mut handler_env,
+                        types,
+                        ty,
+                        merge_block,
+                    ));
+                    self.builder.position_at_end(merge_block);
+                    let phi = self.builder.build_phi(ty, &var.to_string()).unwrap();
+                    for (value, block) in &incoming {
+                        phi.add_incoming(&[(value as &dyn BasicValue, *block)]);
+                    }
+                    env.insert(var.to_string(), phi.as_basic_value());
+                }
             }
-        }
+        })
     }
 
     fn compile_value<'b>(
         &self,
         value: Value,
-        env: &'b HashMap<String, IntValue<'ctx>>,
-    ) -> IntValue<'ctx>
+        env: &'b HashMap<String, BasicValueEnum<'ctx>>,
+    ) -> BasicValueEnum<'ctx>
     where
         'ctx: 'b,
     {
         match value {
-            Value::Number(n) => self.i64_type.const_int(n as u64, true),
-            Value::Var(var) => env.get(&var.to_string()).unwrap().clone(),
-            Value::Global(var) => {
-                let fun_ptr = self
-                    .module
-                    .get_function(&var.to_string())
-                    .unwrap()
-                    .as_global_value()
-                    .as_pointer_value();
-                self.builder
-                    .build_ptr_to_int(fun_ptr, self.i64_type, "ptr")
-                    .unwrap()
-            }
+            Value::Number(n) => self.i64_type.const_int(n as u64, true).into(),
+            Value::Unit => self.i64_type.const_zero().into(),
+            Value::Var(var) => *env.get(&var.to_string()).unwrap(),
+            Value::Global(var) => self
+                .module
+                .get_function(&var.to_string())
+                .unwrap()
+                .as_global_value()
+                .as_pointer_value()
+                .into(),
         }
     }
 }
+
+pub fn emit_llvm_ir(hoisted_anfs: HoistedANFs, checked_arith: bool) -> String {
+    let context = Context::create();
+    let builder = context.create_builder();
+    let module = context.create_module("main");
+    let llvm_compiler = LLVMCompiler::new(&context, &builder, &module, checked_arith);
+    llvm_compiler.compile(hoisted_anfs);
+    module.print_to_string().to_string()
+}
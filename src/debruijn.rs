@@ -0,0 +1,295 @@
+//! Nameless forms of terms, to compare them up to the names and ids of their bound variables.
+
+use std::collections::HashMap;
+
+use crate::{
+    anf::{ANFs, Value, ANF},
+    ast::{Expr, Operator, Pattern, Prim, TypeExpr, Variable},
+    stack::grow,
+};
+
+/// An expression where a bound variable is the number of binders between it and the one binding
+/// it, 0 for the innermost.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Term {
+    Bound(usize),
+    /// a variable no binder of the term binds, such as a top-level `let`, with its name and the
+    /// number of other free variables used before it, so variables with the same name but
+    /// different ids stay apart
+    Free(String, usize),
+    Abs(Option<TypeExpr>, Box<Term>),
+    App(Box<Term>, Box<Term>),
+    Number(i64),
+    Unit,
+    Prim(Prim),
+    Seq(Box<Term>, Box<Term>),
+    BOp(Operator, Box<Term>, Box<Term>),
+    Tuple(Vec<Term>),
+    Project(Box<Term>, usize),
+    Inl(Box<Term>),
+    Inr(Box<Term>),
+    /// each branch binds one variable
+    Case(Box<Term>, Box<Term>, Box<Term>),
+    Constr(String, Vec<Term>),
+    /// each arm binds the variables of its pattern, from left to right
+    Match(Box<Term>, Vec<(TermPattern, Term)>),
+    Annot(Box<Term>, TypeExpr),
+    Ref(Box<Term>),
+    Deref(Box<Term>),
+    Assign(Box<Term>, Box<Term>),
+    Raise(Box<Term>),
+    /// the handler binds the exception
+    Try(Box<Term>, Box<Term>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TermPattern {
+    Wildcard,
+    Var,
+    Tuple(Vec<TermPattern>),
+    Constr(String, Vec<TermPattern>),
+}
+
+/// Converts an expression, either as parsed or alpha converted: a variable refers to the innermost
+/// binder with the same name and id.
+pub fn to_debruijn(expr: &Expr) -> Term {
+    debruijn(expr, &mut Scope::default())
+}
+
+/// Whether the expressions only differ by the names and ids of their bound variables, and by the
+/// ids of their free variables as long as each id of one is always paired with the same id of the
+/// other.
+pub fn alpha_eq(expr1: &Expr, expr2: &Expr) -> bool {
+    to_debruijn(expr1) == to_debruijn(expr2)
+}
+
+/// The variables in scope, innermost last, and the free variables seen so far.
+#[derive(Debug, Default)]
+struct Scope<'a> {
+    bound: Vec<&'a Variable>,
+    free: Vec<&'a Variable>,
+}
+
+fn debruijn<'a>(expr: &'a Expr, scope: &mut Scope<'a>) -> Term {
+    grow(|| {
+        let mut boxed = |expr| Box::new(debruijn(expr, scope));
+        match expr {
+            Expr::Var(var) => match scope.bound.iter().rev().position(|bound| *bound == var) {
+                Some(index) => Term::Bound(index),
+                None => match scope.free.iter().position(|free| *free == var) {
+                    Some(index) => Term::Free(var.name.clone(), index),
+                    None => {
+                        scope.free.push(var);
+                        Term::Free(var.name.clone(), scope.free.len() - 1)
+                    }
+                },
+            },
+            Expr::Abs(var, t, body) => Term::Abs(t.clone(), bind(&[var], body, scope)),
+            Expr::App(e1, e2) => Term::App(boxed(e1), boxed(e2)),
+            Expr::Number(n) => Term::Number(*n),
+            Expr::Unit => Term::Unit,
+            Expr::Prim(prim) => Term::Prim(*prim),
+            Expr::Seq(e1, e2) => Term::Seq(boxed(e1), boxed(e2)),
+            Expr::BOp(op, e1, e2) => Term::BOp(op.clone(), boxed(e1), boxed(e2)),
+            Expr::Tuple(es) => Term::Tuple(es.iter().map(|e| debruijn(e, scope)).collect()),
+            Expr::Project(e, index) => Term::Project(boxed(e), *index),
+            Expr::Inl(e) => Term::Inl(boxed(e)),
+            Expr::Inr(e) => Term::Inr(boxed(e)),
+            Expr::Case(e, var1, e1, var2, e2) => {
+                let e = debruijn(e, scope);
+                Term::Case(
+                    Box::new(e),
+                    bind(&[var1], e1, scope),
+                    bind(&[var2], e2, scope),
+                )
+            }
+            Expr::Constr(name, es) => Term::Constr(
+                name.clone(),
+                es.iter().map(|e| debruijn(e, scope)).collect(),
+            ),
+            Expr::Match(e, arms) => {
+                let e = debruijn(e, scope);
+                let arms = arms
+                    .iter()
+                    .map(|(pattern, e)| {
+                        let mut vars = Vec::new();
+                        let pattern = debruijn_pattern(pattern, &mut vars);
+                        (pattern, *bind(&vars, e, scope))
+                    })
+                    .collect();
+                Term::Match(Box::new(e), arms)
+            }
+            Expr::Annot(e, t) => Term::Annot(boxed(e), t.clone()),
+            Expr::Ref(e) => Term::Ref(boxed(e)),
+            Expr::Deref(e) => Term::Deref(boxed(e)),
+            Expr::Assign(e1, e2) => Term::Assign(boxed(e1), boxed(e2)),
+            // the variable only names the type of the raise, the expression can not refer to it
+            Expr::Raise(e, _) => Term::Raise(boxed(e)),
+            Expr::Try(e, var, handler) => {
+                let e = debruijn(e, scope);
+                Term::Try(Box::new(e), bind(&[var], handler, scope))
+            }
+        }
+    })
+}
+
+/// Converts `body` in the scope of `vars`, bound from left to right.
+fn bind<'a>(vars: &[&'a Variable], body: &'a Expr, scope: &mut Scope<'a>) -> Box<Term> {
+    scope.bound.extend(vars);
+    let body = debruijn(body, scope);
+    scope.bound.truncate(scope.bound.len() - vars.len());
+    Box::new(body)
+}
+
+fn debruijn_pattern<'a>(pattern: &'a Pattern, vars: &mut Vec<&'a Variable>) -> TermPattern {
+    match pattern {
+        Pattern::Wildcard => TermPattern::Wildcard,
+        Pattern::Var(var) => {
+            vars.push(var);
+            TermPattern::Var
+        }
+        Pattern::Tuple(patterns) => TermPattern::Tuple(
+            patterns
+                .iter()
+                .map(|pattern| debruijn_pattern(pattern, vars))
+                .collect(),
+        ),
+        Pattern::Constr(name, patterns) => TermPattern::Constr(
+            name.clone(),
+            patterns
+                .iter()
+                .map(|pattern| debruijn_pattern(pattern, vars))
+                .collect(),
+        ),
+    }
+}
+
+/// Whether the a-normal forms only differ by the names and ids of the variables they bind. The
+/// variables they use without binding must have the same names, and their ids are paired like
+/// those of bound variables.
+pub fn anfs_alpha_eq(anfs1: &ANFs, anfs2: &ANFs) -> bool {
+    Renaming::default().anfs(anfs1, anfs2)
+}
+
+/// Pairs the ids of the variables of one a-normal form with those of the other. Every variable of
+/// an a-normal form has an id of its own, so there is no shadowing to track.
+#[derive(Debug, Default)]
+struct Renaming {
+    left: HashMap<usize, usize>,
+    right: HashMap<usize, usize>,
+}
+
+impl Renaming {
+    fn pair(&mut self, var1: &Variable, var2: &Variable) -> bool {
+        match (self.left.get(&var1.id), self.right.get(&var2.id)) {
+            (Some(id2), Some(id1)) => *id2 == var2.id && *id1 == var1.id,
+            (None, None) => {
+                self.left.insert(var1.id, var2.id);
+                self.right.insert(var2.id, var1.id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn binder(&mut self, var1: &Variable, var2: &Variable) -> bool {
+        self.pair(var1, var2)
+    }
+
+    /// A variable used before it is bound is free, so its name has to match.
+    fn var(&mut self, var1: &Variable, var2: &Variable) -> bool {
+        let free = !self.left.contains_key(&var1.id) && !self.right.contains_key(&var2.id);
+        (!free || var1.name == var2.name) && self.pair(var1, var2)
+    }
+
+    fn binders(&mut self, vars1: &[Variable], vars2: &[Variable]) -> bool {
+        vars1.len() == vars2.len()
+            && vars1
+                .iter()
+                .zip(vars2)
+                .all(|(var1, var2)| self.binder(var1, var2))
+    }
+
+    fn value(&mut self, value1: &Value, value2: &Value) -> bool {
+        match (value1, value2) {
+            (Value::Number(n1), Value::Number(n2)) => n1 == n2,
+            (Value::Unit, Value::Unit) => true,
+            (Value::Var(var1), Value::Var(var2)) | (Value::Global(var1), Value::Global(var2)) => {
+                self.var(var1, var2)
+            }
+            _ => false,
+        }
+    }
+
+    fn values(&mut self, values1: &[Value], values2: &[Value]) -> bool {
+        values1.len() == values2.len()
+            && values1
+                .iter()
+                .zip(values2)
+                .all(|(value1, value2)| self.value(value1, value2))
+    }
+
+    fn anfs(&mut self, anfs1: &ANFs, anfs2: &ANFs) -> bool {
+        anfs1.level == anfs2.level
+            && anfs1.anfs.len() == anfs2.anfs.len()
+            && anfs1
+                .anfs
+                .iter()
+                .zip(&anfs2.anfs)
+                .all(|(anf1, anf2)| self.anf(anf1, anf2))
+            && match (&anfs1.value, &anfs2.value) {
+                (Some(value1), Some(value2)) => self.value(value1, value2),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+
+    fn anf(&mut self, anf1: &ANF, anf2: &ANF) -> bool {
+        grow(|| match (anf1, anf2) {
+            (ANF::Fun(var1, params1, body1), ANF::Fun(var2, params2, body2)) => {
+                self.binder(var1, var2) && self.binders(params1, params2) && self.anfs(body1, body2)
+            }
+            (ANF::App(var1, f1, args1), ANF::App(var2, f2, args2)) => {
+                self.var(f1, f2) && self.values(args1, args2) && self.binder(var1, var2)
+            }
+            (ANF::BOp(var1, op1, x1, y1), ANF::BOp(var2, op2, x2, y2)) => {
+                op1 == op2 && self.value(x1, x2) && self.value(y1, y2) && self.binder(var1, var2)
+            }
+            (ANF::Tuple(var1, values1), ANF::Tuple(var2, values2)) => {
+                self.values(values1, values2) && self.binder(var1, var2)
+            }
+            (ANF::Project(var1, x1, i1), ANF::Project(var2, x2, i2)) => {
+                i1 == i2 && self.var(x1, x2) && self.binder(var1, var2)
+            }
+            (ANF::Store(var1, x1, i1, value1), ANF::Store(var2, x2, i2, value2)) => {
+                i1 == i2
+                    && self.var(x1, x2)
+                    && self.value(value1, value2)
+                    && self.binder(var1, var2)
+            }
+            (ANF::Prim(var1, prim1, args1), ANF::Prim(var2, prim2, args2)) => {
+                prim1 == prim2 && self.values(args1, args2) && self.binder(var1, var2)
+            }
+            (ANF::Switch(var1, value1, branches1), ANF::Switch(var2, value2, branches2)) => {
+                self.value(value1, value2)
+                    && branches1.len() == branches2.len()
+                    && branches1
+                        .iter()
+                        .zip(branches2)
+                        .all(|(branch1, branch2)| self.anfs(branch1, branch2))
+                    && self.binder(var1, var2)
+            }
+            (ANF::Trap(var1), ANF::Trap(var2)) => self.binder(var1, var2),
+            (ANF::Raise(var1, value1), ANF::Raise(var2, value2)) => {
+                self.value(value1, value2) && self.binder(var1, var2)
+            }
+            (ANF::Try(var1, body1, exn1, handler1), ANF::Try(var2, body2, exn2, handler2)) => {
+                self.anfs(body1, body2)
+                    && self.binder(exn1, exn2)
+                    && self.anfs(handler1, handler2)
+                    && self.binder(var1, var2)
+            }
+            _ => false,
+        })
+    }
+}
@@ -0,0 +1,273 @@
+use core::fmt;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    alpha::{AlphaConvEnv, AlphaError, AlphaWarning},
+    anf::{ANFConverter, ANFs, HoistedANFs},
+    ast::{Program, Span, Variable},
+    compile::emit_llvm_ir,
+    module::{ModuleError, ModuleLoader},
+    parser::{line_col, parse_program, SyntaxError},
+    pattern::MatchWarning,
+    prelude::prelude,
+    typeinfer::{Interface, Type, TypeError, TypeInfer},
+    verify::{verify_anfs, verify_hoisted, Stage, VerifyError},
+    wasm_compile::WasmCompiler,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Llvm,
+    Wasm,
+}
+
+/// Selects the backend and which intermediate representations are kept in the [`Artifact`].
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// `None` stops after hoisting, e.g. when the caller drives its own backend
+    pub backend: Option<Backend>,
+    pub ast: bool,
+    pub alpha: bool,
+    pub anf: bool,
+    pub closure: bool,
+    /// check the invariants of each a-normal form stage
+    pub verify: bool,
+    /// abort on integer overflow, division is checked regardless
+    pub checked_arith: bool,
+    /// compile without the prelude, see `prelude.stlc`
+    pub no_prelude: bool,
+    /// warn about shadowed binders and unused parameters
+    pub lint: bool,
+    /// the file the program was read from, imports are relative to its directory or else to the
+    /// current one
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub ast: Option<Program>,
+    pub alpha: Option<Program>,
+    pub ty: Type,
+    pub anf: Option<ANFs>,
+    pub closure: Option<ANFs>,
+    /// always kept, since it is what every backend consumes
+    pub hoisted: HoistedANFs,
+    /// llvm ir or wasm text, depending on the backend
+    pub output: Option<String>,
+    /// the interface of each imported module, in the order they are linked
+    pub interfaces: Vec<(String, Interface)>,
+    pub warnings: Vec<MatchWarning>,
+    /// only found with `Options::lint`
+    pub lints: Vec<(Location, AlphaWarning)>,
+}
+
+/// A position in the program or in a module it imports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// `None` for a program that is not read from a file
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    fn new(path: Option<&Path>, source: &str, span: Span) -> Location {
+        let location = line_col(source, span.start);
+        Location {
+            path: path.map(Path::to_owned),
+            line: location.line,
+            column: location.column,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// every declaration with a syntax error, in source order
+    Syntax(Vec<SyntaxError>),
+    Module(ModuleError),
+    Alpha(Location, AlphaError),
+    Type(TypeError),
+    Verify(Stage, VerifyError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Syntax(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "syntax error at {}", err)?;
+                }
+                Ok(())
+            }
+            CompileError::Module(err) => write!(f, "{}", err),
+            CompileError::Alpha(location, err) => write!(f, "{}: {}", location, err),
+            CompileError::Type(err) => write!(f, "type error: {}", err),
+            CompileError::Verify(stage, err) => write!(f, "invalid {}: {}", stage, err),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+pub fn compile(source: &str, options: Options) -> Result<Artifact, CompileError> {
+    let program = parse_program(source).map_err(CompileError::Syntax)?;
+    let parsed_ast = options.ast.then(|| program.clone());
+    let mut loader = ModuleLoader::new();
+    let dir = match options.path.as_ref().and_then(|path| path.parent()) {
+        Some(dir) => dir.to_owned(),
+        None => PathBuf::from("."),
+    };
+    let program = loader
+        .load_imports(program, &dir)
+        .map_err(CompileError::Module)?;
+    let mut modules = loader.modules;
+    let prelude = (!options.no_prelude).then(prelude);
+    let mut alpha_conv_env = match &prelude {
+        Some(prelude) => AlphaConvEnv::with_globals(prelude.next_var, &prelude.namespace),
+        None => AlphaConvEnv::new(),
+    };
+    if options.lint {
+        alpha_conv_env.enable_warnings();
+    }
+    // the warnings of each file are located in it once it is converted
+    let mut lints = Vec::new();
+    let mut locate_lints = |path: Option<&Path>, source: &str| {
+        for warning in alpha_conv_env.warnings().into_iter().skip(lints.len()) {
+            lints.push((Location::new(path, source, warning.span()), warning));
+        }
+    };
+    for module in &mut modules {
+        module.program = alpha_conv_env
+            .module_conversion(&module.name, module.program.clone())
+            .map_err(|err| alpha_error(Some(&module.path), &module.source, err))?;
+        locate_lints(Some(&module.path), &module.source);
+    }
+    let ast = alpha_conv_env
+        .program_conversion(program)
+        .map_err(|err| alpha_error(options.path.as_deref(), source, err))?;
+    locate_lints(options.path.as_deref(), source);
+    let alpha = options.alpha.then(|| ast.clone());
+    let mut typeinfer = TypeInfer::new(alpha_conv_env.id());
+    typeinfer.instances = alpha_conv_env.instances();
+    // the program is linked into one, alpha conversion qualified the types and constructors of each
+    // module by its name
+    let mut types = Vec::new();
+    let mut exceptions = Vec::new();
+    if let Some(prelude) = &prelude {
+        prelude.declare(&mut typeinfer);
+        types.extend(prelude.types.iter().cloned());
+        exceptions.extend(prelude.exceptions.iter().cloned());
+    }
+    for program in modules.iter().map(|module| &module.program).chain([&ast]) {
+        types.extend(program.types());
+        exceptions.extend(program.exceptions());
+    }
+    typeinfer
+        .data_types
+        .declare(&types, &exceptions)
+        .map_err(CompileError::Type)?;
+    let mut interfaces = Vec::new();
+    for module in &modules {
+        typeinfer
+            .infer_program(&module.program)
+            .map_err(CompileError::Type)?;
+        interfaces.push((module.name.clone(), typeinfer.interface(&module.program)));
+    }
+    let ty = typeinfer
+        .infer_program(&ast)
+        .map_err(CompileError::Type)?
+        .simplify();
+    let mut anfconverter =
+        ANFConverter::new(alpha_conv_env.id(), &typeinfer.env, &typeinfer.data_types);
+    anfconverter.instances = typeinfer.instances.clone();
+    if let Some(prelude) = &prelude {
+        prelude.declare_functions(&mut anfconverter);
+    }
+    let mut anfs = ANFs {
+        anfs: Vec::new(),
+        value: None,
+        level: 0,
+    };
+    for module in modules {
+        anfconverter.convert_module(&module.name, module.program, &mut anfs);
+    }
+    anfconverter.convert_program(ast, &mut anfs);
+    // the top-level names of the prelude are bound once it is linked
+    let globals = prelude.iter().flat_map(|prelude| {
+        prelude.namespace.values.iter().map(|(name, id)| Variable {
+            name: name.clone(),
+            id: *id,
+            span: Span::default(),
+        })
+    });
+    let globals = globals.collect::<Vec<_>>();
+    if options.verify {
+        verify_anfs(&anfs, Stage::Anf, &globals)
+            .map_err(|err| CompileError::Verify(Stage::Anf, err))?;
+    }
+    let anf = options.anf.then(|| anfs.clone());
+    let anfs = anfconverter.closure_conversion(anfs);
+    if options.verify {
+        verify_anfs(&anfs, Stage::Closure, &globals)
+            .map_err(|err| CompileError::Verify(Stage::Closure, err))?;
+    }
+    let closure = options.closure.then(|| anfs.clone());
+    let mut hoisted = HoistedANFs {
+        fun_defs: Vec::new(),
+        main: ANFs {
+            anfs: Vec::new(),
+            value: None,
+            level: 1,
+        },
+        types: HashMap::new(),
+        exports: Vec::new(),
+    };
+    anfconverter.hoisting(anfs, &mut hoisted);
+    if let Some(prelude) = &prelude {
+        prelude.link(&mut hoisted);
+    }
+    if options.verify {
+        verify_hoisted(&hoisted).map_err(|err| CompileError::Verify(Stage::Hoisted, err))?;
+    }
+    let output = match options.backend {
+        Some(Backend::Llvm) => Some(emit_llvm_ir(hoisted.clone(), options.checked_arith)),
+        Some(Backend::Wasm) => {
+            let mut wasm_compiler = WasmCompiler::new(options.checked_arith);
+            wasm_compiler.compile(hoisted.clone());
+            Some(wasm_compiler.program)
+        }
+        None => None,
+    };
+    Ok(Artifact {
+        ast: parsed_ast,
+        alpha,
+        ty,
+        anf,
+        closure,
+        hoisted,
+        output,
+        interfaces,
+        warnings: anfconverter.warnings,
+        lints,
+    })
+}
+
+fn alpha_error(path: Option<&Path>, source: &str, err: AlphaError) -> CompileError {
+    CompileError::Alpha(Location::new(path, source, err.span()), err)
+}
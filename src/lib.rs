@@ -2,6 +2,17 @@ pub mod alpha;
 pub mod anf;
 pub mod ast;
 pub mod compile;
+pub mod debruijn;
+pub mod driver;
+pub mod module;
 pub mod parser;
+pub mod pattern;
+pub mod prelude;
+pub mod pretty;
+pub mod runtime;
+pub mod stack;
 pub mod typeinfer;
+pub mod verify;
 pub mod wasm_compile;
+
+pub use driver::{compile, Artifact, Backend, CompileError, Options};
@@ -1,16 +1,21 @@
-use inkwell::context::Context;
+use std::path::PathBuf;
+
+use inkwell::{
+    context::Context,
+    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
+    OptimizationLevel,
+};
 use simply_typed_lambda_calculus_compiler::{
-    alpha::AlphaConvEnv,
-    anf::{ANFConverter, ANFs, HoistedANFs},
-    compile::LLVMCompiler,
-    parser::expr_parser,
-    typeinfer::TypeInfer,
-    wasm_compile::WasmCompiler,
+    anf::Typed, compile, compile::LLVMCompiler, pretty::format_source, runtime, Backend,
+    CompileError, Options,
 };
-use structopt::StructOpt;
+use structopt::{clap::AppSettings, StructOpt};
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "simply_typed_lambda_calculus_compiler")]
+#[structopt(
+    name = "simply_typed_lambda_calculus_compiler",
+    setting = AppSettings::SubcommandsNegateReqs
+)]
 struct Opt {
     #[structopt(long)]
     ast: bool,
@@ -40,12 +45,84 @@ struct Opt {
     #[structopt(short, long)]
     wasm: bool,
 
-    program: String,
+    /// write a native object file to link with runtime/runtime.c instead of running the program
+    #[structopt(long, parse(from_os_str))]
+    object: Option<PathBuf>,
+
+    /// check the invariants of each a-normal form stage
+    #[structopt(long)]
+    verify: bool,
+
+    /// abort on integer overflow instead of wrapping around
+    #[structopt(long)]
+    checked_arith: bool,
+
+    /// do not import the prelude implicitly
+    #[structopt(long)]
+    no_prelude: bool,
+
+    /// warn about shadowed binders and unused parameters
+    #[structopt(long)]
+    lint: bool,
+
+    /// read the program from a file instead of the command line
+    #[structopt(short, long, parse(from_os_str), conflicts_with = "program")]
+    file: Option<PathBuf>,
+
+    #[structopt(required_unless = "file")]
+    program: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// reformat source files in place
+    Fmt {
+        /// the width lines are broken to fit in, where possible
+        #[structopt(long, default_value = "100")]
+        width: usize,
+
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+/// Reformats each file, reporting the ones that can not be and going on with the others.
+fn format_files(width: usize, files: &[PathBuf]) -> bool {
+    let mut ok = true;
+    for path in files {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                ok = false;
+                continue;
+            }
+        };
+        match format_source(&source, width) {
+            Ok(formatted) if formatted == source => (),
+            Ok(formatted) => {
+                if let Err(err) = std::fs::write(path, formatted) {
+                    eprintln!("{}: {}", path.display(), err);
+                    ok = false;
+                }
+            }
+            Err(err) => {
+                for line in err.to_string().lines() {
+                    eprintln!("{}:{}", path.display(), line);
+                }
+                ok = false;
+            }
+        }
+    }
+    ok
 }
 
 fn main() {
     let Opt {
-        ast: _ast,
+        ast,
         alpha,
         type_,
         anf,
@@ -53,71 +130,133 @@ fn main() {
         hoist,
         llvm,
         wasm,
+        object,
+        verify,
+        checked_arith,
+        no_prelude,
+        lint,
+        file,
         program,
+        command,
     } = Opt::from_args();
-    let ast = expr_parser::expr(&program).unwrap();
-    if _ast {
-        println!("ast:\n{:?}\n", &ast);
+    if let Some(Command::Fmt { width, files }) = command {
+        let ok = format_files(width, &files);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    let program = match &file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(program) => program,
+            Err(err) => {
+                eprintln!("{}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        },
+        None => program.unwrap(),
+    };
+    let options = Options {
+        backend: if wasm { Some(Backend::Wasm) } else { None },
+        ast,
+        alpha,
+        anf,
+        closure,
+        verify,
+        checked_arith,
+        no_prelude,
+        lint,
+        path: file.clone(),
+    };
+    let artifact = match compile(&program, options) {
+        Ok(artifact) => artifact,
+        Err(CompileError::Syntax(errors)) => {
+            let name = file
+                .as_ref()
+                .map_or("<program>".into(), |path| path.display().to_string());
+            for err in errors {
+                eprintln!("{}:{}", name, err);
+            }
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    for warning in &artifact.warnings {
+        eprintln!("warning: {}", warning);
     }
-    let alpha_conv_env = AlphaConvEnv::new();
-    let ast = alpha_conv_env.alpha_conversion(ast).unwrap();
-    if alpha {
-        println!("alpha converted:\n{:?}\n", &ast);
+    for (location, warning) in &artifact.lints {
+        eprintln!("warning: {}: {}", location, warning);
+    }
+    if let Some(ast) = &artifact.ast {
+        println!("ast:\n{}\n", ast);
+    }
+    if let Some(ast) = &artifact.alpha {
+        println!("alpha converted:\n{:#}\n", ast);
     }
-    let mut typeinfer = TypeInfer::new(alpha_conv_env.id());
-    let ty = typeinfer.type_infer(&ast).unwrap();
     if type_ {
-        println!("Type: {:?}\n", ty.simplify());
+        for (name, interface) in &artifact.interfaces {
+            println!("module {}:\n{}", name, interface);
+        }
+        println!("Type: {:?}\n", artifact.ty);
     }
-    let mut anfconverter = ANFConverter::new(alpha_conv_env.id());
-    let mut anfs = ANFs {
-        anfs: Vec::new(),
-        value: None,
-        level: 0,
-    };
-    anfconverter.convert(ast, &mut anfs);
-    if anf {
-        println!("ANF:{}\n", &anfs);
+    if let Some(anfs) = &artifact.anf {
+        println!("ANF:{}\n", Typed(anfs, &artifact.hoisted.types));
     }
-    let anfs = anfconverter.closure_conversion(anfs);
-    if closure {
-        println!("closure converted ANF:{}\n", &anfs);
+    if let Some(anfs) = &artifact.closure {
+        println!(
+            "closure converted ANF:{}\n",
+            Typed(anfs, &artifact.hoisted.types)
+        );
     }
-    let mut hoisted_anfs = HoistedANFs {
-        fun_defs: Vec::new(),
-        main: ANFs {
-            anfs: Vec::new(),
-            value: None,
-            level: 1,
-        },
-    };
-    anfconverter.hoisting(anfs, &mut hoisted_anfs);
     if hoist {
-        println!("hoisted ANF:\n{}\n", &hoisted_anfs);
+        println!("hoisted ANF:\n{}\n", &artifact.hoisted);
     }
-    if wasm {
-        let mut wasm_compiler = WasmCompiler::new();
-        wasm_compiler.compile(hoisted_anfs);
-        println!("{}", wasm_compiler.program);
+    if let Some(output) = artifact.output {
+        println!("{}", output);
         return;
     }
     let context = Context::create();
     let builder = context.create_builder();
     let module = context.create_module("main");
-    let llvm_compiler = LLVMCompiler::new(&context, &builder, &module);
-    llvm_compiler.compile(hoisted_anfs);
+    let llvm_compiler = LLVMCompiler::new(&context, &builder, &module, checked_arith);
+    llvm_compiler.compile(artifact.hoisted);
     if llvm {
         llvm_compiler.module.print_to_stderr();
     }
+    if let Some(path) = object {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let triple = TargetMachine::get_default_triple();
+        let target_machine = Target::from_triple(&triple)
+            .unwrap()
+            .create_target_machine(
+                &triple,
+                &TargetMachine::get_host_cpu_name().to_string(),
+                &TargetMachine::get_host_cpu_features().to_string(),
+                OptimizationLevel::Aggressive,
+                RelocMode::PIC,
+                CodeModel::Default,
+            )
+            .unwrap();
+        module.set_triple(&triple);
+        module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+        target_machine
+            .write_to_file(&module, FileType::Object, &path)
+            .unwrap();
+        return;
+    }
     let execution_engine = llvm_compiler
         .module
-        .create_jit_execution_engine(inkwell::OptimizationLevel::Aggressive)
+        .create_jit_execution_engine(OptimizationLevel::Aggressive)
         .unwrap();
+    for (symbol, address) in runtime::symbols() {
+        let fun = llvm_compiler.module.get_function(symbol).unwrap();
+        execution_engine.add_global_mapping(&fun, address);
+    }
+    // main prints the result itself, so the output is the same as that of the native executable
     unsafe {
-        let r = execution_engine
-            .get_function::<unsafe extern "C" fn() -> i64>("main")
+        execution_engine
+            .get_function::<unsafe extern "C" fn() -> i32>("main")
             .unwrap()
             .call();
-        println!("{:?}", r);
     }
 }
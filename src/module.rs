@@ -0,0 +1,120 @@
+//! Loads the modules a program imports, so they can be compiled with it into one program.
+
+use core::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    ast::{Decl, Program},
+    parser::{parse_program, SyntaxError},
+    prelude,
+};
+
+/// A file loaded by `import`, named after its file stem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub name: String,
+    pub path: PathBuf,
+    /// kept to locate the errors found after parsing
+    pub source: String,
+    pub program: Program,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    Read(PathBuf, String),
+    Syntax(PathBuf, Vec<SyntaxError>),
+    /// the module imports itself, directly or through other modules
+    Cycle(PathBuf),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::Read(path, err) => write!(f, "{}: {}", path.display(), err),
+            ModuleError::Syntax(path, errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}:{}", path.display(), err)?;
+                }
+                Ok(())
+            }
+            ModuleError::Cycle(path) => write!(f, "{} imports itself", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+#[derive(Debug, Default)]
+pub struct ModuleLoader {
+    /// every module loaded so far, each after the modules it imports
+    pub modules: Vec<Module>,
+    /// the modules whose imports are being loaded
+    loading: Vec<PathBuf>,
+}
+
+impl ModuleLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the modules `program` imports, with paths relative to `dir`, and replaces each path by
+    /// the name of the module.
+    pub fn load_imports(
+        &mut self,
+        mut program: Program,
+        dir: &Path,
+    ) -> Result<Program, ModuleError> {
+        for decl in &mut program.decls {
+            if let Decl::Import(path) = decl {
+                *path = self.load(&dir.join(&*path))?;
+            }
+        }
+        Ok(program)
+    }
+
+    /// Returns the name of the module at `path`, loading it unless it has been already.
+    fn load(&mut self, path: &Path) -> Result<String, ModuleError> {
+        let read_error = |err: std::io::Error| ModuleError::Read(path.to_owned(), err.to_string());
+        let path = path.canonicalize().map_err(read_error)?;
+        if let Some(module) = self.modules.iter().find(|module| module.path == path) {
+            return Ok(module.name.clone());
+        }
+        if self.loading.contains(&path) {
+            return Err(ModuleError::Cycle(path));
+        }
+        let source = std::fs::read_to_string(&path).map_err(read_error)?;
+        let program =
+            parse_program(&source).map_err(|errors| ModuleError::Syntax(path.clone(), errors))?;
+        self.loading.push(path.clone());
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let program = self.load_imports(program, dir)?;
+        self.loading.pop();
+        let name = self.module_name(&path);
+        self.modules.push(Module {
+            name: name.clone(),
+            path,
+            source,
+            program,
+        });
+        Ok(name)
+    }
+
+    /// The file stem, numbered if another module or the prelude has the same one, since it prefixes
+    /// the symbols and types of the module.
+    fn module_name(&self, path: &Path) -> String {
+        let stem = path
+            .file_stem()
+            .map_or("module".into(), |stem| stem.to_string_lossy())
+            .replace(|c: char| !(c.is_alphanumeric() || c == '_'), "_");
+        let mut name = stem.clone();
+        let mut n = 1;
+        while name == prelude::MODULE || self.modules.iter().any(|module| module.name == name) {
+            n += 1;
+            name = format!("{}_{}", stem, n);
+        }
+        name
+    }
+}
@@ -1,30 +1,523 @@
-use crate::ast::*;
-use peg;
+use core::fmt;
+use std::collections::BTreeSet;
+
+use crate::{ast::*, stack::grow};
+use peg::{
+    self,
+    error::{ErrorState, ParseError},
+    str::LineCol,
+    ParseLiteral, RuleResult,
+};
+
+/// Words that can not be used as variable names, including some reserved for future syntax.
+pub const KEYWORDS: &[&str] = &[
+    "fst",
+    "snd",
+    "inl",
+    "inr",
+    "case",
+    "of",
+    "match",
+    "with",
+    "type",
+    "ref",
+    "raise",
+    "try",
+    "exception",
+    "import",
+    "land",
+    "lor",
+    "lxor",
+    "let",
+    "rec",
+    "in",
+    "if",
+    "then",
+    "else",
+];
+
+/// Wraps a rule passed as an argument, see the `grown` rule, so that it runs on a stack that grows,
+/// see `stack::grow`. peg has no hook of its own for this.
+fn grow_rule<'input, I: ?Sized, S, T>(
+    rule: impl Fn(&'input I, &mut S, &mut ErrorState, usize) -> RuleResult<T>,
+) -> impl Fn(&'input I, &mut S, &mut ErrorState, usize) -> RuleResult<T> {
+    move |input, state, err_state, pos| grow(|| rule(input, state, err_state, pos))
+}
 
 peg::parser! {
     pub grammar expr_parser() for str {
-        rule _ = quiet!{[' ' | '\n' | '\t']*}
+        rule _ = quiet!{([' ' | '\n' | '\t'] / comment())*}
+
+        /// `(* ... *)`, which do not nest.
+        rule comment() = "(*" (!"*)" [_])* "*)"
 
+        // the leading whitespace stays outside of `quiet!`, so the name is reported at the position
+        // the other alternatives fail at
         rule number() -> Expr
-            = _ n:$(['0'..='9']+) _ {? n.parse().map(|n| Expr::Number(n)).or(Err("number"))}
+            = _ e:(quiet!{n:$("-"? ['0'..='9']+) _ {? n.parse().map(|n| Expr::Number(n)).or(Err("number"))}}
+                   / expected!("number")) { e }
+
+        rule ident_char() = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '\'']
 
+        rule keyword(kw: &'static str)
+            = _ (quiet!{##parse_string_literal(kw) !ident_char() _} / expected!(kw))
+
+        rule index() -> usize
+            = n:$(['0'..='9']+) _ {? n.parse().or(Err("index"))}
+
+        /// `_` alone is the wildcard pattern. Names starting with an uppercase letter are
+        /// constructors, never variables, so `\X. X` is a syntax error.
         rule identifier() -> Variable
-            = _ s:$(['a'..='z' | 'A'..='Z']+) _ { Variable { name: s.to_owned(), id: 0 } }
+            = _ start:position!() v:(quiet!{!("_" !ident_char()) s:$(['a'..='z' | '_'] ident_char()*) _ {?
+                if KEYWORDS.contains(&s) {
+                    Err("identifier")
+                } else {
+                    let span = Span { start, end: start + s.len() };
+                    Ok(Variable { name: s.to_owned(), id: 0, span })
+                }
+            }} / expected!("identifier")) { v }
+
+        /// `m.f` is the value `f` of the imported module `m`, written without spaces.
+        rule variable() -> Variable
+            = quiet!{_ start:position!() m:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+) "."
+                     dot:position!() v:identifier() {?
+                if v.span.start == dot {
+                    let span = Span { start, end: v.span.end };
+                    Ok(Variable { name: format!("{}.{}", m, v.name), id: 0, span })
+                } else {
+                    Err("variable")
+                }
+            }}
+            / identifier()
+
+        rule constructor() -> String
+            = _ c:(quiet!{s:$(['A'..='Z'] ident_char()*) _ { s.to_owned() }} / expected!("constructor")) {
+                c
+            }
+
+        rule type_name() -> String
+            = v:identifier() { v.name }
+
+        /// Calls a rule passed as a Rust function.
+        rule call<T>(r: rule<T>) -> T = r()
+
+        /// Runs the rule on a stack that grows, at each level of nesting of a recursive rule.
+        rule grown<T>(r: rule<T>) -> T = call(grow_rule(r))
+
+        rule ty() -> TypeExpr = grown(<type_arrow()>)
+
+        rule type_arrow() -> TypeExpr
+            = t1:type_sum() t2:("->" t:ty() { t })? {
+                match t2 {
+                    Some(t2) => TypeExpr::Arrow(Box::new(t1), Box::new(t2)),
+                    None => t1,
+                }
+            }
+
+        rule type_sum() -> TypeExpr
+            = t:type_product() ts:("+" t:type_product() { t })* {
+                ts.into_iter().fold(t, |t1, t2| TypeExpr::Sum(Box::new(t1), Box::new(t2)))
+            }
+
+        rule type_product() -> TypeExpr
+            = ts:(type_postfix() ++ "*") {
+                if ts.len() == 1 {
+                    ts.into_iter().next().unwrap()
+                } else {
+                    TypeExpr::Product(ts)
+                }
+            }
+
+        rule type_postfix() -> TypeExpr
+            = t:type_atom() refs:(keyword("ref") { })* {
+                refs.iter().fold(t, |t, _| TypeExpr::Ref(Box::new(t)))
+            }
+
+        rule type_atom() -> TypeExpr
+            = quiet!{
+                keyword("int") { TypeExpr::Int }
+                / keyword("unit") { TypeExpr::Unit }
+                / name:type_name() { TypeExpr::Name(name) }
+                / _ "(" t:ty() ")" _ { t }
+            }
+            / expected!("type")
+
+        rule constructor_decl() -> (String, Vec<TypeExpr>)
+            = name:constructor() fields:(keyword("of") ts:(type_postfix() ++ "*") { ts })? {
+                (name, fields.unwrap_or_default())
+            }
+
+        rule type_decl() -> TypeDecl
+            = keyword("type") name:type_name() "=" _ "|"? constructors:(constructor_decl() ++ "|") {
+                TypeDecl { name, constructors }
+            }
 
-        pub rule expr() -> Expr = precedence! {
+        /// `;;` may end any declaration, a main expression after a `let` needs it.
+        rule decl() -> Decl = d:decl_kind() (";;" _)? { d }
+
+        rule decl_kind() -> Decl
+            = t:type_decl() { Decl::Type(t) }
+            / keyword("exception") c:constructor_decl() { Decl::Exception(c.0, c.1) }
+            / keyword("let") keyword("rec") f:identifier() e:let_body() {?
+                match e {
+                    Expr::Abs(..) => Ok(Decl::LetRec(f, e)),
+                    _ => Err("function"),
+                }
+            }
+            / keyword("let") x:identifier() e:let_body() { Decl::Let(x, e) }
+            / keyword("import") path:string() { Decl::Import(path) }
+
+        rule string() -> String
+            = _ s:(quiet!{"\"" s:$([^ '"' | '\n']*) "\"" _ { s.to_owned() }} / expected!("string")) {
+                s
+            }
+
+        /// `x (y : t) = e` is `\x (y : t). e`.
+        rule let_body() -> Expr
+            = bs:binder()* "=" e:expr() {
+                bs.into_iter()
+                    .rev()
+                    .fold(e, |e, (v, t)| Expr::Abs(v, t, Box::new(e)))
+            }
+
+        /// A single declaration, used to keep parsing after a syntax error.
+        pub rule declaration() -> Decl = d:decl() _ { d }
+
+        pub rule program() -> Program
+            = decls:decl()* main:expr()? _ { Program { decls, main } }
+
+        /// The offsets of the declarations and of the main expression, after the whitespace and
+        /// comments before them.
+        pub rule item_starts() -> Vec<usize>
+            = ds:(_ p:position!() decl() { p })* m:(_ p:position!() expr() { p })? _ {
+                ds.into_iter().chain(m).collect()
+            }
+
+        /// `C (p1, p2)` has two fields, a single field only needs parentheses when it is compound.
+        rule pattern() -> Pattern = grown(<pattern_constr()>)
+
+        rule pattern_constr() -> Pattern
+            = c:constructor() ps:pattern_args() { Pattern::Constr(c, ps) }
+            / pattern_atom()
+
+        rule pattern_args() -> Vec<Pattern>
+            = _ "(" ps:(pattern() ++ ",") ")" _ { ps }
+            / p:pattern_atom() { vec![p] }
+
+        rule pattern_atom() -> Pattern
+            = _ "_" !ident_char() _ { Pattern::Wildcard }
+            / c:constructor() { Pattern::Constr(c, Vec::new()) }
+            / name:identifier() { Pattern::Var(name) }
+            / _ "(" ps:(pattern() ++ ",") ")" _ {
+                if ps.len() == 1 {
+                    ps.into_iter().next().unwrap()
+                } else {
+                    Pattern::Tuple(ps)
+                }
+            }
+
+        rule binder() -> (Variable, Option<TypeExpr>)
+            = v:identifier() { (v, None) }
+            / _ "(" v:identifier() ":" t:ty() ")" _ { (v, Some(t)) }
+
+        rule arm() -> (Pattern, Expr)
+            = p:pattern() "->" e:expr() { (p, e) }
+
+        /// Every nested expression is parsed through here or through `expr_levels`' own recursion,
+        /// which only the prefix operators and `:=` use.
+        pub rule expr() -> Expr = grown(<expr_levels()>)
+
+        rule expr_levels() -> Expr = precedence! {
+            // a postfix operator, so that a long sequence recurses through `expr`
+            x:@ ";" y:expr() { Expr::Seq(Box::new(x), Box::new(y)) }
+            --
+            x:@ ":=" y:(@) { Expr::Assign(Box::new(x), Box::new(y)) }
+            --
+            x:(@) keyword("lor") y:@ { Expr::BOp(Operator::Or, Box::new(x), Box::new(y)) }
+            --
+            x:(@) keyword("lxor") y:@ { Expr::BOp(Operator::Xor, Box::new(x), Box::new(y)) }
+            --
+            x:(@) keyword("land") y:@ { Expr::BOp(Operator::And, Box::new(x), Box::new(y)) }
+            --
+            x:(@) "<<" y:@ { Expr::BOp(Operator::Shl, Box::new(x), Box::new(y)) }
+            x:(@) ">>>" y:@ { Expr::BOp(Operator::UShr, Box::new(x), Box::new(y)) }
+            x:(@) ">>" y:@ { Expr::BOp(Operator::Shr, Box::new(x), Box::new(y)) }
+            --
             x:(@) "+" y:@ { Expr::BOp(Operator::Add, Box::new(x), Box::new(y)) }
             x:(@) "-" y:@ { Expr::BOp(Operator::Sub, Box::new(x), Box::new(y)) }
             --
             x:(@) "*" y:@ { Expr::BOp(Operator::Mul, Box::new(x), Box::new(y)) }
             x:(@) "/" y:@ { Expr::BOp(Operator::Div, Box::new(x), Box::new(y)) }
+            x:(@) "%" y:@ { Expr::BOp(Operator::Mod, Box::new(x), Box::new(y)) }
+            --
+            // `-f x` negates the application, as in ML
+            _ "-" e:@ { Expr::BOp(Operator::Sub, Box::new(Expr::Number(0)), Box::new(e)) }
             --
-            x:(@) _ y:@ { Expr::App(Box::new(x), Box::new(y)) }
+            x:@ _ "(" es:(expr() ++ ",") ")" _ { apply_to_list(x, es) }
+            // prefix operators apply to every operand, so `a - b - c` would pass `-c` to `b`
+            x:(@) _ !"-" y:@ { apply(x, y) }
             --
-            _ "\\" v:identifier() "." e:expr() { Expr::Abs(v, Box::new(e)) }
+            keyword("fst") e:@ { Expr::Project(Box::new(e), 0) }
+            keyword("snd") e:@ { Expr::Project(Box::new(e), 1) }
+            keyword("inl") e:@ { Expr::Inl(Box::new(e)) }
+            keyword("inr") e:@ { Expr::Inr(Box::new(e)) }
+            keyword("ref") e:@ { Expr::Ref(Box::new(e)) }
+            _ "!" e:@ { Expr::Deref(Box::new(e)) }
+            keyword("raise") e:@ { Expr::Raise(Box::new(e), 0) }
+            --
+            e:@ "." n:index() { Expr::Project(Box::new(e), n) }
+            --
+            _ ("\\" / "λ") bs:binder()+ "." e:expr() {
+                bs.into_iter()
+                    .rev()
+                    .fold(e, |e, (v, t)| Expr::Abs(v, t, Box::new(e)))
+            }
+            keyword("case") e:expr() keyword("of")
+                keyword("inl") x:identifier() "->" e1:expr() "|"
+                keyword("inr") y:identifier() "->" e2:expr() {
+                Expr::Case(Box::new(e), x, Box::new(e1), y, Box::new(e2))
+            }
+            keyword("try") e:expr() keyword("with") x:identifier() "->" h:expr() {
+                Expr::Try(Box::new(e), x, Box::new(h))
+            }
+            keyword("match") e:expr() keyword("with") "|"? arms:(arm() ++ "|") {
+                Expr::Match(Box::new(e), arms)
+            }
             n:number() { n }
-            name:identifier() { Expr::Var(name) }
-            _ "(" e:expr() ")" _ { e }
+            name:variable() { Expr::Var(name) }
+            c:constructor() { Expr::Constr(c, Vec::new()) }
+            _ "(" _ ")" _ { Expr::Unit }
+            _ "(" es:(expr() ++ ",") t:(":" t:ty() { t })? ")" _ {
+                let e = if es.len() == 1 {
+                    es.into_iter().next().unwrap()
+                } else {
+                    Expr::Tuple(es)
+                };
+                match t {
+                    Some(t) => Expr::Annot(Box::new(e), t),
+                    None => e,
+                }
+            }
         }
 
     }
 }
+
+/// Applies `f` to `x`, unless `f` is a constructor written alone, which takes `x` as its field.
+/// Only the head of an application takes fields, so `f Nil x` passes `Nil` and `x` to `f`.
+fn apply(f: Expr, x: Expr) -> Expr {
+    match f {
+        Expr::Constr(c, fields) if fields.is_empty() => Expr::Constr(c, vec![x]),
+        f => Expr::App(Box::new(f), Box::new(x)),
+    }
+}
+
+/// Applies `f` to a list in parentheses, which holds every field when `f` is a constructor written
+/// alone, so that `Cons (x, t)` has two fields and `Box ((x, y))` one.
+fn apply_to_list(f: Expr, mut es: Vec<Expr>) -> Expr {
+    match f {
+        Expr::Constr(c, fields) if fields.is_empty() => Expr::Constr(c, es),
+        f if es.len() == 1 => Expr::App(Box::new(f), Box::new(es.pop().unwrap())),
+        f => Expr::App(Box::new(f), Box::new(Expr::Tuple(es))),
+    }
+}
+
+/// Keywords that start a declaration, where parsing resumes after a syntax error.
+const DECL_KEYWORDS: &[&str] = &["type", "exception", "let", "import"];
+
+/// Tokens that must be followed by an expression.
+const EXPR_PREFIXES: &[&str] = &[
+    "->", ":=", "<<", ">>>", ">>", ".", "(", ",", "=", "+", "-", "*", "/", "%", ";", "fst", "snd",
+    "inl", "inr", "ref", "!", "raise", "try", "match", "case", "with", "land", "lor", "lxor",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub location: LineCol,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.location.line, self.location.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Parses a whole program, reporting every declaration with a syntax error and not only the first
+/// one.
+pub fn parse_program(source: &str) -> Result<Program, Vec<SyntaxError>> {
+    let err = match expr_parser::program(source) {
+        Ok(program) => return Ok(program),
+        Err(err) => err,
+    };
+    let starts = declaration_starts(source);
+    let mut errors = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let segment = match starts.get(i + 1) {
+            Some(&end) => &source[start..end],
+            // the main expression follows the last declaration
+            None => &source[start..],
+        };
+        let result = match starts.get(i + 1) {
+            Some(_) => expr_parser::declaration(segment).map(|_| ()),
+            None => expr_parser::program(segment).map(|_| ()),
+        };
+        if let Err(err) = result {
+            errors.push(syntax_error(source, start + err.location.offset, &err));
+        }
+    }
+    if errors.is_empty() {
+        errors.push(syntax_error(source, err.location.offset, &err));
+    }
+    Err(errors)
+}
+
+/// The offsets of the lines starting with a declaration keyword, and of the start of the source.
+fn declaration_starts(source: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let word = line
+            .trim_start()
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or("");
+        if offset != 0 && DECL_KEYWORDS.contains(&word) {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    // text before the first declaration is only whitespace in a valid program
+    if starts.len() > 1 && source[..starts[1]].trim().is_empty() {
+        starts.remove(0);
+    }
+    starts
+}
+
+pub fn line_col(source: &str, offset: usize) -> LineCol {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    LineCol {
+        line,
+        column: before[line_start..].chars().count() + 1,
+        offset,
+    }
+}
+
+/// The token at the start of `rest`, a word or a single character.
+fn next_token(rest: &str) -> &str {
+    let rest = rest.trim_start();
+    let word = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+        .unwrap_or(rest.len());
+    match rest.chars().next() {
+        Some(_) if word > 0 => &rest[..word],
+        Some(c) => &rest[..c.len_utf8()],
+        None => "",
+    }
+}
+
+/// The operator or keyword `before` ends with, if it has to be followed by an expression.
+fn expr_prefix(before: &str) -> Option<&'static str> {
+    let before = before.trim_end();
+    EXPR_PREFIXES.iter().copied().find(|prefix| {
+        before.ends_with(prefix)
+            && (!prefix.chars().all(char::is_alphabetic)
+                || !before[..before.len() - prefix.len()]
+                    .ends_with(|c: char| c.is_alphanumeric() || c == '_' || c == '\''))
+    })
+}
+
+/// Operators that may continue an expression, reported as one alternative.
+const OPERATORS: &[&str] = &[
+    "+", "*", "/", "%", "<<", ">>", ">>>", ":=", ";", ".", "land", "lor", "lxor",
+];
+
+/// Tokens that may start an expression, reported as one alternative when a number would have been
+/// accepted too.
+const EXPR_STARTS: &[&str] = &[
+    "!",
+    "(",
+    "-",
+    "\\",
+    "λ",
+    "number",
+    "identifier",
+    "constructor",
+    "fst",
+    "snd",
+    "inl",
+    "inr",
+    "ref",
+    "raise",
+    "try",
+    "match",
+    "case",
+];
+
+/// Turns peg's set of expected tokens into a sentence.
+fn syntax_error(source: &str, offset: usize, err: &ParseError<LineCol>) -> SyntaxError {
+    let token = next_token(&source[offset..]);
+    let found = match token {
+        "" => "end of input".to_owned(),
+        token => format!("'{}'", token),
+    };
+    let expr = err.expected.tokens().any(|token| token == "number");
+    let expected = err
+        .expected
+        .tokens()
+        // character classes only repeat what the named rules say
+        .filter(|token| !token.starts_with('['))
+        .map(|token| {
+            let name = token
+                .strip_prefix('"')
+                .and_then(|literal| literal.strip_suffix('"'))
+                .map(|literal| literal.replace("\\\\", "\\"));
+            let quoted = name.is_some();
+            let name = name.unwrap_or_else(|| token.to_owned());
+            if OPERATORS.contains(&name.as_str()) {
+                "operator".to_owned()
+            } else if expr && EXPR_STARTS.contains(&name.as_str()) {
+                "expression".to_owned()
+            } else if name == "EOF" {
+                "end of input".to_owned()
+            } else if quoted {
+                format!("'{}'", name)
+            } else {
+                name
+            }
+        })
+        .collect::<BTreeSet<_>>();
+    // variables could start with an uppercase letter before constructors were added
+    let constructor = token.starts_with(char::is_uppercase)
+        && expected.contains("identifier")
+        && !expected.contains("constructor");
+    let mut message = if expected.contains("end of input") {
+        format!("unexpected {}", found)
+    } else if let (true, Some(prefix)) = (expr, expr_prefix(&source[..offset])) {
+        format!("expected expression after '{}', found {}", prefix, found)
+    } else {
+        match expected.len() {
+            0 => format!("unexpected {}", found),
+            1 => format!("expected {}, found {}", expected.first().unwrap(), found),
+            _ => {
+                let expected = expected.into_iter().collect::<Vec<_>>();
+                format!("expected one of {}, found {}", expected.join(", "), found)
+            }
+        }
+    };
+    if constructor {
+        message.push_str(", a name starting with an uppercase letter is a constructor");
+    }
+    SyntaxError {
+        location: line_col(source, offset),
+        message,
+    }
+}
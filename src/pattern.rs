@@ -0,0 +1,242 @@
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Pattern, Variable},
+    stack::grow,
+    typeinfer::DataTypes,
+};
+
+/// Field indices leading from the scrutinee to a part of it. The fields of a constructor start at
+/// 1, after its tag.
+pub type Access = Vec<usize>;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DecisionTree {
+    /// No arm matches.
+    Fail,
+    /// Runs the arm with the given index after binding its variables to parts of the scrutinee.
+    Leaf(usize, Vec<(Variable, Access)>),
+    /// Tests the tag of a value of the named data type, constructors without a case of their own
+    /// go to the default.
+    Switch(
+        Access,
+        String,
+        Vec<(usize, DecisionTree)>,
+        Option<Box<DecisionTree>>,
+    ),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MatchWarning {
+    /// An example of a value that no arm matches.
+    NonExhaustive(Pattern),
+    /// The pattern of an arm that is never used.
+    Redundant(Pattern),
+}
+
+impl fmt::Display for MatchWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchWarning::NonExhaustive(pattern) => {
+                write!(f, "match is not exhaustive, {} is not matched", pattern)
+            }
+            MatchWarning::Redundant(pattern) => {
+                write!(f, "the arm for {} is never used", pattern)
+            }
+        }
+    }
+}
+
+/// What is known about a part of the scrutinee on the current path through the tree.
+#[derive(Debug, Clone)]
+enum Shape {
+    Tuple(usize),
+    Constr(String, usize),
+}
+
+#[derive(Debug, Clone)]
+struct Row {
+    patterns: Vec<Pattern>,
+    bindings: Vec<(Variable, Access)>,
+    action: usize,
+}
+
+struct MatchCompiler<'a> {
+    data_types: &'a DataTypes,
+    shapes: HashMap<Access, Shape>,
+    used: Vec<bool>,
+    missing: Option<Pattern>,
+}
+
+impl MatchCompiler<'_> {
+    fn compile(&mut self, occurrences: Vec<Access>, mut rows: Vec<Row>) -> DecisionTree {
+        grow(|| {
+            for row in &mut rows {
+                for (pattern, occurrence) in row.patterns.iter_mut().zip(&occurrences) {
+                    if let Pattern::Var(var) = pattern {
+                        row.bindings.push((var.clone(), occurrence.clone()));
+                        *pattern = Pattern::Wildcard;
+                    }
+                }
+            }
+            let Some(first) = rows.first() else {
+                if self.missing.is_none() {
+                    self.missing = Some(self.witness(&[]));
+                }
+                return DecisionTree::Fail;
+            };
+            let Some(column) = first
+                .patterns
+                .iter()
+                .position(|pattern| *pattern != Pattern::Wildcard)
+            else {
+                self.used[first.action] = true;
+                return DecisionTree::Leaf(first.action, first.bindings.clone());
+            };
+            let occurrence = occurrences[column].clone();
+            let mut rest = occurrences;
+            rest.remove(column);
+            match &first.patterns[column] {
+                // a tuple always matches, it only makes its fields available to the other columns
+                Pattern::Tuple(patterns) => {
+                    let len = patterns.len();
+                    let rows = specialize(&rows, column, |pattern| match pattern {
+                        Pattern::Tuple(patterns) => Some(patterns.clone()),
+                        _ => Some(vec![Pattern::Wildcard; len]),
+                    });
+                    self.shapes.insert(occurrence.clone(), Shape::Tuple(len));
+                    let tree = self.compile(extend(&rest, &occurrence, 0..len), rows);
+                    self.shapes.remove(&occurrence);
+                    tree
+                }
+                Pattern::Constr(name, _) => {
+                    let data_types = self.data_types;
+                    let data_type = data_types.constructor(name).unwrap().data_type.clone();
+                    let mut cases = Vec::new();
+                    let mut missing = None;
+                    for constructor in data_types.constructors(&data_type) {
+                        let arity = constructor.fields.len();
+                        let used = rows.iter().any(|row| {
+                            matches!(
+                                &row.patterns[column],
+                                Pattern::Constr(name, _) if *name == constructor.name
+                            )
+                        });
+                        if !used {
+                            missing.get_or_insert(Shape::Constr(constructor.name.clone(), arity));
+                            continue;
+                        }
+                        let rows = specialize(&rows, column, |pattern| match pattern {
+                            Pattern::Constr(name, patterns) if *name == constructor.name => {
+                                Some(patterns.clone())
+                            }
+                            Pattern::Constr(_, _) => None,
+                            _ => Some(vec![Pattern::Wildcard; arity]),
+                        });
+                        self.shapes.insert(
+                            occurrence.clone(),
+                            Shape::Constr(constructor.name.clone(), arity),
+                        );
+                        let tree = self.compile(extend(&rest, &occurrence, 1..arity + 1), rows);
+                        cases.push((constructor.tag, tree));
+                    }
+                    let default = missing.map(|shape| {
+                        let rows = specialize(&rows, column, |pattern| match pattern {
+                            Pattern::Constr(_, _) => None,
+                            _ => Some(Vec::new()),
+                        });
+                        self.shapes.insert(occurrence.clone(), shape);
+                        Box::new(self.compile(rest, rows))
+                    });
+                    self.shapes.remove(&occurrence);
+                    DecisionTree::Switch(occurrence, data_type, cases, default)
+                }
+                _ => unreachable!(),
+            }
+        })
+    }
+
+    /// Builds the part of a value that the current path has not matched.
+    fn witness(&self, access: &[usize]) -> Pattern {
+        grow(|| {
+            let field = |i| {
+                let mut access = access.to_vec();
+                access.push(i);
+                self.witness(&access)
+            };
+            match self.shapes.get(access) {
+                Some(Shape::Tuple(len)) => Pattern::Tuple((0..*len).map(field).collect()),
+                Some(Shape::Constr(name, arity)) => {
+                    Pattern::Constr(name.clone(), (1..arity + 1).map(field).collect())
+                }
+                None => Pattern::Wildcard,
+            }
+        })
+    }
+}
+
+/// Replaces the pattern in `column` of every row by the patterns `f` gives for its fields,
+/// dropping the rows it gives `None` for.
+fn specialize(
+    rows: &[Row],
+    column: usize,
+    f: impl Fn(&Pattern) -> Option<Vec<Pattern>>,
+) -> Vec<Row> {
+    rows.iter()
+        .filter_map(|row| {
+            let mut row = row.clone();
+            let pattern = row.patterns.remove(column);
+            row.patterns.extend(f(&pattern)?);
+            Some(row)
+        })
+        .collect()
+}
+
+fn extend(
+    occurrences: &[Access],
+    occurrence: &Access,
+    fields: impl Iterator<Item = usize>,
+) -> Vec<Access> {
+    let mut occurrences = occurrences.to_vec();
+    occurrences.extend(fields.map(|i| {
+        let mut access = occurrence.clone();
+        access.push(i);
+        access
+    }));
+    occurrences
+}
+
+/// Compiles the patterns of the arms of a match into a decision tree that tests each part of
+/// the scrutinee at most once, and reports missing and unused arms.
+pub fn compile_match(
+    data_types: &DataTypes,
+    patterns: &[Pattern],
+) -> (DecisionTree, Vec<MatchWarning>) {
+    let mut compiler = MatchCompiler {
+        data_types,
+        shapes: HashMap::new(),
+        used: vec![false; patterns.len()],
+        missing: None,
+    };
+    let rows = patterns
+        .iter()
+        .enumerate()
+        .map(|(action, pattern)| Row {
+            patterns: vec![pattern.clone()],
+            bindings: Vec::new(),
+            action,
+        })
+        .collect();
+    let tree = compiler.compile(vec![Vec::new()], rows);
+    let mut warnings = Vec::new();
+    if let Some(pattern) = compiler.missing {
+        warnings.push(MatchWarning::NonExhaustive(pattern));
+    }
+    for (pattern, used) in patterns.iter().zip(compiler.used) {
+        if !used {
+            warnings.push(MatchWarning::Redundant(pattern.clone()));
+        }
+    }
+    (tree, warnings)
+}
@@ -0,0 +1,140 @@
+//! The prelude every program imports implicitly. It is compiled once per thread and linked in front
+//! of each program.
+
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    alpha::{AlphaConvEnv, Namespace},
+    anf::{ANFConverter, ANFs, HoistedANFs, ANF},
+    ast::{TypeDecl, TypeExpr},
+    parser::parse_program,
+    typeinfer::{Type, TypeInfer},
+};
+
+pub const SOURCE: &str = include_str!("prelude.stlc");
+
+/// The module the prelude is compiled as, which qualifies its types and constructors, see
+/// `AlphaConvEnv::module_conversion`.
+pub const MODULE: &str = "prelude";
+
+#[derive(Debug, Clone)]
+pub struct Prelude {
+    /// the top-level names, bound in every program
+    pub namespace: Namespace,
+    pub types: Vec<TypeDecl>,
+    pub exceptions: Vec<(String, Vec<TypeExpr>)>,
+    /// the type of each top-level name
+    pub env: Vec<(usize, Type)>,
+    /// the top-level functions that are polymorphic
+    pub schemes: HashSet<usize>,
+    /// the code of the top-level functions and the function each copy of one is a copy of, see
+    /// `ANFConverter::specialize`
+    pub functions: HashMap<usize, ANF>,
+    pub origins: HashMap<usize, usize>,
+    pub hoisted: HoistedANFs,
+    /// ids and type variables of a program compiled against the prelude start here
+    pub next_var: usize,
+}
+
+thread_local! {
+    static PRELUDE: OnceCell<Rc<Prelude>> = const { OnceCell::new() };
+}
+
+/// The compiled prelude, cached after the first call.
+pub fn prelude() -> Rc<Prelude> {
+    PRELUDE.with(|prelude| Rc::clone(prelude.get_or_init(|| Rc::new(Prelude::compile()))))
+}
+
+impl Prelude {
+    fn compile() -> Prelude {
+        let program = parse_program(SOURCE).expect("the prelude parses");
+        let alpha_conv_env = AlphaConvEnv::new();
+        let program = alpha_conv_env
+            .module_conversion(MODULE, program)
+            .expect("the prelude has no unbound variables");
+        let namespace = alpha_conv_env.namespace(MODULE);
+        let mut typeinfer = TypeInfer::new(alpha_conv_env.id());
+        typeinfer.instances = alpha_conv_env.instances();
+        let types = program.types();
+        let exceptions = program.exceptions();
+        typeinfer
+            .data_types
+            .declare(&types, &exceptions)
+            .expect("the prelude declares its types once");
+        typeinfer
+            .infer_program(&program)
+            .expect("the prelude is well typed");
+        let env = namespace
+            .values
+            .iter()
+            .map(|(_, id)| (*id, typeinfer.env[*id].simplify()))
+            .collect();
+        let mut anfconverter =
+            ANFConverter::new(alpha_conv_env.id(), &typeinfer.env, &typeinfer.data_types);
+        anfconverter.instances = typeinfer.instances.clone();
+        let mut anfs = ANFs {
+            anfs: Vec::new(),
+            value: None,
+            level: 0,
+        };
+        anfconverter.convert_program(program, &mut anfs);
+        let anfs = anfconverter.closure_conversion(anfs);
+        let mut hoisted = HoistedANFs {
+            fun_defs: Vec::new(),
+            main: ANFs {
+                anfs: Vec::new(),
+                value: None,
+                level: 1,
+            },
+            types: HashMap::new(),
+            exports: Vec::new(),
+        };
+        anfconverter.hoisting(anfs, &mut hoisted);
+        // the prelude is not part of the interface of the program
+        hoisted.exports.clear();
+        Prelude {
+            namespace,
+            types,
+            exceptions,
+            env,
+            schemes: typeinfer.schemes,
+            functions: anfconverter.functions,
+            origins: anfconverter.origins,
+            hoisted,
+            next_var: anfconverter.next_var.max(typeinfer.next_tvar),
+        }
+    }
+
+    /// Binds the top-level names of the prelude in the type environment of a program. The types are
+    /// copied, so inferring the program leaves the cached ones as they are.
+    pub fn declare(&self, typeinfer: &mut TypeInfer) {
+        for (id, t) in &self.env {
+            typeinfer.env[*id] = typeinfer.instantiate(t, &mut HashMap::new());
+        }
+        typeinfer.schemes.extend(&self.schemes);
+    }
+
+    /// Lets a program copy the functions of the prelude at the types it uses them at. The types of
+    /// their binders are the ones of the prelude, not the copies `declare` binds its names at.
+    pub fn declare_functions(&self, anfconverter: &mut ANFConverter) {
+        anfconverter.functions.extend(self.functions.clone());
+        anfconverter.origins.extend(self.origins.clone());
+        anfconverter.types.extend(self.hoisted.types.clone());
+    }
+
+    /// Puts the functions of the prelude in front of the ones of the program, and runs its main
+    /// expression first, which binds the top-level names the program uses.
+    pub fn link(&self, hoisted: &mut HoistedANFs) {
+        let mut fun_defs = self.hoisted.fun_defs.clone();
+        fun_defs.append(&mut hoisted.fun_defs);
+        hoisted.fun_defs = fun_defs;
+        let mut anfs = self.hoisted.main.anfs.clone();
+        anfs.append(&mut hoisted.main.anfs);
+        hoisted.main.anfs = anfs;
+        hoisted.types.extend(self.hoisted.types.clone());
+    }
+}
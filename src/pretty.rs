@@ -0,0 +1,623 @@
+//! Prints programs as source. `Display` puts each expression on one line, `format_source` breaks
+//! the ones that do not fit in the given width and keeps the comments between declarations.
+
+use core::{fmt, mem};
+
+use crate::{
+    ast::{Decl, Expr, Operator, Program, TypeExpr, Variable},
+    parser::{expr_parser, line_col, parse_program, SyntaxError},
+    stack::grow,
+};
+use peg::str::LineCol;
+
+const INDENT: usize = 2;
+
+/// A document laid out by `render`, in the style of Wadler's "A prettier printer".
+#[derive(Debug)]
+enum Doc {
+    Text(String),
+    /// a space, or a new line when the enclosing group does not fit
+    Line,
+    /// the first when the enclosing group fits on the line, the second otherwise
+    Alt(Box<Doc>, Box<Doc>),
+    Nest(usize, Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+impl Doc {
+    /// Moves the documents right below `self` to `docs`, leaving line breaks in their place.
+    fn take_children(&mut self, docs: &mut Vec<Doc>) {
+        let mut take = |doc: &mut Box<Doc>| docs.push(mem::replace(&mut **doc, Doc::Line));
+        match self {
+            Doc::Text(_) | Doc::Line => {}
+            Doc::Alt(a, b) => {
+                take(a);
+                take(b);
+            }
+            Doc::Nest(_, doc) | Doc::Group(doc) => take(doc),
+            Doc::Concat(children) => docs.append(children),
+        }
+    }
+}
+
+// the derived drop glue would recurse once per level of a deeply nested document
+impl Drop for Doc {
+    fn drop(&mut self) {
+        let mut docs = Vec::new();
+        self.take_children(&mut docs);
+        while let Some(mut doc) = docs.pop() {
+            doc.take_children(&mut docs);
+        }
+    }
+}
+
+fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+fn nest(doc: Doc) -> Doc {
+    Doc::Nest(INDENT, Box::new(doc))
+}
+
+fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+fn concat(docs: impl IntoIterator<Item = Doc>) -> Doc {
+    Doc::Concat(docs.into_iter().collect())
+}
+
+fn parens(doc: Doc) -> Doc {
+    group(concat([text("("), Doc::Nest(1, Box::new(doc)), text(")")]))
+}
+
+fn render(doc: &Doc, width: usize) -> String {
+    let width = width.min(isize::MAX as usize) as isize;
+    let mut out = String::new();
+    let mut column = 0;
+    // (indentation, flat, doc), the next one on top
+    let mut stack = vec![(0, false, doc)];
+    while let Some((indent, flat, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                column += s.chars().count() as isize;
+            }
+            Doc::Line if flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                column = indent as isize;
+            }
+            Doc::Alt(a, b) => stack.push((indent, flat, if flat { a } else { b })),
+            Doc::Nest(n, doc) => stack.push((indent + n, flat, doc)),
+            Doc::Group(doc) => {
+                let flat = flat || fits(width - column, doc, &stack);
+                stack.push((indent, flat, doc));
+            }
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, flat, doc))),
+        }
+    }
+    out
+}
+
+/// Whether `doc` on one line, and what follows it up to the next line break, fit in `width`.
+fn fits(mut width: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut stack = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+    while width >= 0 {
+        let (flat, doc) = match stack.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(&(_, flat, doc)) => (flat, doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) => width -= s.chars().count() as isize,
+            Doc::Line if flat => width -= 1,
+            Doc::Line => return true,
+            Doc::Alt(a, b) => stack.push((flat, if flat { a } else { b })),
+            Doc::Nest(_, doc) | Doc::Group(doc) => stack.push((flat, doc)),
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (flat, doc))),
+        }
+    }
+    false
+}
+
+// the levels of `expr_parser::expr`, from the loosest
+const SEQ: usize = 0;
+const ASSIGN: usize = 1;
+const NEG: usize = 8;
+const APP: usize = 9;
+const PREFIX: usize = 10;
+const POSTFIX: usize = 11;
+const ATOM: usize = 12;
+
+fn operator(op: &Operator) -> (&'static str, usize) {
+    match op {
+        Operator::Or => ("lor", 2),
+        Operator::Xor => ("lxor", 3),
+        Operator::And => ("land", 4),
+        Operator::Shl => ("<<", 5),
+        Operator::UShr => (">>>", 5),
+        Operator::Shr => (">>", 5),
+        Operator::Add => ("+", 6),
+        Operator::Sub => ("-", 6),
+        Operator::Mul => ("*", 7),
+        Operator::Div => ("/", 7),
+        Operator::Mod => ("%", 7),
+    }
+}
+
+/// What comes after an expression, which decides whether it needs parentheses even when its
+/// level is high enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Follow {
+    /// a closing token, a keyword or nothing
+    End,
+    /// an infix operator, `.n`, `|` or an argument, which a lambda, `case`, `match` or `try`
+    /// would take into its last expression
+    Operator,
+}
+
+struct Printer {
+    /// print variables with their ids, as they are after alpha conversion
+    ids: bool,
+}
+
+impl Printer {
+    fn var(&self, var: &Variable) -> Doc {
+        if self.ids {
+            text(var.to_string())
+        } else {
+            text(var.name.clone())
+        }
+    }
+
+    /// `level` is the loosest level the context accepts without parentheses.
+    fn expr(&self, e: &Expr, level: usize, follow: Follow) -> Doc {
+        grow(|| {
+            let (own, open) = match e {
+                Expr::Seq(..) => (SEQ, false),
+                Expr::Assign(..) => (ASSIGN, false),
+                Expr::BOp(Operator::Sub, x, _) if **x == Expr::Number(0) => (NEG, false),
+                Expr::BOp(op, ..) => (operator(op).1, false),
+                Expr::Number(n) if *n < 0 => (NEG, false),
+                Expr::App(..) => (APP, false),
+                Expr::Project(_, 0 | 1)
+                | Expr::Inl(_)
+                | Expr::Inr(_)
+                | Expr::Ref(_)
+                | Expr::Deref(_)
+                | Expr::Raise(..) => (PREFIX, false),
+                Expr::Project(..) => (POSTFIX, false),
+                Expr::Abs(..) | Expr::Case(..) | Expr::Match(..) | Expr::Try(..) => (ATOM, true),
+                // the fields of a constructor are its arguments, see `parser::apply`
+                Expr::Constr(_, args) if !args.is_empty() => (APP, false),
+                _ => (ATOM, false),
+            };
+            if own < level || open && follow != Follow::End {
+                parens(self.expr(e, SEQ, Follow::End))
+            } else {
+                self.unparenthesized(e, follow)
+            }
+        })
+    }
+
+    fn unparenthesized(&self, e: &Expr, follow: Follow) -> Doc {
+        match e {
+            Expr::Var(var) => self.var(var),
+            Expr::Number(n) => text(n.to_string()),
+            Expr::Unit => text("()"),
+            Expr::Prim(prim) => text(prim.to_string()),
+            Expr::Abs(..) => {
+                let mut binders = vec![text("\\")];
+                let mut body = e;
+                while let Expr::Abs(var, t, e) = body {
+                    if binders.len() > 1 {
+                        binders.push(text(" "));
+                    }
+                    binders.push(self.binder(var, t.as_ref()));
+                    body = e;
+                }
+                binders.push(text("."));
+                group(concat([
+                    concat(binders),
+                    nest(concat([Doc::Line, self.expr(body, SEQ, Follow::End)])),
+                ]))
+            }
+            Expr::App(f, x) => group(concat([
+                self.expr(f, APP, Follow::Operator),
+                nest(concat([Doc::Line, self.expr(x, PREFIX, follow)])),
+            ])),
+            Expr::Seq(x, y) => group(concat([
+                self.expr(x, ASSIGN, Follow::Operator),
+                text(";"),
+                Doc::Line,
+                self.expr(y, SEQ, follow),
+            ])),
+            Expr::Assign(x, y) => self.infix(":=", x, ASSIGN + 1, y, ASSIGN, follow),
+            Expr::BOp(Operator::Sub, x, y) if **x == Expr::Number(0) => {
+                concat([text("-"), self.expr(y, NEG, follow)])
+            }
+            Expr::BOp(op, x, y) => {
+                let (op, level) = operator(op);
+                self.infix(op, x, level, y, level + 1, follow)
+            }
+            Expr::Tuple(es) => self.tuple(es),
+            Expr::Project(e, 0) => self.prefix("fst ", e, follow),
+            Expr::Project(e, 1) => self.prefix("snd ", e, follow),
+            Expr::Project(e, n) => concat([
+                self.expr(e, POSTFIX, Follow::Operator),
+                text(format!(".{}", n)),
+            ]),
+            Expr::Inl(e) => self.prefix("inl ", e, follow),
+            Expr::Inr(e) => self.prefix("inr ", e, follow),
+            Expr::Ref(e) => self.prefix("ref ", e, follow),
+            Expr::Deref(e) => self.prefix("!", e, follow),
+            Expr::Raise(e, _) => self.prefix("raise ", e, follow),
+            Expr::Case(e, x, e1, y, e2) => group(concat([
+                text("case "),
+                self.expr(e, SEQ, Follow::End),
+                text(" of"),
+                nest(concat([
+                    Doc::Line,
+                    self.arm(text("inl "), x, e1, Follow::Operator),
+                    Doc::Line,
+                    text("| "),
+                    self.arm(text("inr "), y, e2, Follow::End),
+                ])),
+            ])),
+            Expr::Try(e, x, h) => group(concat([
+                text("try"),
+                nest(concat([Doc::Line, self.expr(e, SEQ, Follow::End)])),
+                Doc::Line,
+                text("with "),
+                self.arm(Doc::Concat(Vec::new()), x, h, Follow::End),
+            ])),
+            Expr::Match(e, arms) => {
+                let mut docs = vec![
+                    text("match "),
+                    self.expr(e, SEQ, Follow::End),
+                    text(" with"),
+                ];
+                for (i, (pattern, e)) in arms.iter().enumerate() {
+                    let bar = concat([Doc::Line, text("| ")]);
+                    docs.push(if i == 0 {
+                        Doc::Alt(Box::new(text(" ")), Box::new(bar))
+                    } else {
+                        bar
+                    });
+                    let follow = if i + 1 == arms.len() {
+                        Follow::End
+                    } else {
+                        Follow::Operator
+                    };
+                    docs.push(group(concat([
+                        text(format!("{} ->", pattern)),
+                        nest(concat([Doc::Line, self.expr(e, SEQ, follow)])),
+                    ])));
+                }
+                group(concat(docs))
+            }
+            Expr::Constr(name, args) => match args.as_slice() {
+                [] => text(name.clone()),
+                [Expr::Number(n)] if *n >= 0 => text(format!("{} {}", name, n)),
+                [Expr::Var(var)] => concat([text(format!("{} ", name)), self.var(var)]),
+                [Expr::Constr(c, fields)] if fields.is_empty() => text(format!("{} {}", name, c)),
+                _ => concat([text(format!("{} ", name)), self.tuple(args)]),
+            },
+            Expr::Annot(e, t) => parens(concat([
+                self.expr(e, SEQ, Follow::End),
+                text(" :"),
+                nest(concat([Doc::Line, text(t.to_string())])),
+            ])),
+        }
+    }
+
+    fn infix(
+        &self,
+        op: &str,
+        x: &Expr,
+        x_level: usize,
+        y: &Expr,
+        y_level: usize,
+        follow: Follow,
+    ) -> Doc {
+        group(concat([
+            self.expr(x, x_level, Follow::Operator),
+            text(format!(" {}", op)),
+            nest(concat([Doc::Line, self.expr(y, y_level, follow)])),
+        ]))
+    }
+
+    fn prefix(&self, keyword: &str, e: &Expr, follow: Follow) -> Doc {
+        concat([text(keyword), self.expr(e, PREFIX, follow)])
+    }
+
+    /// Also the fields of a constructor, where a single one is in parentheses of its own.
+    fn tuple(&self, es: &[Expr]) -> Doc {
+        let mut docs = Vec::new();
+        for (i, e) in es.iter().enumerate() {
+            if i > 0 {
+                docs.push(text(","));
+                docs.push(Doc::Line);
+            }
+            docs.push(self.expr(e, SEQ, Follow::End));
+        }
+        parens(concat(docs))
+    }
+
+    fn binder(&self, var: &Variable, t: Option<&TypeExpr>) -> Doc {
+        match t {
+            Some(t) => concat([text("("), self.var(var), text(format!(" : {})", t))]),
+            None => self.var(var),
+        }
+    }
+
+    fn arm(&self, head: Doc, var: &Variable, e: &Expr, follow: Follow) -> Doc {
+        group(concat([
+            head,
+            self.var(var),
+            text(" ->"),
+            nest(concat([Doc::Line, self.expr(e, SEQ, follow)])),
+        ]))
+    }
+
+    fn decl(&self, decl: &Decl) -> Doc {
+        match decl {
+            Decl::Type(t) => {
+                let mut docs = vec![text(format!("type {} =", t.name))];
+                for (i, (name, fields)) in t.constructors.iter().enumerate() {
+                    let bar = concat([Doc::Line, text("| ")]);
+                    docs.push(if i == 0 {
+                        Doc::Alt(Box::new(text(" ")), Box::new(bar))
+                    } else {
+                        bar
+                    });
+                    docs.push(text(constructor_decl(name, fields)));
+                }
+                group(nest(concat(docs)))
+            }
+            Decl::Exception(name, fields) => {
+                text(format!("exception {}", constructor_decl(name, fields)))
+            }
+            Decl::Let(var, e) => self.let_decl("let ", var, e),
+            Decl::LetRec(var, e) => self.let_decl("let rec ", var, e),
+            Decl::Import(path) => text(format!("import \"{}\"", path)),
+        }
+    }
+
+    /// Prints the parameters of a function after its name.
+    fn let_decl(&self, keyword: &str, var: &Variable, mut e: &Expr) -> Doc {
+        let mut docs = vec![text(keyword), self.var(var)];
+        while let Expr::Abs(var, t, body) = e {
+            docs.push(text(" "));
+            docs.push(self.binder(var, t.as_ref()));
+            e = body;
+        }
+        docs.push(text(" ="));
+        docs.push(nest(concat([Doc::Line, self.expr(e, SEQ, Follow::End)])));
+        group(concat(docs))
+    }
+
+    /// One document per declaration and the main expression, `;;` ends a `let` the main
+    /// expression follows.
+    fn program(&self, program: &Program) -> Vec<Doc> {
+        let mut docs: Vec<_> = program.decls.iter().map(|decl| self.decl(decl)).collect();
+        if let Some(main) = &program.main {
+            if let Some(Decl::Let(..) | Decl::LetRec(..)) = program.decls.last() {
+                let last = docs.pop().unwrap();
+                docs.push(concat([last, text(";;")]));
+            }
+            docs.push(self.expr(main, SEQ, Follow::End));
+        }
+        docs
+    }
+}
+
+fn constructor_decl(name: &str, fields: &[TypeExpr]) -> String {
+    let fields: Vec<_> = fields.iter().map(|t| type_expr(t, 3)).collect();
+    if fields.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{} of {}", name, fields.join(" * "))
+    }
+}
+
+/// The levels are those of `expr_parser::ty`: arrow, sum, product and `ref`.
+fn type_expr(t: &TypeExpr, level: usize) -> String {
+    let (own, s) = match t {
+        TypeExpr::Int => (4, "int".to_owned()),
+        TypeExpr::Unit => (4, "unit".to_owned()),
+        TypeExpr::Name(name) => (4, name.clone()),
+        TypeExpr::Arrow(a, b) => (0, format!("{} -> {}", type_expr(a, 1), type_expr(b, 0))),
+        TypeExpr::Sum(a, b) => (1, format!("{} + {}", type_expr(a, 1), type_expr(b, 2))),
+        TypeExpr::Product(ts) => {
+            let ts: Vec<_> = ts.iter().map(|t| type_expr(t, 3)).collect();
+            (2, ts.join(" * "))
+        }
+        TypeExpr::Ref(t) => (3, format!("{} ref", type_expr(t, 3))),
+    };
+    if own < level {
+        format!("({})", s)
+    } else {
+        s
+    }
+}
+
+/// The alternate form `{:#}` prints variables with their ids.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let printer = Printer { ids: f.alternate() };
+        let doc = printer.expr(self, SEQ, Follow::End);
+        write!(f, "{}", render(&doc, usize::MAX))
+    }
+}
+
+impl fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", type_expr(self, 0))
+    }
+}
+
+impl fmt::Display for Decl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let printer = Printer { ids: f.alternate() };
+        write!(f, "{}", render(&printer.decl(self), usize::MAX))
+    }
+}
+
+/// One line per declaration.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let printer = Printer { ids: f.alternate() };
+        for (i, doc) in printer.program(self).iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", render(doc, usize::MAX))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    Syntax(Vec<SyntaxError>),
+    /// only comments between declarations are kept, so one inside is an error rather than lost
+    Comment(LineCol),
+    /// the formatted source parses to another program, which is a bug of the printer
+    Changed,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Syntax(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", err)?;
+                }
+                Ok(())
+            }
+            FormatError::Comment(location) => write!(
+                f,
+                "{}:{}: comments inside a declaration can not be formatted",
+                location.line, location.column
+            ),
+            FormatError::Changed => write!(f, "the formatted program differs from the source"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// Prints the program in `source` with lines of at most `width` columns where possible. Blank
+/// lines and comments between declarations are kept.
+pub fn format_source(source: &str, width: usize) -> Result<String, FormatError> {
+    let program = parse_program(source).map_err(FormatError::Syntax)?;
+    let starts = expr_parser::item_starts(source).map_err(|_| FormatError::Changed)?;
+    let comments = comments(source);
+    let printer = Printer { ids: false };
+    let mut out = String::new();
+    write_gap(&mut out, source, &comments, 0, starts.first().copied());
+    for (i, doc) in printer.program(&program).iter().enumerate() {
+        let end = starts.get(i + 1).copied();
+        let item = &source[starts[i]..end.unwrap_or(source.len())];
+        let content_end = starts[i] + content_len(item, starts[i], &comments);
+        if let Some(&(start, _)) = comments
+            .iter()
+            .find(|&&(start, _)| start > starts[i] && start < content_end)
+        {
+            return Err(FormatError::Comment(line_col(source, start)));
+        }
+        out.push_str(&render(doc, width));
+        write_gap(&mut out, source, &comments, content_end, end);
+    }
+    if parse_program(&out).as_ref() != Ok(&program) {
+        return Err(FormatError::Changed);
+    }
+    Ok(out)
+}
+
+/// The start and end offsets of every comment.
+fn comments(source: &str) -> Vec<(usize, usize)> {
+    let mut comments = Vec::new();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < source.len() {
+        let rest = &source[i..];
+        if rest.starts_with('"') {
+            in_string = !in_string;
+        } else if rest.starts_with('\n') {
+            in_string = false;
+        } else if !in_string && rest.starts_with("(*") {
+            let end = rest[2..].find("*)").map_or(source.len(), |end| i + end + 4);
+            comments.push((i, end));
+            i = end;
+            continue;
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    comments
+}
+
+/// The length of `item`, which starts at `offset`, without the whitespace and comments after it.
+fn content_len(item: &str, offset: usize, comments: &[(usize, usize)]) -> usize {
+    let mut len = 0;
+    for (i, c) in item.char_indices() {
+        let in_comment = comments
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&(offset + i)));
+        if !in_comment && !c.is_whitespace() {
+            len = i + c.len_utf8();
+        }
+    }
+    len
+}
+
+/// Writes the comments from `start` up to the next item at `end`, or up to the end of the source,
+/// and ends the line. A comment on the line of the code before stays there, and blank lines before
+/// a comment or the next item are kept as one.
+fn write_gap(
+    out: &mut String,
+    source: &str,
+    comments: &[(usize, usize)],
+    start: usize,
+    end: Option<usize>,
+) {
+    let end_offset = end.unwrap_or(source.len());
+    let mut position = start;
+    for &(comment_start, comment_end) in comments {
+        if comment_start < start || comment_start >= end_offset {
+            continue;
+        }
+        let newlines = source[position..comment_start].matches('\n').count();
+        if !out.is_empty() {
+            if newlines == 0 && position > 0 {
+                out.push(' ');
+            } else {
+                out.push('\n');
+                if newlines > 1 {
+                    out.push('\n');
+                }
+            }
+        }
+        out.push_str(&source[comment_start..comment_end]);
+        position = comment_end;
+    }
+    if !out.is_empty() {
+        out.push('\n');
+        if end.is_some() && source[position..end_offset].matches('\n').count() > 1 {
+            out.push('\n');
+        }
+    }
+}
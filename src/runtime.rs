@@ -0,0 +1,116 @@
+//! Runtime functions the JIT maps into compiled programs, matching runtime/runtime.c.
+
+use core::fmt;
+
+use crate::ast::Prim;
+
+/// Exit status of a program aborted by a runtime error.
+pub const RUNTIME_ERROR_STATUS: i32 = 2;
+
+/// Errors detected by checks the backends insert around arithmetic, in the unmatched case of a
+/// `match` and at the end of a program. Compiled code passes the code to `stlc_runtime_error`,
+/// which prints the message and exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    DivisionByZero,
+    Overflow,
+    /// a `match` without an arm for the value
+    MatchFailure,
+    /// an exception raised outside of any `try`
+    UncaughtException,
+}
+
+impl RuntimeError {
+    pub const SYMBOL: &'static str = "stlc_runtime_error";
+
+    pub const ALL: [RuntimeError; 4] = [
+        RuntimeError::DivisionByZero,
+        RuntimeError::Overflow,
+        RuntimeError::MatchFailure,
+        RuntimeError::UncaughtException,
+    ];
+
+    pub fn code(self) -> i64 {
+        match self {
+            RuntimeError::DivisionByZero => 1,
+            RuntimeError::Overflow => 2,
+            RuntimeError::MatchFailure => 3,
+            RuntimeError::UncaughtException => 4,
+        }
+    }
+
+    pub fn from_code(code: i64) -> Option<RuntimeError> {
+        Self::ALL.into_iter().find(|error| error.code() == code)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::Overflow => write!(f, "integer overflow"),
+            RuntimeError::MatchFailure => write!(f, "match failure"),
+            RuntimeError::UncaughtException => write!(f, "uncaught exception"),
+        }
+    }
+}
+
+pub extern "C" fn stlc_print_int(n: i64) {
+    print!("{}", n);
+}
+
+pub extern "C" fn stlc_print_newline() {
+    println!();
+}
+
+pub extern "C" fn stlc_runtime_error(code: i64) {
+    use std::io::Write;
+    // output of the program so far must not be lost
+    let _ = std::io::stdout().flush();
+    match RuntimeError::from_code(code) {
+        Some(error) => eprintln!("runtime error: {}", error),
+        None => eprintln!("runtime error: {}", code),
+    }
+    std::process::exit(RUNTIME_ERROR_STATUS);
+}
+
+/// The address of each runtime function by symbol.
+pub fn symbols() -> [(&'static str, usize); 3] {
+    [
+        (
+            Prim::PrintInt.symbol(),
+            stlc_print_int as *const () as usize,
+        ),
+        (
+            Prim::PrintNewline.symbol(),
+            stlc_print_newline as *const () as usize,
+        ),
+        (
+            RuntimeError::SYMBOL,
+            stlc_runtime_error as *const () as usize,
+        ),
+    ]
+}
+
+/// Keeps the symbols of exported functions apart from the entry point, the runtime and the C
+/// library, which a program may well name its functions after.
+const EXPORT_PREFIX: &str = "stlc_export_";
+
+/// The symbol a top-level function named `name` is exported as, `module.name` for the functions of
+/// an imported module. It is a C identifier, and different names have different symbols: every
+/// `_` of the symbol starts an escape, `_u` for a `_` of the name, `__` for `.`, `_prime` for `'`
+/// and `_x` with the code in hex and a closing `_` for any other character that may not appear in
+/// one, from the file name of a module.
+pub fn export_symbol(name: &str) -> String {
+    let mut symbol = EXPORT_PREFIX.to_owned();
+    for c in name.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => symbol.push(c),
+            '_' => symbol.push_str("_u"),
+            '.' => symbol.push_str("__"),
+            '\'' => symbol.push_str("_prime"),
+            c => symbol.push_str(&format!("_x{:x}_", c as u32)),
+        }
+    }
+    symbol
+}
@@ -0,0 +1,14 @@
+//! Keeps the passes from overflowing the stack on deeply nested programs, such as generated ones.
+
+/// The space left on the stack below which a recursive call moves to a new segment, more than any
+/// pass uses between two calls to `grow`.
+const RED_ZONE: usize = 256 * 1024;
+
+const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Runs `f`, on a new segment of the stack if little of the current one is left. Each function
+/// recursing on the structure of a program calls it, so the depth of a program is only bounded by
+/// the memory available.
+pub fn grow<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, SEGMENT_SIZE, f)
+}
@@ -1,50 +1,500 @@
-use std::{cell::RefCell, rc::Rc};
+use core::{fmt, mem};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
-use crate::ast::Expr;
+use crate::{
+    ast::{Decl, Expr, Pattern, Prim, Program, TypeDecl, TypeExpr},
+    stack::grow,
+};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// The data type whose constructors are the declared exceptions.
+pub const EXN: &str = "exn";
+
+#[derive(Debug, Eq)]
 pub enum Type {
     Int,
+    Unit,
     Arrow(Box<Type>, Box<Type>),
     TVar(usize, Rc<RefCell<Option<Type>>>),
+    Product(Vec<Type>),
+    Sum(Box<Type>, Box<Type>),
+    /// code pointer of a closure converted function, without its environment parameter
+    Code(Vec<Type>, Box<Type>),
+    /// a data type declared with `type`, referred to by name so that it can be recursive
+    Data(String),
+    /// a mutable cell; `generalize` only takes syntactic lambdas, so the type of `ref (\x. x)`
+    /// is never generalized and every use of the cell has the same one
+    Ref(Box<Type>),
+}
+
+// the derived implementations would recurse without `grow` on deeply nested types
+impl Clone for Type {
+    fn clone(&self) -> Self {
+        grow(|| match self {
+            Type::Int => Type::Int,
+            Type::Unit => Type::Unit,
+            Type::Arrow(t1, t2) => Type::Arrow(t1.clone(), t2.clone()),
+            Type::TVar(n, r) => Type::TVar(*n, Rc::clone(r)),
+            Type::Product(ts) => Type::Product(ts.clone()),
+            Type::Sum(t1, t2) => Type::Sum(t1.clone(), t2.clone()),
+            Type::Code(args, ret) => Type::Code(args.clone(), ret.clone()),
+            Type::Data(name) => Type::Data(name.clone()),
+            Type::Ref(t) => Type::Ref(t.clone()),
+        })
+    }
+}
+
+impl PartialEq for Type {
+    fn eq(&self, other: &Self) -> bool {
+        grow(|| match (self, other) {
+            (Type::Int, Type::Int) | (Type::Unit, Type::Unit) => true,
+            (Type::Arrow(t1, t2), Type::Arrow(u1, u2)) | (Type::Sum(t1, t2), Type::Sum(u1, u2)) => {
+                t1 == u1 && t2 == u2
+            }
+            (Type::TVar(n, r), Type::TVar(m, s)) => n == m && r == s,
+            (Type::Product(ts), Type::Product(us)) => ts == us,
+            (Type::Code(args1, ret1), Type::Code(args2, ret2)) => args1 == args2 && ret1 == ret2,
+            (Type::Data(name1), Type::Data(name2)) => name1 == name2,
+            (Type::Ref(t), Type::Ref(u)) => t == u,
+            _ => false,
+        })
+    }
+}
+
+impl Type {
+    fn fmt_prec(&self, f: &mut fmt::Formatter<'_>, prec: usize) -> fmt::Result {
+        grow(|| {
+            match self {
+                Type::Int => write!(f, "int"),
+                Type::Unit => write!(f, "unit"),
+                // postfix, so it never needs parentheses itself
+                Type::Ref(t) => {
+                    t.fmt_prec(f, 4)?;
+                    write!(f, " ref")
+                }
+                Type::Data(name) => write!(f, "{}", name),
+                Type::TVar(n, r) => match &*r.borrow() {
+                    Some(t) => t.fmt_prec(f, prec),
+                    None => write!(f, "'t{}", n),
+                },
+                Type::Arrow(t1, t2) => {
+                    if prec > 0 {
+                        write!(f, "(")?;
+                    }
+                    t1.fmt_prec(f, 1)?;
+                    write!(f, " -> ")?;
+                    t2.fmt_prec(f, 0)?;
+                    if prec > 0 {
+                        write!(f, ")")?;
+                    }
+                    Ok(())
+                }
+                Type::Sum(t1, t2) => {
+                    if prec > 1 {
+                        write!(f, "(")?;
+                    }
+                    t1.fmt_prec(f, 1)?;
+                    write!(f, " + ")?;
+                    t2.fmt_prec(f, 2)?;
+                    if prec > 1 {
+                        write!(f, ")")?;
+                    }
+                    Ok(())
+                }
+                Type::Product(ts) => {
+                    if prec > 2 {
+                        write!(f, "(")?;
+                    }
+                    for (i, t) in ts.iter().enumerate() {
+                        if i != 0 {
+                            write!(f, " * ")?;
+                        }
+                        t.fmt_prec(f, 3)?;
+                    }
+                    if prec > 2 {
+                        write!(f, ")")?;
+                    }
+                    Ok(())
+                }
+                Type::Code(args, ret) => {
+                    if prec > 0 {
+                        write!(f, "(")?;
+                    }
+                    write!(f, "code(")?;
+                    for (i, t) in args.iter().enumerate() {
+                        if i != 0 {
+                            write!(f, ", ")?;
+                        }
+                        t.fmt_prec(f, 0)?;
+                    }
+                    write!(f, ") -> ")?;
+                    ret.fmt_prec(f, 0)?;
+                    if prec > 0 {
+                        write!(f, ")")?;
+                    }
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_prec(f, 0)
+    }
 }
 
 impl Type {
+    pub fn exn() -> Type {
+        Type::Data(EXN.to_owned())
+    }
+
+    pub fn of_prim(prim: Prim) -> Type {
+        match prim {
+            Prim::PrintInt => Type::Arrow(Box::new(Type::Int), Box::new(Type::Unit)),
+            Prim::PrintNewline => Type::Arrow(Box::new(Type::Unit), Box::new(Type::Unit)),
+        }
+    }
+
+    fn occurs(&self, n: usize) -> bool {
+        grow(|| match self.simplify() {
+            Type::TVar(m, _) => m == n,
+            Type::Int | Type::Unit | Type::Data(_) => false,
+            Type::Arrow(t1, t2) | Type::Sum(t1, t2) => t1.occurs(n) || t2.occurs(n),
+            Type::Product(ts) => ts.iter().any(|t| t.occurs(n)),
+            Type::Ref(t) => t.occurs(n),
+            Type::Code(args, ret) => args.iter().any(|t| t.occurs(n)) || ret.occurs(n),
+        })
+    }
+
+    /// Collects the type variables that are not bound yet.
+    fn tvars(&self, tvars: &mut HashSet<usize>) {
+        grow(|| match self.simplify() {
+            Type::TVar(n, _) => {
+                tvars.insert(n);
+            }
+            Type::Int | Type::Unit | Type::Data(_) => {}
+            Type::Arrow(t1, t2) | Type::Sum(t1, t2) => {
+                t1.tvars(tvars);
+                t2.tvars(tvars);
+            }
+            Type::Product(ts) => ts.iter().for_each(|t| t.tvars(tvars)),
+            Type::Ref(t) => t.tvars(tvars),
+            Type::Code(args, ret) => {
+                args.iter().for_each(|t| t.tvars(tvars));
+                ret.tvars(tvars);
+            }
+        })
+    }
+
+    /// Records in `subst` the type each variable of `self` stands for in `instance`.
+    pub fn match_instance(&self, instance: &Type, subst: &mut HashMap<usize, Type>) {
+        grow(|| match (self.simplify(), instance.simplify()) {
+            (Type::TVar(n, _), t) => {
+                subst.insert(n, t);
+            }
+            (Type::Arrow(t1, t2), Type::Arrow(u1, u2)) | (Type::Sum(t1, t2), Type::Sum(u1, u2)) => {
+                t1.match_instance(&u1, subst);
+                t2.match_instance(&u2, subst);
+            }
+            (Type::Product(ts), Type::Product(us)) => ts
+                .iter()
+                .zip(&us)
+                .for_each(|(t, u)| t.match_instance(u, subst)),
+            (Type::Ref(t), Type::Ref(u)) => t.match_instance(&u, subst),
+            (Type::Code(args1, ret1), Type::Code(args2, ret2)) => {
+                args1
+                    .iter()
+                    .zip(&args2)
+                    .for_each(|(t, u)| t.match_instance(u, subst));
+                ret1.match_instance(&ret2, subst);
+            }
+            _ => {}
+        })
+    }
+
+    /// Replaces the variables `subst` has a type for.
+    pub fn substitute(&self, subst: &HashMap<usize, Type>) -> Type {
+        grow(|| match self.simplify() {
+            Type::TVar(n, r) => match subst.get(&n) {
+                Some(t) => t.clone(),
+                None => Type::TVar(n, r),
+            },
+            Type::Arrow(t1, t2) => Type::Arrow(
+                Box::new(t1.substitute(subst)),
+                Box::new(t2.substitute(subst)),
+            ),
+            Type::Sum(t1, t2) => Type::Sum(
+                Box::new(t1.substitute(subst)),
+                Box::new(t2.substitute(subst)),
+            ),
+            Type::Product(ts) => Type::Product(ts.iter().map(|t| t.substitute(subst)).collect()),
+            Type::Ref(t) => Type::Ref(Box::new(t.substitute(subst))),
+            Type::Code(args, ret) => Type::Code(
+                args.iter().map(|t| t.substitute(subst)).collect(),
+                Box::new(ret.substitute(subst)),
+            ),
+            t => t,
+        })
+    }
+
     pub fn simplify(&self) -> Self {
-        match self {
+        grow(|| match self {
             Type::TVar(n, r) => match &*r.borrow() {
                 Some(t) => t.simplify(),
                 None => Type::TVar(*n, Rc::clone(r)),
             },
             Type::Arrow(t1, t2) => Type::Arrow(Box::new(t1.simplify()), Box::new(t2.simplify())),
+            Type::Product(ts) => Type::Product(ts.iter().map(|t| t.simplify()).collect()),
+            Type::Sum(t1, t2) => Type::Sum(Box::new(t1.simplify()), Box::new(t2.simplify())),
+            Type::Ref(t) => Type::Ref(Box::new(t.simplify())),
+            Type::Code(args, ret) => Type::Code(
+                args.iter().map(|t| t.simplify()).collect(),
+                Box::new(ret.simplify()),
+            ),
             _ => self.clone(),
-        }
+        })
     }
 
     pub fn get_type(env: &[Type], expr: &Expr) -> Option<Type> {
-        match expr {
-            Expr::Var(var) => env.get(var.id).map(|t| t.simplify()),
-            Expr::Abs(var, expr) => {
-                let t = env.get(var.id)?.simplify();
-                let t2 = Self::get_type(env, expr)?;
-                Some(Type::Arrow(Box::new(t), Box::new(t2)))
-            }
-            Expr::App(expr1, _) => {
-                let t1 = Self::get_type(env, expr1)?;
-                match t1 {
-                    Type::Arrow(_, t12) => Some(t12.simplify()),
+        grow(|| {
+            match expr {
+                Expr::Var(var) => env.get(var.id).map(|t| t.simplify()),
+                Expr::Abs(var, _, expr) => {
+                    let t = env.get(var.id)?.simplify();
+                    let t2 = Self::get_type(env, expr)?;
+                    Some(Type::Arrow(Box::new(t), Box::new(t2)))
+                }
+                Expr::App(expr1, _) => {
+                    let t1 = Self::get_type(env, expr1)?;
+                    match t1 {
+                        Type::Arrow(_, t12) => Some(t12.simplify()),
+                        _ => None,
+                    }
+                }
+                Expr::Number(_) => Some(Type::Int),
+                Expr::Unit => Some(Type::Unit),
+                Expr::Prim(prim) => Some(Type::of_prim(*prim)),
+                Expr::Seq(_, expr2) => Self::get_type(env, expr2),
+                Expr::BOp(_, _, _) => Some(Type::Int),
+                Expr::Tuple(exprs) => Some(Type::Product(
+                    exprs
+                        .iter()
+                        .map(|expr| Self::get_type(env, expr))
+                        .collect::<Option<_>>()?,
+                )),
+                Expr::Project(expr, index) => match Self::get_type(env, expr)? {
+                    Type::Product(ts) => ts.get(*index).cloned(),
+                    _ => None,
+                },
+                // the other side of an injection and the data type of a constructor are not
+                // recorded in the expression
+                Expr::Inl(_) | Expr::Inr(_) | Expr::Constr(_, _) => None,
+                Expr::Case(_, _, expr1, _, _) => Self::get_type(env, expr1),
+                Expr::Match(_, arms) => Self::get_type(env, &arms.first()?.1),
+                Expr::Annot(expr, _) => Self::get_type(env, expr),
+                Expr::Ref(expr) => Some(Type::Ref(Box::new(Self::get_type(env, expr)?))),
+                Expr::Deref(expr) => match Self::get_type(env, expr)? {
+                    Type::Ref(t) => Some(*t),
                     _ => None,
+                },
+                Expr::Assign(_, _) => Some(Type::Unit),
+                Expr::Raise(_, id) => env.get(*id).map(|t| t.simplify()),
+                Expr::Try(expr, _, _) => Self::get_type(env, expr),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    /// the type the context requires and the type that was found
+    Mismatch(Type, Type),
+    /// an annotation and the type inferred for what it annotates
+    Annotation(Type, Type),
+    NotAFunction(Type),
+    Project(Type, usize),
+    /// a field taken from a value of which nothing tells the width, beyond a pair
+    UnknownWidth(usize),
+    UnknownType(String),
+    UnknownConstructor(String),
+    /// a constructor given the wrong number of fields
+    ConstructorArity(String, usize),
+    DuplicateType(String),
+    DuplicateConstructor(String),
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Mismatch(expected, found) => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            TypeError::Annotation(annotation, found) => {
+                write!(f, "annotated as {}, but inferred {}", annotation, found)
+            }
+            TypeError::NotAFunction(t) => write!(f, "{} is not a function type", t),
+            TypeError::Project(t, index) => {
+                write!(f, "cannot take field {} of a value of type {}", index, t)
+            }
+            TypeError::UnknownWidth(index) => write!(
+                f,
+                "cannot infer the width of the tuple field {} is taken from, annotate its type",
+                index
+            ),
+            TypeError::UnknownType(name) => write!(f, "unknown type {}", name),
+            TypeError::UnknownConstructor(name) => write!(f, "unknown constructor {}", name),
+            // `f S x` passes `S` alone, its fields have to be in parentheses as in `f (S x)`
+            TypeError::ConstructorArity(name, 0) => write!(
+                f,
+                "constructor {} takes fields, put it in parentheses with them",
+                name
+            ),
+            TypeError::ConstructorArity(name, fields) => {
+                write!(f, "constructor {} does not take {} fields", name, fields)
+            }
+            TypeError::DuplicateType(name) => write!(f, "type {} is declared twice", name),
+            TypeError::DuplicateConstructor(name) => {
+                write!(f, "constructor {} is declared twice", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Constructor {
+    pub name: String,
+    pub data_type: String,
+    pub tag: usize,
+    pub fields: Vec<Type>,
+}
+
+/// The data types declared by a program, with their constructors in declaration order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataTypes {
+    types: HashMap<String, Vec<Constructor>>,
+    /// data type and tag of each constructor
+    tags: HashMap<String, (String, usize)>,
+}
+
+impl DataTypes {
+    pub fn constructor(&self, name: &str) -> Option<&Constructor> {
+        let (data_type, tag) = self.tags.get(name)?;
+        Some(&self.types[data_type][*tag])
+    }
+
+    pub fn constructors(&self, data_type: &str) -> &[Constructor] {
+        &self.types[data_type]
+    }
+
+    /// Fails on a type name that has not been declared.
+    pub fn resolve(&self, ty: &TypeExpr) -> Result<Type, TypeError> {
+        grow(|| match ty {
+            TypeExpr::Int => Ok(Type::Int),
+            TypeExpr::Unit => Ok(Type::Unit),
+            TypeExpr::Name(name) if self.types.contains_key(name) => Ok(Type::Data(name.clone())),
+            TypeExpr::Name(name) => Err(TypeError::UnknownType(name.clone())),
+            TypeExpr::Arrow(t1, t2) => Ok(Type::Arrow(
+                Box::new(self.resolve(t1)?),
+                Box::new(self.resolve(t2)?),
+            )),
+            TypeExpr::Product(ts) => Ok(Type::Product(
+                ts.iter()
+                    .map(|t| self.resolve(t))
+                    .collect::<Result<_, _>>()?,
+            )),
+            TypeExpr::Sum(t1, t2) => Ok(Type::Sum(
+                Box::new(self.resolve(t1)?),
+                Box::new(self.resolve(t2)?),
+            )),
+            TypeExpr::Ref(t) => Ok(Type::Ref(Box::new(self.resolve(t)?))),
+        })
+    }
+
+    /// Declarations may refer to each other, but neither type nor constructor names may repeat.
+    /// The exceptions become the constructors of `exn`, which is declared even without any.
+    pub fn declare(
+        &mut self,
+        decls: &[TypeDecl],
+        exceptions: &[(String, Vec<TypeExpr>)],
+    ) -> Result<(), TypeError> {
+        let exn = TypeDecl {
+            name: EXN.to_owned(),
+            constructors: exceptions.to_vec(),
+        };
+        let decls = decls.iter().chain([&exn]).collect::<Vec<_>>();
+        let mut names = HashSet::new();
+        for decl in &decls {
+            if !names.insert(&decl.name) || self.types.contains_key(&decl.name) {
+                return Err(TypeError::DuplicateType(decl.name.clone()));
+            }
+            self.types.insert(decl.name.clone(), Vec::new());
+        }
+        for decl in decls {
+            let mut constructors = Vec::new();
+            for (tag, (name, fields)) in decl.constructors.iter().enumerate() {
+                if self.tags.contains_key(name) {
+                    return Err(TypeError::DuplicateConstructor(name.clone()));
                 }
+                self.tags.insert(name.clone(), (decl.name.clone(), tag));
+                constructors.push(Constructor {
+                    name: name.clone(),
+                    data_type: decl.name.clone(),
+                    tag,
+                    fields: fields
+                        .iter()
+                        .map(|t| self.resolve(t))
+                        .collect::<Result<_, _>>()?,
+                });
             }
-            Expr::Number(_) => Some(Type::Int),
-            Expr::BOp(_, _, _) => Some(Type::Int),
+            self.types.insert(decl.name.clone(), constructors);
         }
+        Ok(())
+    }
+}
+
+/// What a module declares for the modules importing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub types: Vec<TypeDecl>,
+    pub exceptions: Vec<String>,
+    /// the type of each top-level `let`, a name declared twice only once with its last type
+    pub values: Vec<(String, Type)>,
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for decl in &self.types {
+            writeln!(f, "type {}", decl.name)?;
+        }
+        for name in &self.exceptions {
+            writeln!(f, "exception {}", name)?;
+        }
+        for (name, t) in &self.values {
+            writeln!(f, "val {} : {}", name, t)?;
+        }
+        Ok(())
     }
 }
 
 pub struct TypeInfer {
     pub next_tvar: usize,
     pub env: Vec<Type>,
+    pub data_types: DataTypes,
+    /// the tuple type, index and field type of each projection out of a tuple of unknown width
+    projections: Vec<(Type, usize, Type)>,
+    /// the top-level `let` each use of one refers to, see `AlphaConvEnv::instances`
+    pub instances: HashMap<usize, usize>,
+    /// top-level functions whose type variables are instantiated anew at each use
+    pub schemes: HashSet<usize>,
+    /// top-level `let`s that are not generalized
+    monomorphic: Vec<usize>,
 }
 
 impl TypeInfer {
@@ -54,23 +504,61 @@ impl TypeInfer {
             env: (0..next_tvar)
                 .map(|n| Type::TVar(n, Rc::new(RefCell::new(None))))
                 .collect(),
+            data_types: DataTypes::default(),
+            projections: Vec::new(),
+            instances: HashMap::new(),
+            schemes: HashSet::new(),
+            monomorphic: Vec::new(),
         }
     }
 
     fn unify(&mut self, t1: &Type, t2: &Type) -> bool {
-        let t1 = t1.simplify();
-        let t2 = t2.simplify();
-        match (t1, t2) {
-            (Type::Int, Type::Int) => true,
-            (Type::Arrow(t11, t12), Type::Arrow(t21, t22)) => {
-                self.unify(&t11, &t21) && self.unify(&t12, &t22)
-            }
-            (Type::TVar(n1, _), Type::TVar(n2, _)) if n1 == n2 => true,
-            (Type::TVar(_, r), t) | (t, Type::TVar(_, r)) => {
-                *r.borrow_mut() = Some(t.clone());
-                true
+        grow(|| {
+            let t1 = t1.simplify();
+            let t2 = t2.simplify();
+            match (t1, t2) {
+                (Type::Int, Type::Int) | (Type::Unit, Type::Unit) => true,
+                (Type::Data(name1), Type::Data(name2)) => name1 == name2,
+                (Type::Arrow(t11, t12), Type::Arrow(t21, t22)) => {
+                    self.unify(&t11, &t21) && self.unify(&t12, &t22)
+                }
+                (Type::Ref(t1), Type::Ref(t2)) => self.unify(&t1, &t2),
+                (Type::Sum(t11, t12), Type::Sum(t21, t22)) => {
+                    self.unify(&t11, &t21) && self.unify(&t12, &t22)
+                }
+                (Type::Product(ts1), Type::Product(ts2)) => {
+                    ts1.len() == ts2.len()
+                        && ts1.iter().zip(&ts2).all(|(t1, t2)| self.unify(t1, t2))
+                }
+                (Type::TVar(n1, _), Type::TVar(n2, _)) if n1 == n2 => true,
+                (Type::TVar(n, r), t) | (t, Type::TVar(n, r)) => {
+                    // binding a variable to a type containing it would make the type infinite
+                    if t.occurs(n) {
+                        return false;
+                    }
+                    *r.borrow_mut() = Some(t.clone());
+                    true
+                }
+                _ => false,
             }
-            _ => false,
+        })
+    }
+
+    /// Unifies `found` with `expected`, which is what the context of the expression requires.
+    fn expect(&mut self, expected: &Type, found: &Type) -> Result<(), TypeError> {
+        if self.unify(expected, found) {
+            Ok(())
+        } else {
+            Err(TypeError::Mismatch(expected.simplify(), found.simplify()))
+        }
+    }
+
+    fn annotate(&mut self, annotation: &TypeExpr, found: &Type) -> Result<Type, TypeError> {
+        let annotation = self.data_types.resolve(annotation)?;
+        if self.unify(&annotation, found) {
+            Ok(annotation)
+        } else {
+            Err(TypeError::Annotation(annotation, found.simplify()))
         }
     }
 
@@ -81,33 +569,322 @@ impl TypeInfer {
         t
     }
 
-    pub fn type_infer(&mut self, expr: &Expr) -> Option<Type> {
-        match expr {
-            Expr::Var(var) => self.env.get(var.id).map(|t| t.clone()),
-            Expr::Abs(var, expr) => {
-                let t = self.env.get(var.id)?.clone();
-                let t2 = self.type_infer(expr)?;
-                Some(Type::Arrow(Box::new(t), Box::new(t2)))
-            }
-            Expr::App(e1, e2) => {
-                let t1 = self.type_infer(e1)?;
-                let t2 = self.type_infer(e2)?;
-                let ret_type = self.new_tvar();
-                if self.unify(&t1, &Type::Arrow(Box::new(t2), Box::new(ret_type.clone()))) {
-                    Some(ret_type)
-                } else {
-                    None
-                }
-            }
-            Expr::Number(_) => Some(Type::Int),
-            Expr::BOp(_, e1, e2) => {
-                let t1 = self.type_infer(e1)?;
-                let t2 = self.type_infer(e2)?;
-                if self.unify(&t1, &Type::Int) && self.unify(&t2, &Type::Int) {
-                    Some(Type::Int)
-                } else {
-                    None
+    /// Infers the type of every `let` in order, returning the type of the main expression or unit.
+    pub fn infer_program(&mut self, program: &Program) -> Result<Type, TypeError> {
+        for decl in &program.decls {
+            if let Decl::Let(var, expr) | Decl::LetRec(var, expr) = decl {
+                let t = self.type_infer(expr)?;
+                let declared = self.env[var.id].clone();
+                self.expect(&declared, &t)?;
+                self.resolve_projections()?;
+                self.generalize(var.id, expr);
+            }
+        }
+        match &program.main {
+            Some(main) => {
+                let t = self.type_infer(main)?;
+                self.resolve_projections()?;
+                Ok(t)
+            }
+            None => Ok(Type::Unit),
+        }
+    }
+
+    /// Checks the projections out of tuples whose width is known by the end of a declaration.
+    /// A tuple whose width is still unknown is taken to be a pair, as for `fst` and `snd`.
+    fn resolve_projections(&mut self) -> Result<(), TypeError> {
+        loop {
+            let pending = mem::take(&mut self.projections);
+            let count = pending.len();
+            for (t, index, field) in pending {
+                match t.simplify() {
+                    Type::Product(ts) if index < ts.len() => self.expect(&ts[index], &field)?,
+                    Type::TVar(_, _) => self.projections.push((t, index, field)),
+                    t => return Err(TypeError::Project(t, index)),
+                }
+            }
+            if self.projections.is_empty() {
+                return Ok(());
+            }
+            // a pair may fix the width of the other tuples, so only one is assumed at a time
+            if self.projections.len() == count {
+                let i = match self.projections.iter().position(|(_, index, _)| *index < 2) {
+                    Some(i) => i,
+                    None => return Err(TypeError::UnknownWidth(self.projections[0].1)),
+                };
+                let (t, index, field) = self.projections.remove(i);
+                let mut pair = vec![self.new_tvar(), self.new_tvar()];
+                pair[index] = field;
+                self.expect(&Type::Product(pair), &t)?;
+            }
+        }
+    }
+
+    /// A syntactic value restriction: only a `let` bound to a lambda is generalized, not one bound
+    /// to an application or a `ref` that may create a cell when it runs. Nor is a lambda whose type
+    /// shares a variable with a `let` that is not, since a later use may still fix that type.
+    fn generalize(&mut self, id: usize, expr: &Expr) {
+        let mut shared = HashSet::new();
+        for id in &self.monomorphic {
+            self.env[*id].tvars(&mut shared);
+        }
+        let mut tvars = HashSet::new();
+        self.env[id].tvars(&mut tvars);
+        if matches!(expr, Expr::Abs(..)) && tvars.is_disjoint(&shared) {
+            self.schemes.insert(id);
+        } else {
+            self.monomorphic.push(id);
+        }
+    }
+
+    /// Copies `t`, replacing each type variable by a fresh one.
+    pub fn instantiate(&mut self, t: &Type, fresh: &mut HashMap<usize, Type>) -> Type {
+        grow(|| match t.simplify() {
+            Type::TVar(n, _) => match fresh.get(&n) {
+                Some(t) => t.clone(),
+                None => {
+                    let t = self.new_tvar();
+                    fresh.insert(n, t.clone());
+                    t
+                }
+            },
+            Type::Arrow(t1, t2) => Type::Arrow(
+                Box::new(self.instantiate(&t1, fresh)),
+                Box::new(self.instantiate(&t2, fresh)),
+            ),
+            Type::Sum(t1, t2) => Type::Sum(
+                Box::new(self.instantiate(&t1, fresh)),
+                Box::new(self.instantiate(&t2, fresh)),
+            ),
+            Type::Product(ts) => {
+                Type::Product(ts.iter().map(|t| self.instantiate(t, fresh)).collect())
+            }
+            Type::Ref(t) => Type::Ref(Box::new(self.instantiate(&t, fresh))),
+            Type::Code(args, ret) => Type::Code(
+                args.iter().map(|t| self.instantiate(t, fresh)).collect(),
+                Box::new(self.instantiate(&ret, fresh)),
+            ),
+            t => t,
+        })
+    }
+
+    /// The interface of a module whose program has been inferred.
+    pub fn interface(&self, program: &Program) -> Interface {
+        let mut values: Vec<(String, Type)> = Vec::new();
+        for decl in &program.decls {
+            if let Decl::Let(var, _) | Decl::LetRec(var, _) = decl {
+                values.retain(|(name, _)| *name != var.name);
+                values.push((var.name.clone(), self.env[var.id].simplify()));
+            }
+        }
+        // alpha conversion qualifies what a module declares by its name, see
+        // `AlphaConvEnv::module_conversion`
+        let unqualified = |name: String| match name.rsplit_once('.') {
+            Some((_, name)) => name.to_owned(),
+            None => name,
+        };
+        Interface {
+            types: program
+                .types()
+                .into_iter()
+                .map(|decl| TypeDecl {
+                    name: unqualified(decl.name),
+                    constructors: decl
+                        .constructors
+                        .into_iter()
+                        .map(|(name, fields)| (unqualified(name), fields))
+                        .collect(),
+                })
+                .collect(),
+            exceptions: program
+                .exceptions()
+                .into_iter()
+                .map(|(name, _)| unqualified(name))
+                .collect(),
+            values,
+        }
+    }
+
+    pub fn type_infer(&mut self, expr: &Expr) -> Result<Type, TypeError> {
+        grow(|| {
+            match expr {
+                Expr::Var(var) => match self.instances.get(&var.id) {
+                    Some(&def) => {
+                        let mut t = self.env[def].clone();
+                        if self.schemes.contains(&def) {
+                            t = self.instantiate(&t, &mut HashMap::new());
+                        }
+                        let instance = self.env[var.id].clone();
+                        self.expect(&instance, &t)?;
+                        Ok(instance)
+                    }
+                    None => Ok(self.env[var.id].clone()),
+                },
+                Expr::Abs(var, annotation, expr) => {
+                    let mut t = self.env[var.id].clone();
+                    if let Some(annotation) = annotation {
+                        t = self.annotate(annotation, &t)?;
+                    }
+                    let t2 = self.type_infer(expr)?;
+                    Ok(Type::Arrow(Box::new(t), Box::new(t2)))
+                }
+                Expr::App(e1, e2) => {
+                    let t1 = self.type_infer(e1)?;
+                    let t2 = self.type_infer(e2)?;
+                    match t1.simplify() {
+                        Type::Arrow(arg, ret) => {
+                            self.expect(&arg, &t2)?;
+                            Ok(*ret)
+                        }
+                        t1 @ Type::TVar(_, _) => {
+                            let ret_type = self.new_tvar();
+                            self.expect(
+                                &Type::Arrow(Box::new(t2), Box::new(ret_type.clone())),
+                                &t1,
+                            )?;
+                            Ok(ret_type)
+                        }
+                        t1 => Err(TypeError::NotAFunction(t1)),
+                    }
+                }
+                Expr::Number(_) => Ok(Type::Int),
+                Expr::Unit => Ok(Type::Unit),
+                Expr::Prim(prim) => Ok(Type::of_prim(*prim)),
+                Expr::Seq(e1, e2) => {
+                    let t1 = self.type_infer(e1)?;
+                    self.expect(&Type::Unit, &t1)?;
+                    self.type_infer(e2)
+                }
+                Expr::BOp(_, e1, e2) => {
+                    let t1 = self.type_infer(e1)?;
+                    self.expect(&Type::Int, &t1)?;
+                    let t2 = self.type_infer(e2)?;
+                    self.expect(&Type::Int, &t2)?;
+                    Ok(Type::Int)
+                }
+                Expr::Tuple(exprs) => Ok(Type::Product(
+                    exprs
+                        .iter()
+                        .map(|expr| self.type_infer(expr))
+                        .collect::<Result<_, _>>()?,
+                )),
+                Expr::Project(expr, index) => match self.type_infer(expr)?.simplify() {
+                    Type::Product(ts) if *index < ts.len() => Ok(ts[*index].clone()),
+                    // the width of the tuple may be fixed later on, see `resolve_projections`
+                    t @ Type::TVar(_, _) => {
+                        let field = self.new_tvar();
+                        self.projections.push((t, *index, field.clone()));
+                        Ok(field)
+                    }
+                    t => Err(TypeError::Project(t, *index)),
+                },
+                Expr::Inl(expr) => {
+                    let t = self.type_infer(expr)?;
+                    Ok(Type::Sum(Box::new(t), Box::new(self.new_tvar())))
+                }
+                Expr::Inr(expr) => {
+                    let t = self.type_infer(expr)?;
+                    Ok(Type::Sum(Box::new(self.new_tvar()), Box::new(t)))
+                }
+                Expr::Case(expr, var1, expr1, var2, expr2) => {
+                    let t = self.type_infer(expr)?;
+                    let t1 = self.env[var1.id].clone();
+                    let t2 = self.env[var2.id].clone();
+                    self.expect(&Type::Sum(Box::new(t1), Box::new(t2)), &t)?;
+                    let ret_type = self.type_infer(expr1)?;
+                    let t2 = self.type_infer(expr2)?;
+                    self.expect(&ret_type, &t2)?;
+                    Ok(ret_type)
+                }
+                Expr::Raise(expr, id) => {
+                    let t = self.type_infer(expr)?;
+                    self.expect(&Type::exn(), &t)?;
+                    Ok(self.env[*id].clone())
+                }
+                Expr::Try(expr, var, handler) => {
+                    let ret_type = self.type_infer(expr)?;
+                    let t = self.env[var.id].clone();
+                    self.expect(&Type::exn(), &t)?;
+                    let t = self.type_infer(handler)?;
+                    self.expect(&ret_type, &t)?;
+                    Ok(ret_type)
+                }
+                Expr::Constr(name, exprs) => {
+                    let constructor = self.constructor(name, exprs.len())?;
+                    for (expr, field) in exprs.iter().zip(&constructor.fields) {
+                        let t = self.type_infer(expr)?;
+                        self.expect(field, &t)?;
+                    }
+                    Ok(Type::Data(constructor.data_type))
+                }
+                Expr::Match(expr, arms) => {
+                    let t = self.type_infer(expr)?;
+                    let ret_type = self.new_tvar();
+                    for (pattern, expr) in arms {
+                        self.check_pattern(pattern, &t)?;
+                        let t2 = self.type_infer(expr)?;
+                        self.expect(&ret_type, &t2)?;
+                    }
+                    Ok(ret_type)
+                }
+                Expr::Annot(expr, annotation) => {
+                    let t = self.type_infer(expr)?;
+                    self.annotate(annotation, &t)
+                }
+                Expr::Ref(expr) => Ok(Type::Ref(Box::new(self.type_infer(expr)?))),
+                Expr::Deref(expr) => {
+                    let t = self.type_infer(expr)?;
+                    let content = self.new_tvar();
+                    self.expect(&Type::Ref(Box::new(content.clone())), &t)?;
+                    Ok(content)
+                }
+                Expr::Assign(e1, e2) => {
+                    let t1 = self.type_infer(e1)?;
+                    let content = self.new_tvar();
+                    self.expect(&Type::Ref(Box::new(content.clone())), &t1)?;
+                    let t2 = self.type_infer(e2)?;
+                    self.expect(&content, &t2)?;
+                    Ok(Type::Unit)
+                }
+            }
+        })
+    }
+
+    fn constructor(&self, name: &str, fields: usize) -> Result<Constructor, TypeError> {
+        let constructor = self
+            .data_types
+            .constructor(name)
+            .ok_or_else(|| TypeError::UnknownConstructor(name.to_owned()))?;
+        if constructor.fields.len() == fields {
+            Ok(constructor.clone())
+        } else {
+            Err(TypeError::ConstructorArity(name.to_owned(), fields))
+        }
+    }
+
+    /// Unifies the variables bound by the pattern with the parts of `ty` they match.
+    fn check_pattern(&mut self, pattern: &Pattern, ty: &Type) -> Result<(), TypeError> {
+        match pattern {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Var(var) => {
+                let t = self.env[var.id].clone();
+                self.expect(ty, &t)
+            }
+            Pattern::Tuple(patterns) => {
+                let ts = patterns.iter().map(|_| self.new_tvar()).collect::<Vec<_>>();
+                self.expect(ty, &Type::Product(ts.clone()))?;
+                for (pattern, t) in patterns.iter().zip(&ts) {
+                    self.check_pattern(pattern, t)?;
+                }
+                Ok(())
+            }
+            Pattern::Constr(name, patterns) => {
+                let constructor = self.constructor(name, patterns.len())?;
+                self.expect(ty, &Type::Data(constructor.data_type))?;
+                for (pattern, t) in patterns.iter().zip(&constructor.fields) {
+                    self.check_pattern(pattern, t)?;
                 }
+                Ok(())
             }
         }
     }
@@ -0,0 +1,477 @@
+use core::fmt;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    anf::{ANFs, HoistedANFs, Value, ANF},
+    ast::Variable,
+    stack::grow,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Anf,
+    Closure,
+    Hoisted,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Anf => write!(f, "a-normal form"),
+            Stage::Closure => write!(f, "closure converted a-normal form"),
+            Stage::Hoisted => write!(f, "hoisted a-normal form"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    Undefined(Variable),
+    DuplicateBinder(Variable),
+    NestedFun(Variable),
+    ProjectNonTuple(Variable),
+    ProjectOutOfRange(Variable, usize),
+    UnknownGlobal(Variable),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Undefined(var) => write!(f, "{} is used before it is defined", var),
+            VerifyError::DuplicateBinder(var) => write!(f, "{} is bound more than once", var),
+            VerifyError::NestedFun(var) => {
+                write!(f, "function {} is still nested after hoisting", var)
+            }
+            VerifyError::ProjectNonTuple(var) => {
+                write!(f, "{} is projected but does not hold a tuple", var)
+            }
+            VerifyError::ProjectOutOfRange(var, index) => {
+                write!(f, "index {} is out of range for tuple {}", index, var)
+            }
+            VerifyError::UnknownGlobal(var) => {
+                write!(f, "@{} does not refer to a function definition", var)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// What a variable was bound by, as far as the verifier cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Binding {
+    Param,
+    Fun,
+    App,
+    BOp,
+    Tuple(usize),
+    Project,
+    Store,
+    Prim,
+    Switch,
+    Trap,
+    Raise,
+    Try,
+}
+
+struct Verifier {
+    stage: Stage,
+    globals: HashSet<Variable>,
+    binders: HashSet<Variable>,
+}
+
+impl Verifier {
+    fn bind(
+        &mut self,
+        var: &Variable,
+        binding: Binding,
+        scope: &mut HashMap<Variable, Binding>,
+    ) -> Result<(), VerifyError> {
+        if !self.binders.insert(var.clone()) {
+            return Err(VerifyError::DuplicateBinder(var.clone()));
+        }
+        scope.insert(var.clone(), binding);
+        Ok(())
+    }
+
+    fn use_var(
+        &self,
+        var: &Variable,
+        scope: &HashMap<Variable, Binding>,
+    ) -> Result<Binding, VerifyError> {
+        scope
+            .get(var)
+            .copied()
+            .ok_or_else(|| VerifyError::Undefined(var.clone()))
+    }
+
+    fn use_value(
+        &self,
+        value: &Value,
+        scope: &HashMap<Variable, Binding>,
+    ) -> Result<(), VerifyError> {
+        match value {
+            Value::Number(_) | Value::Unit => Ok(()),
+            Value::Var(var) => self.use_var(var, scope).map(|_| ()),
+            Value::Global(var) => {
+                if self.stage != Stage::Anf && self.globals.contains(var) {
+                    Ok(())
+                } else {
+                    Err(VerifyError::UnknownGlobal(var.clone()))
+                }
+            }
+        }
+    }
+
+    fn verify_fun(&mut self, args: &[Variable], body: &ANFs) -> Result<(), VerifyError> {
+        let outer_binders = std::mem::take(&mut self.binders);
+        let mut scope = HashMap::new();
+        for arg in args {
+            self.bind(arg, Binding::Param, &mut scope)?;
+        }
+        let result = self.verify_anfs(body, &mut scope);
+        self.binders = outer_binders;
+        result
+    }
+
+    fn verify_anfs(
+        &mut self,
+        anfs: &ANFs,
+        scope: &mut HashMap<Variable, Binding>,
+    ) -> Result<(), VerifyError> {
+        grow(|| {
+            for anf in &anfs.anfs {
+                match anf {
+                    ANF::Fun(var, args, body) => match self.stage {
+                        Stage::Hoisted => return Err(VerifyError::NestedFun(var.clone())),
+                        // before closure conversion a function body may refer to enclosing variables
+                        Stage::Anf => {
+                            let mut body_scope = scope.clone();
+                            // a function declared by `let rec` refers to itself
+                            body_scope.insert(var.clone(), Binding::Fun);
+                            for arg in args {
+                                self.bind(arg, Binding::Param, &mut body_scope)?;
+                            }
+                            self.verify_anfs(body, &mut body_scope)?;
+                            self.bind(var, Binding::Fun, scope)?;
+                        }
+                        Stage::Closure => {
+                            self.verify_fun(args, body)?;
+                            self.bind(var, Binding::Fun, scope)?;
+                        }
+                    },
+                    ANF::App(var, func, args) => {
+                        self.use_var(func, scope)?;
+                        for arg in args {
+                            self.use_value(arg, scope)?;
+                        }
+                        self.bind(var, Binding::App, scope)?;
+                    }
+                    ANF::BOp(var, _, val1, val2) => {
+                        self.use_value(val1, scope)?;
+                        self.use_value(val2, scope)?;
+                        self.bind(var, Binding::BOp, scope)?;
+                    }
+                    ANF::Tuple(var, tuple) => {
+                        for val in tuple {
+                            self.use_value(val, scope)?;
+                        }
+                        self.bind(var, Binding::Tuple(tuple.len()), scope)?;
+                    }
+                    ANF::Project(var, tuple, index) => {
+                        match self.use_var(tuple, scope)? {
+                            Binding::BOp | Binding::Fun | Binding::Store | Binding::Prim => {
+                                return Err(VerifyError::ProjectNonTuple(tuple.clone()))
+                            }
+                            Binding::Tuple(len) if *index >= len => {
+                                return Err(VerifyError::ProjectOutOfRange(tuple.clone(), *index))
+                            }
+                            _ => (),
+                        }
+                        self.bind(var, Binding::Project, scope)?;
+                    }
+                    ANF::Switch(var, tag, branches) => {
+                        self.use_value(tag, scope)?;
+                        for branch in branches {
+                            // bindings inside a branch are not visible after the switch
+                            self.verify_anfs(branch, &mut scope.clone())?;
+                        }
+                        self.bind(var, Binding::Switch, scope)?;
+                    }
+                    ANF::Store(var, tuple, _, value) => {
+                        self.use_var(tuple, scope)?;
+                        self.use_value(value, scope)?;
+                        self.bind(var, Binding::Store, scope)?;
+                    }
+                    ANF::Prim(var, _, args) => {
+                        for arg in args {
+                            self.use_value(arg, scope)?;
+                        }
+                        self.bind(var, Binding::Prim, scope)?;
+                    }
+                    ANF::Trap(var) => self.bind(var, Binding::Trap, scope)?,
+                    ANF::Raise(var, value) => {
+                        self.use_value(value, scope)?;
+                        self.bind(var, Binding::Raise, scope)?;
+                    }
+                    ANF::Try(var, body, exn, handler) => {
+                        self.verify_anfs(body, &mut scope.clone())?;
+                        let mut handler_scope = scope.clone();
+                        self.bind(exn, Binding::Param, &mut handler_scope)?;
+                        self.verify_anfs(handler, &mut handler_scope)?;
+                        self.bind(var, Binding::Try, scope)?;
+                    }
+                }
+            }
+            match &anfs.value {
+                Some(value) => self.use_value(value, scope),
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+fn collect_funs(anfs: &ANFs, funs: &mut HashSet<Variable>) {
+    grow(|| {
+        for anf in &anfs.anfs {
+            match anf {
+                ANF::Fun(var, _, body) => {
+                    funs.insert(var.clone());
+                    collect_funs(body, funs);
+                }
+                ANF::Switch(_, _, branches) => {
+                    for branch in branches {
+                        collect_funs(branch, funs);
+                    }
+                }
+                ANF::Try(_, body, _, handler) => {
+                    collect_funs(body, funs);
+                    collect_funs(handler, funs);
+                }
+                _ => (),
+            }
+        }
+    })
+}
+
+/// Checks the invariants of a (closure converted) a-normal form that has not been hoisted yet.
+/// `bound` are the variables bound before it, by the prelude.
+pub fn verify_anfs(anfs: &ANFs, stage: Stage, bound: &[Variable]) -> Result<(), VerifyError> {
+    let mut globals = HashSet::new();
+    if stage == Stage::Closure {
+        collect_funs(anfs, &mut globals);
+    }
+    let mut verifier = Verifier {
+        stage,
+        globals,
+        binders: HashSet::new(),
+    };
+    let mut scope = bound
+        .iter()
+        .map(|var| (var.clone(), Binding::Param))
+        .collect();
+    verifier.verify_anfs(anfs, &mut scope)
+}
+
+pub fn verify_hoisted(hoisted_anfs: &HoistedANFs) -> Result<(), VerifyError> {
+    let mut verifier = Verifier {
+        stage: Stage::Hoisted,
+        globals: HashSet::new(),
+        binders: HashSet::new(),
+    };
+    for (var, _, _) in &hoisted_anfs.fun_defs {
+        if !verifier.globals.insert(var.clone()) {
+            return Err(VerifyError::DuplicateBinder(var.clone()));
+        }
+    }
+    for (_, args, body) in &hoisted_anfs.fun_defs {
+        verifier.verify_fun(args, body)?;
+    }
+    verifier.verify_fun(&[], &hoisted_anfs.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::ast::{Operator, Span};
+
+    fn var(name: &str, id: usize) -> Variable {
+        Variable {
+            name: name.to_owned(),
+            id,
+            span: Span::default(),
+        }
+    }
+
+    fn anfs(anfs: Vec<ANF>, value: Value) -> ANFs {
+        ANFs {
+            anfs,
+            value: Some(value),
+            level: 0,
+        }
+    }
+
+    fn add(r: &Variable, x: Value, y: Value) -> ANF {
+        ANF::BOp(r.clone(), Operator::Add, x, y)
+    }
+
+    fn hoisted(fun_defs: Vec<(Variable, Vec<Variable>, ANFs)>, main: ANFs) -> HoistedANFs {
+        HoistedANFs {
+            fun_defs,
+            main,
+            types: HashMap::new(),
+            exports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn undefined() {
+        let (r, x) = (var("r", 0), var("x", 1));
+        let program = anfs(
+            vec![add(&r, Value::Var(x.clone()), Value::Number(1))],
+            Value::Var(r),
+        );
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::Undefined(x.clone()))
+        );
+        // the variables the program is compiled with, like the functions of the prelude, are bound
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, std::slice::from_ref(&x)),
+            Ok(())
+        );
+        // a parameter is bound in the body of its function only
+        let f = var("f", 2);
+        let program = anfs(
+            vec![ANF::Fun(
+                f.clone(),
+                vec![x.clone()],
+                anfs(Vec::new(), Value::Var(x.clone())),
+            )],
+            Value::Var(x.clone()),
+        );
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::Undefined(x))
+        );
+        // the bindings of a branch end with it
+        let (s, b) = (var("s", 3), var("b", 4));
+        let branch = anfs(
+            vec![add(&b, Value::Number(1), Value::Number(2))],
+            Value::Var(b.clone()),
+        );
+        let program = anfs(
+            vec![ANF::Switch(s, Value::Number(0), vec![branch])],
+            Value::Var(b.clone()),
+        );
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::Undefined(b))
+        );
+    }
+
+    #[test]
+    fn duplicate_binder() {
+        let r = var("r", 0);
+        let program = anfs(
+            vec![
+                add(&r, Value::Number(1), Value::Number(2)),
+                add(&r, Value::Number(3), Value::Number(4)),
+            ],
+            Value::Var(r.clone()),
+        );
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::DuplicateBinder(r))
+        );
+        // two hoisted functions of the same name
+        let f = var("f", 1);
+        let body = anfs(Vec::new(), Value::Number(0));
+        let program = hoisted(
+            vec![
+                (f.clone(), Vec::new(), body.clone()),
+                (f.clone(), Vec::new(), body.clone()),
+            ],
+            body,
+        );
+        assert_eq!(
+            verify_hoisted(&program),
+            Err(VerifyError::DuplicateBinder(f))
+        );
+    }
+
+    #[test]
+    fn nested_fun() {
+        let (f, g) = (var("f", 0), var("g", 1));
+        let inner = anfs(
+            vec![ANF::Fun(
+                g.clone(),
+                Vec::new(),
+                anfs(Vec::new(), Value::Number(0)),
+            )],
+            Value::Number(0),
+        );
+        let program = hoisted(vec![(f, Vec::new(), inner)], anfs(Vec::new(), Value::Unit));
+        assert_eq!(verify_hoisted(&program), Err(VerifyError::NestedFun(g)));
+    }
+
+    #[test]
+    fn project_non_tuple() {
+        let (r, p) = (var("r", 0), var("p", 1));
+        let program = anfs(
+            vec![
+                add(&r, Value::Number(1), Value::Number(2)),
+                ANF::Project(p.clone(), r.clone(), 0),
+            ],
+            Value::Var(p),
+        );
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::ProjectNonTuple(r))
+        );
+    }
+
+    #[test]
+    fn project_out_of_range() {
+        let (t, p) = (var("t", 0), var("p", 1));
+        let tuple = |index| {
+            anfs(
+                vec![
+                    ANF::Tuple(t.clone(), vec![Value::Number(1), Value::Number(2)]),
+                    ANF::Project(p.clone(), t.clone(), index),
+                ],
+                Value::Var(p.clone()),
+            )
+        };
+        assert_eq!(verify_anfs(&tuple(1), Stage::Anf, &[]), Ok(()));
+        assert_eq!(
+            verify_anfs(&tuple(2), Stage::Anf, &[]),
+            Err(VerifyError::ProjectOutOfRange(t.clone(), 2))
+        );
+    }
+
+    #[test]
+    fn unknown_global() {
+        let (g, env) = (var("g", 0), var("env", 1));
+        let program = anfs(
+            vec![ANF::Tuple(env, vec![Value::Global(g.clone())])],
+            Value::Unit,
+        );
+        assert_eq!(
+            verify_hoisted(&hoisted(Vec::new(), program.clone())),
+            Err(VerifyError::UnknownGlobal(g.clone()))
+        );
+        // before closure conversion nothing is referred to as a global
+        assert_eq!(
+            verify_anfs(&program, Stage::Anf, &[]),
+            Err(VerifyError::UnknownGlobal(g.clone()))
+        );
+        let defined = hoisted(
+            vec![(g, Vec::new(), anfs(Vec::new(), Value::Number(0)))],
+            program,
+        );
+        assert_eq!(verify_hoisted(&defined), Ok(()));
+    }
+}
@@ -1,20 +1,95 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use crate::{
     anf::{ANFs, HoistedANFs, Value, ANF},
     ast::{Operator, Variable},
+    runtime::{RuntimeError, RUNTIME_ERROR_STATUS},
+    stack::grow,
 };
 
+/// Bytes at the start of memory used by the runtime for the `fd_write` arguments, the digits of
+/// `print_int` and the runtime error messages, tuples are allocated after them.
+const SCRATCH_SIZE: u32 = 256;
+
+/// Where the runtime error messages start.
+const MESSAGES: u32 = 64;
+
+/// The runtime functions, writing through WASI `fd_write`. Memory holds the iovec at 0, the number
+/// of bytes written at 8 and the text from 16. `stlc_alloc` bumps the pointer past the bytes it
+/// returns, growing memory by the pages they need.
+const RUNTIME: &str = r#"(func $stlc_alloc (param $size i32) (result i32)
+(local $p i32)
+(local $pages i32)
+(local.set $p (global.get $stack_pointer))
+(global.set $stack_pointer (i32.add (local.get $p) (local.get $size)))
+(local.set $pages (i32.sub (i32.shr_u (i32.add (global.get $stack_pointer) (i32.const 65535)) (i32.const 16)) (memory.size)))
+(if (i32.gt_s (local.get $pages) (i32.const 0))
+(then (if (i32.eq (memory.grow (local.get $pages)) (i32.const -1)) (then unreachable))))
+(local.get $p))
+(func $stlc_div (param $a i64) (param $b i64) (result i64)
+(if (i64.eqz (local.get $b)) (then (call $stlc_runtime_error (i64.const 1))))
+(if (i32.and (i64.eq (local.get $a) (i64.const -9223372036854775808)) (i64.eq (local.get $b) (i64.const -1)))
+(then (call $stlc_runtime_error (i64.const 2))))
+(i64.div_s (local.get $a) (local.get $b)))
+(func $stlc_rem (param $a i64) (param $b i64) (result i64)
+(if (i64.eqz (local.get $b)) (then (call $stlc_runtime_error (i64.const 1))))
+(i64.rem_s (local.get $a) (local.get $b)))
+(func $stlc_add_checked (param $a i64) (param $b i64) (result i64)
+(local $r i64)
+(local.set $r (i64.add (local.get $a) (local.get $b)))
+(if (i64.lt_s (i64.and (i64.xor (local.get $a) (local.get $r)) (i64.xor (local.get $b) (local.get $r))) (i64.const 0))
+(then (call $stlc_runtime_error (i64.const 2))))
+(local.get $r))
+(func $stlc_sub_checked (param $a i64) (param $b i64) (result i64)
+(local $r i64)
+(local.set $r (i64.sub (local.get $a) (local.get $b)))
+(if (i64.lt_s (i64.and (i64.xor (local.get $a) (local.get $b)) (i64.xor (local.get $a) (local.get $r))) (i64.const 0))
+(then (call $stlc_runtime_error (i64.const 2))))
+(local.get $r))
+(func $stlc_mul_checked (param $a i64) (param $b i64) (result i64)
+(local $r i64)
+(local.set $r (i64.mul (local.get $a) (local.get $b)))
+(if (i64.eq (local.get $a) (i64.const -1))
+(then (if (i64.eq (local.get $b) (i64.const -9223372036854775808)) (then (call $stlc_runtime_error (i64.const 2)))))
+(else (if (i64.ne (local.get $a) (i64.const 0))
+(then (if (i64.ne (i64.div_s (local.get $r) (local.get $a)) (local.get $b)) (then (call $stlc_runtime_error (i64.const 2))))))))
+(local.get $r))
+(func $stlc_print_int (param $n i64)
+(local $pos i32) (local $neg i32) (local $digit i64)
+(local.set $pos (i32.const 48))
+(local.set $neg (i64.lt_s (local.get $n) (i64.const 0)))
+(loop $digits
+(local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+(local.set $digit (i64.rem_s (local.get $n) (i64.const 10)))
+(local.set $digit (select (i64.sub (i64.const 0) (local.get $digit)) (local.get $digit) (i64.lt_s (local.get $digit) (i64.const 0))))
+(i64.store8 (local.get $pos) (i64.add (local.get $digit) (i64.const 48)))
+(local.set $n (i64.div_s (local.get $n) (i64.const 10)))
+(br_if $digits (i64.ne (local.get $n) (i64.const 0))))
+(if (local.get $neg) (then
+(local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+(i32.store8 (local.get $pos) (i32.const 45))))
+(call $write (i32.const 1) (local.get $pos) (i32.sub (i32.const 48) (local.get $pos))))
+(func $stlc_print_newline
+(i32.store8 (i32.const 16) (i32.const 10))
+(call $write (i32.const 1) (i32.const 16) (i32.const 1)))
+(func $write (param $fd i32) (param $ptr i32) (param $len i32)
+(i32.store (i32.const 0) (local.get $ptr))
+(i32.store (i32.const 4) (local.get $len))
+(drop (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8))))"#;
+
 pub struct WasmCompiler {
     pub program: String,
     pub fun_table: HashMap<Variable, u32>,
+    /// abort on overflowing `+`, `-` and `*` instead of wrapping around
+    checked_arith: bool,
 }
 
 impl WasmCompiler {
-    pub fn new() -> Self {
+    pub fn new(checked_arith: bool) -> Self {
         Self {
             program: String::new(),
             fun_table: HashMap::new(),
+            checked_arith,
         }
     }
 
@@ -29,20 +104,61 @@ impl WasmCompiler {
 
     pub fn compile(&mut self, hoisted_anfs: HoistedANFs) {
         self.append_line("(module");
-        self.append_line("(memory 1)");
-        self.append_line("(global $stack_pointer (mut i32) (i32.const 0))");
+        self.append_line("(import \"wasi_snapshot_preview1\" \"fd_write\" (func $fd_write (param i32 i32 i32 i32) (result i32)))");
+        self.append_line(
+            "(import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))",
+        );
+        self.append_line("(memory (export \"memory\") 1)");
+        self.append_line(&format!(
+            "(global $stack_pointer (mut i32) (i32.const {SCRATCH_SIZE}))"
+        ));
+        // the payload is the exception value
+        self.append_line("(tag $stlc_exn (param i64))");
+        self.append_line(RUNTIME);
+        self.generate_runtime_error();
         self.generate_fun_table(&hoisted_anfs);
-        self.append_line("(type $t (func (param i32 i32) (result i32)))");
+        self.generate_fun_types(&hoisted_anfs);
+        let prints_result = hoisted_anfs.prints_result();
         // start function definition
         for (fun_name, args, body) in hoisted_anfs.fun_defs {
             self.compile_fun(&fun_name.to_string(), args, &body);
         }
-        self.compile_fun("_start", Vec::new(), &hoisted_anfs.main);
+        self.compile_start(&hoisted_anfs.main, prints_result);
         // end function definition
         self.append_line("(export \"_start\" (func $_start))");
+        for (symbol, fun_name) in &hoisted_anfs.exports {
+            self.append_line(&format!("(export \"{symbol}\" (func ${fun_name}))"));
+        }
         self.append_line(")");
     }
 
+    /// Prints the message of the error code to stderr and exits, the messages are stored after the
+    /// scratch space used by `print_int`.
+    fn generate_runtime_error(&mut self) {
+        let mut offset = MESSAGES;
+        let mut data = String::new();
+        self.append_line("(func $stlc_runtime_error (param $code i64)");
+        for error in RuntimeError::ALL {
+            let message = format!("runtime error: {}", error);
+            let len = message.len() + 1;
+            self.append_line(&format!(
+                "(if (i64.eq (local.get $code) (i64.const {})) (then (call $write (i32.const 2) (i32.const {offset}) (i32.const {len}))))",
+                error.code(),
+            ));
+            data.push_str(&message);
+            data.push_str("\\0a");
+            offset += len as u32;
+        }
+        assert!(
+            offset <= SCRATCH_SIZE,
+            "runtime error messages overflow the scratch space"
+        );
+        self.append_line(&format!(
+            "(call $proc_exit (i32.const {RUNTIME_ERROR_STATUS})))"
+        ));
+        self.append_line(&format!("(data (i32.const {MESSAGES}) \"{data}\")"));
+    }
+
     fn generate_fun_table(&mut self, hoisted_anfs: &HoistedANFs) {
         let fun_count = hoisted_anfs.fun_defs.len();
         self.append_line(&format!("(table {fun_count} funcref)"));
@@ -54,98 +170,299 @@ impl WasmCompiler {
         self.append_line(&format!("(elem (i32.const 0){fun_names})"));
     }
 
+    /// Every value is passed around as an i64 word: ints are unboxed and pointers are zero
+    /// extended, so `call_indirect` only has to distinguish functions by arity.
+    fn generate_fun_types(&mut self, hoisted_anfs: &HoistedANFs) {
+        let mut arities = BTreeSet::new();
+        for (_, args, body) in &hoisted_anfs.fun_defs {
+            arities.insert(args.len());
+            collect_arities(body, &mut arities);
+        }
+        collect_arities(&hoisted_anfs.main, &mut arities);
+        for arity in arities {
+            let params = " i64".repeat(arity);
+            self.append_line(&format!(
+                "(type $t{arity} (func (param{params}) (result i64)))"
+            ));
+        }
+    }
+
     fn compile_fun(&mut self, fun_name: &str, args: Vec<Variable>, body: &ANFs) {
         self.append(&format!("(func ${fun_name} "));
         for arg in &args {
-            self.append(&format!("(param ${arg} i32) "));
+            self.append(&format!("(param ${arg} i64) "));
         }
-        self.append_line("(result i32)");
+        self.append_line("(result i64)");
         let mut bound_vars = HashSet::new();
         for arg in &args {
             bound_vars.insert(arg.id);
         }
         let mut local_vars: HashSet<&Variable> = HashSet::new();
-        for anf in &body.anfs {
-            match anf {
-                ANF::Fun(var, _, _) => {
-                    local_vars.insert(var);
-                }
-                ANF::App(var, _, _) => {
-                    local_vars.insert(var);
-                }
-                ANF::BOp(var, _, _, _) => {
-                    local_vars.insert(var);
-                }
-                ANF::Tuple(var, _) => {
-                    local_vars.insert(var);
-                }
-                ANF::Project(var, _, _) => {
-                    local_vars.insert(var);
-                }
-            }
+        collect_local_vars(body, &mut local_vars);
+        for var in &local_vars {
+            self.append(&format!("(local ${var} i64) "));
         }
-        self.append_line(&local_vars.iter().fold(String::new(), |acc, var| {
-            format!("(local ${var} i32) {acc}")
-        }));
-        for anf in &body.anfs {
-            self.compile_anf(anf);
+        self.append_line("");
+        self.compile_body(body, true);
+        self.append_line(")");
+    }
+
+    /// An exception escaping the program is a runtime error, so calls in `_start` can not be tail
+    /// calls, which would leave the handler. `_start` takes and returns nothing, as WASI expects, so
+    /// an int result is printed like `main` of the llvm backend does.
+    fn compile_start(&mut self, body: &ANFs, prints_result: bool) {
+        self.append_line("(func $_start");
+        let mut local_vars: HashSet<&Variable> = HashSet::new();
+        collect_local_vars(body, &mut local_vars);
+        for var in &local_vars {
+            self.append(&format!("(local ${var} i64) "));
         }
-        self.compile_value(&body.value.clone().unwrap());
+        self.append_line("");
+        self.append_line("(block $uncaught (result i64)");
+        self.append_line("(try_table (result i64) (catch $stlc_exn $uncaught)");
+        self.compile_body(body, false);
         self.append_line(")");
+        if prints_result {
+            self.append_line("(call $stlc_print_int)");
+            self.append_line("(call $stlc_print_newline)");
+        } else {
+            self.append_line("drop");
+        }
+        self.append_line("return");
+        self.append_line(")");
+        self.append_line("drop");
+        self.append_line(&format!(
+            "(call $stlc_runtime_error (i64.const {}))",
+            RuntimeError::UncaughtException.code()
+        ));
+        self.append_line("unreachable");
+        self.append_line(")");
+    }
+
+    /// Leaves the value of `body` on the stack, or returns it directly when `tail` is set.
+    fn compile_body(&mut self, body: &ANFs, tail: bool) {
+        let tail = tail && body.ends_in_tail_position();
+        for (i, anf) in body.anfs.iter().enumerate() {
+            self.compile_anf(anf, tail && i + 1 == body.anfs.len());
+        }
+        if !tail {
+            self.compile_value(&body.result());
+        }
     }
 
-    fn compile_anf(&mut self, anf: &ANF) {
-        match anf {
-            ANF::Fun(_, _, _) => {
-                unreachable!("hoisted anf should not have internal function definition")
+    /// Tests the tag against each branch index in turn, the last branch is the fallback.
+    fn compile_switch(&mut self, tag: &Value, branches: &[ANFs], index: usize, tail: bool) {
+        grow(|| {
+            if index + 1 == branches.len() {
+                self.compile_body(&branches[index], tail);
+                return;
             }
-            ANF::App(var, func, args) => {
-                for arg in args {
-                    self.compile_value(arg);
+            self.compile_value(tag);
+            self.append_line(&format!("i64.const {index}"));
+            self.append_line("i64.eq");
+            self.append_line("(if (result i64)");
+            self.append_line("(then");
+            self.compile_body(&branches[index], tail);
+            self.append_line(")");
+            self.append_line("(else");
+            self.compile_switch(tag, branches, index + 1, tail);
+            self.append_line(")");
+            self.append_line(")");
+        })
+    }
+
+    /// A tail call becomes `return_call_indirect` from the tail call proposal and ends the function,
+    /// a switch in tail position leaves its value on the stack.
+    fn compile_anf(&mut self, anf: &ANF, tail: bool) {
+        grow(|| {
+            match anf {
+                ANF::Fun(_, _, _) => {
+                    unreachable!("hoisted anf should not have internal function definition")
+                }
+                ANF::App(var, func, args) => {
+                    for arg in args {
+                        self.compile_value(arg);
+                    }
+                    let call = if tail {
+                        "return_call_indirect"
+                    } else {
+                        "call_indirect"
+                    };
+                    self.append_line(&format!(
+                        "({call} (type $t{}) (i32.wrap_i64 (local.get ${func})))",
+                        args.len()
+                    ));
+                    if !tail {
+                        self.append_line(&format!("local.set ${var}"));
+                    }
+                }
+                ANF::BOp(var, op, v1, v2) => {
+                    self.compile_value(v1);
+                    self.compile_value(v2);
+                    // `i64.div_s` and `i64.rem_s` would trap, the runtime reports the error instead
+                    match op {
+                        Operator::Add if self.checked_arith => {
+                            self.append_line("call $stlc_add_checked")
+                        }
+                        Operator::Sub if self.checked_arith => {
+                            self.append_line("call $stlc_sub_checked")
+                        }
+                        Operator::Mul if self.checked_arith => {
+                            self.append_line("call $stlc_mul_checked")
+                        }
+                        Operator::Add => self.append_line("i64.add"),
+                        Operator::Sub => self.append_line("i64.sub"),
+                        Operator::Mul => self.append_line("i64.mul"),
+                        Operator::Div => self.append_line("call $stlc_div"),
+                        Operator::Mod => self.append_line("call $stlc_rem"),
+                        Operator::And => self.append_line("i64.and"),
+                        Operator::Or => self.append_line("i64.or"),
+                        Operator::Xor => self.append_line("i64.xor"),
+                        // shift amounts are taken modulo 64
+                        Operator::Shl => self.append_line("i64.shl"),
+                        Operator::Shr => self.append_line("i64.shr_s"),
+                        Operator::UShr => self.append_line("i64.shr_u"),
+                    }
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Tuple(var, tuple) => {
+                    self.append_line(&format!("i32.const {}", tuple.len() * 8));
+                    self.append_line("call $stlc_alloc");
+                    self.append_line("i64.extend_i32_u");
+                    self.append_line(&format!("local.set ${var}"));
+                    for (i, v) in tuple.iter().enumerate() {
+                        self.append_line(&format!("local.get ${var}"));
+                        self.append_line("i32.wrap_i64");
+                        self.compile_value(v);
+                        self.append_line(&format!("i64.store offset={}", i * 8));
+                    }
+                }
+                ANF::Project(var, tuple, index) => {
+                    self.append_line(&format!("local.get ${tuple}"));
+                    self.append_line("i32.wrap_i64");
+                    self.append_line(&format!("i64.load offset={}", index * 8));
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Store(var, tuple, index, value) => {
+                    self.append_line(&format!("local.get ${tuple}"));
+                    self.append_line("i32.wrap_i64");
+                    self.compile_value(value);
+                    self.append_line(&format!("i64.store offset={}", index * 8));
+                    self.append_line("i64.const 0");
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Prim(var, prim, args) => {
+                    for arg in args {
+                        self.compile_value(arg);
+                    }
+                    self.append_line(&format!("call ${}", prim.symbol()));
+                    // every primitive returns unit
+                    self.append_line("i64.const 0");
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Switch(var, tag, branches) => {
+                    self.compile_switch(tag, branches, 0, tail);
+                    if !tail {
+                        self.append_line(&format!("local.set ${var}"));
+                    }
+                }
+                ANF::Trap(var) => {
+                    self.append_line(&format!(
+                        "(call $stlc_runtime_error (i64.const {}))",
+                        RuntimeError::MatchFailure.code()
+                    ));
+                    // the stack is polymorphic after `unreachable`, so the set still validates
+                    self.append_line("unreachable");
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Raise(var, value) => {
+                    self.compile_value(value);
+                    self.append_line("throw $stlc_exn");
+                    self.append_line(&format!("local.set ${var}"));
+                }
+                ANF::Try(var, body, exn, handler) => {
+                    // the body is never in tail position, a tail call would leave the handler
+                    self.append_line(&format!("(block $done_{var} (result i64)"));
+                    self.append_line(&format!("(block $catch_{var} (result i64)"));
+                    self.append_line(&format!(
+                        "(try_table (result i64) (catch $stlc_exn $catch_{var})"
+                    ));
+                    self.compile_body(body, false);
+                    self.append_line(")");
+                    self.append_line(&format!("br $done_{var}"));
+                    self.append_line(")");
+                    self.append_line(&format!("local.set ${exn}"));
+                    self.compile_body(handler, false);
+                    self.append_line(")");
+                    self.append_line(&format!("local.set ${var}"));
                 }
-                self.append_line(&format!("(call_indirect (type $t) (local.get ${func}))"));
-                self.append_line(&format!("local.set ${var}"));
-            }
-            ANF::BOp(var, op, v1, v2) => {
-                self.compile_value(v1);
-                self.compile_value(v2);
-                match op {
-                    Operator::Add => self.append_line("i32.add"),
-                    Operator::Sub => self.append_line("i32.sub"),
-                    Operator::Mul => self.append_line("i32.mul"),
-                    Operator::Div => self.append_line("i32.div_s"),
-                }
-                self.append_line(&format!("local.set ${var}"));
-            }
-            ANF::Tuple(var, tuple) => {
-                for (i, v) in tuple.iter().enumerate() {
-                    self.append_line("global.get $stack_pointer");
-                    self.compile_value(v);
-                    self.append_line(&format!("i32.store offset={}", i * 4));
-                }
-                self.append_line("global.get $stack_pointer");
-                self.append_line(&format!("local.set ${var}"));
-                self.append_line("global.get $stack_pointer");
-                self.append_line(&format!("i32.const {}", tuple.len() * 4));
-                self.append_line("i32.add");
-                self.append_line("global.set $stack_pointer");
-            }
-            ANF::Project(var, tuple, index) => {
-                self.append_line(&format!("local.get ${tuple}"));
-                self.append_line(&format!("i32.load offset={}", index * 4));
-                self.append_line(&format!("local.set ${var}"));
             }
-        }
+        })
     }
 
     fn compile_value(&mut self, value: &Value) {
         match value {
-            Value::Number(n) => self.append_line(&format!("i32.const {n}")),
+            Value::Number(n) => self.append_line(&format!("i64.const {n}")),
+            Value::Unit => self.append_line("i64.const 0"),
             Value::Var(var) => self.append_line(&format!("local.get ${var}")),
             Value::Global(var) => {
                 let index = self.fun_table.get(var).unwrap();
-                self.append_line(&format!("i32.const {index}"));
+                self.append_line(&format!("i64.const {index}"));
             }
         }
     }
 }
+
+fn collect_arities(anfs: &ANFs, arities: &mut BTreeSet<usize>) {
+    grow(|| {
+        for anf in &anfs.anfs {
+            match anf {
+                ANF::App(_, _, args) => {
+                    arities.insert(args.len());
+                }
+                ANF::Switch(_, _, branches) => {
+                    for branch in branches {
+                        collect_arities(branch, arities);
+                    }
+                }
+                ANF::Try(_, body, _, handler) => {
+                    collect_arities(body, arities);
+                    collect_arities(handler, arities);
+                }
+                _ => (),
+            }
+        }
+    })
+}
+
+fn collect_local_vars<'a>(anfs: &'a ANFs, local_vars: &mut HashSet<&'a Variable>) {
+    grow(|| {
+        for anf in &anfs.anfs {
+            match anf {
+                ANF::Fun(var, _, _)
+                | ANF::App(var, _, _)
+                | ANF::BOp(var, _, _, _)
+                | ANF::Tuple(var, _)
+                | ANF::Project(var, _, _)
+                | ANF::Store(var, _, _, _)
+                | ANF::Prim(var, _, _)
+                | ANF::Trap(var)
+                | ANF::Raise(var, _) => {
+                    local_vars.insert(var);
+                }
+                ANF::Try(var, body, exn, handler) => {
+                    local_vars.insert(var);
+                    local_vars.insert(exn);
+                    collect_local_vars(body, local_vars);
+                    collect_local_vars(handler, local_vars);
+                }
+                ANF::Switch(var, _, branches) => {
+                    local_vars.insert(var);
+                    for branch in branches {
+                        collect_local_vars(branch, local_vars);
+                    }
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,58 @@
+//! Errors alpha conversion reports about the names of a program.
+use simply_typed_lambda_calculus_compiler::{
+    alpha::{AlphaConvEnv, AlphaError},
+    ast::Span,
+    parser::expr_parser,
+};
+
+fn convert(source: &str) -> Result<(), AlphaError> {
+    let expr = expr_parser::expr(source).unwrap_or_else(|err| panic!("{}: {}", source, err));
+    AlphaConvEnv::new().alpha_conversion(expr).map(|_| ())
+}
+
+#[test]
+fn unbound_variables_suggest_names_in_scope() {
+    let err = convert("\\count. \\counter. cont + 1").unwrap_err();
+    assert_eq!(
+        err,
+        AlphaError::Unbound {
+            name: "cont".to_owned(),
+            span: Span { start: 18, end: 22 },
+            suggestions: vec!["count".to_owned()],
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "unbound variable cont, did you mean count?"
+    );
+}
+
+#[test]
+fn a_pattern_binds_each_name_once() {
+    let err = convert("\\p. match p with (x, x) -> x").unwrap_err();
+    assert_eq!(
+        err,
+        AlphaError::DuplicateBinder {
+            name: "x".to_owned(),
+            span: Span { start: 21, end: 22 },
+        }
+    );
+    assert_eq!(err.to_string(), "x is bound several times in the pattern");
+    for source in [
+        "\\l. match l with Cons (x, Cons (x, t)) -> x",
+        "\\p. match p with ((x, y), (z, y)) -> x",
+        "\\p. match p with A -> 0 | B (_a, _a) -> 1",
+    ] {
+        assert!(
+            matches!(convert(source), Err(AlphaError::DuplicateBinder { .. })),
+            "{}",
+            source
+        );
+    }
+    // the arms of a match and nested matches bind their names anew
+    assert_eq!(
+        convert("\\p. match p with (x, y) -> (match y with (x, z) -> x) | z -> z"),
+        Ok(())
+    );
+    assert_eq!(convert("\\p. match p with (_, _) -> 0"), Ok(()));
+}
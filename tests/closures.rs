@@ -0,0 +1,55 @@
+//! Closures capture each free variable of a function once, however often it is used.
+mod common;
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{
+    anf::{HoistedANFs, Value, ANF},
+    compile, Options,
+};
+
+const SOURCE: &str = "let f x = \\y. x + x * y + x;;
+f 2 3";
+
+fn hoisted() -> HoistedANFs {
+    let options = Options {
+        no_prelude: true,
+        ..Default::default()
+    };
+    compile(SOURCE, options).unwrap().hoisted
+}
+
+#[test]
+fn free_variables_are_captured_once() {
+    assert_eq!(run(SOURCE).stdout, "10\n");
+    let hoisted = hoisted();
+    // the lambda takes its environment and `y`
+    let (lambda, args, body) = hoisted
+        .fun_defs
+        .iter()
+        .find(|(_, args, _)| args.len() == 2 && args[1].name == "y")
+        .expect("the lambda is hoisted");
+    let env = &args[0];
+    let projections: Vec<_> = body
+        .anfs
+        .iter()
+        .filter_map(|anf| match anf {
+            ANF::Project(var, tuple, index) if tuple == env => Some((var.name.as_str(), *index)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(projections, [("x", 1)]);
+    // the closure built by `f` holds the code and `x`
+    let closure = hoisted
+        .fun_defs
+        .iter()
+        .flat_map(|(_, _, body)| &body.anfs)
+        .find_map(|anf| match anf {
+            ANF::Tuple(_, fields) if fields.first() == Some(&Value::Global(lambda.clone())) => {
+                Some(fields)
+            }
+            _ => None,
+        })
+        .expect("f builds the closure of the lambda");
+    assert_eq!(closure.len(), 2);
+    assert!(matches!(&closure[1], Value::Var(x) if x.name == "x"));
+}
@@ -0,0 +1,171 @@
+//! Runs programs on the JIT, as native executables and on a wasm runtime, to check that the three
+//! agree.
+// each test crate uses its own part of the harness
+#![allow(dead_code)]
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use simply_typed_lambda_calculus_compiler::{compile, Backend, Options};
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store};
+
+const COMPILER: &str = env!("CARGO_BIN_EXE_simply_typed_lambda_calculus_compiler");
+
+/// What a test checks of a run of a program.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Run {
+    pub stdout: String,
+    pub status: i32,
+}
+
+impl Run {
+    fn new(output: Output) -> Run {
+        Run {
+            stdout: String::from_utf8(output.stdout).unwrap(),
+            status: output.status.code().expect("killed by a signal"),
+        }
+    }
+}
+
+/// Runs `source` on every backend and returns the run they agree on.
+pub fn run(source: &str) -> Run {
+    let jit = run_jit(source);
+    assert_eq!(run_native(source), jit, "native executable and JIT differ");
+    assert_eq!(run_wasm(source), jit, "wasm and JIT differ");
+    jit
+}
+
+pub fn run_jit(source: &str) -> Run {
+    let output = Command::new(COMPILER)
+        .arg("--")
+        .arg(source)
+        .output()
+        .unwrap();
+    Run::new(output)
+}
+
+/// Links the object file with runtime/runtime.c, as the help of `--object` says.
+pub fn run_native(source: &str) -> Run {
+    let dir = scratch_dir();
+    let object = dir.join("program.o");
+    let executable = dir.join("program");
+    let output = Command::new(COMPILER)
+        .arg("--object")
+        .arg(&object)
+        .arg("--")
+        .arg(source)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let runtime = Path::new(env!("CARGO_MANIFEST_DIR")).join("runtime/runtime.c");
+    let status = Command::new("cc")
+        .arg(&object)
+        .arg(runtime)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    Run::new(Command::new(&executable).output().unwrap())
+}
+
+/// Runs `_start` with just the two WASI functions the backend imports.
+pub fn run_wasm(source: &str) -> Run {
+    let options = Options {
+        backend: Some(Backend::Wasm),
+        ..Default::default()
+    };
+    let wat = compile(source, options).unwrap().output.unwrap();
+    let mut config = Config::new();
+    config.wasm_tail_call(true);
+    config.wasm_exceptions(true);
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::new(&engine, &wat).unwrap();
+    // the store keeps what the program writes to stdout
+    let mut store = Store::new(&engine, Vec::new());
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("wasi_snapshot_preview1", "fd_write", fd_write)
+        .unwrap();
+    linker
+        .func_wrap(
+            "wasi_snapshot_preview1",
+            "proc_exit",
+            |status: i32| -> wasmtime::Result<()> { Err(Exit(status).into()) },
+        )
+        .unwrap();
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .unwrap();
+    let status = match start.call(&mut store, ()) {
+        Ok(()) => 0,
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(&Exit(status)) => status,
+            None => panic!("{:?}", err),
+        },
+    };
+    Run {
+        stdout: String::from_utf8(store.into_data()).unwrap(),
+        status,
+    }
+}
+
+/// Gathers the buffers written to stdout, stderr is left out like it is for the other backends.
+fn fd_write(
+    mut caller: Caller<'_, Vec<u8>>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nwritten: i32,
+) -> i32 {
+    let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+    let mut written = Vec::new();
+    for i in 0..iovs_len {
+        let mut iov = [0; 8];
+        memory
+            .read(&caller, (iovs + i * 8) as usize, &mut iov)
+            .unwrap();
+        let ptr = u32::from_le_bytes(iov[..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(iov[4..].try_into().unwrap()) as usize;
+        written.extend_from_slice(&memory.data(&caller)[ptr..ptr + len]);
+    }
+    let len = written.len() as u32;
+    if fd == 1 {
+        caller.data_mut().extend(written);
+    }
+    memory
+        .write(&mut caller, nwritten as usize, &len.to_le_bytes())
+        .unwrap();
+    0
+}
+
+/// The status passed to `proc_exit`, which ends the run as an error.
+#[derive(Debug)]
+struct Exit(i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// A fresh directory for the files of one run, tests run in parallel.
+fn scratch_dir() -> PathBuf {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let dir =
+        Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("run-{}-{}", std::process::id(), run));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,192 @@
+//! Alpha-equivalence of expressions and a-normal forms.
+use simply_typed_lambda_calculus_compiler::{
+    alpha::AlphaConvEnv,
+    anf::{ANFs, Value, ANF},
+    ast::{Expr, Operator, Span, Variable},
+    debruijn::{alpha_eq, anfs_alpha_eq, to_debruijn, Term},
+    parser::expr_parser,
+};
+
+fn parse(source: &str) -> Expr {
+    expr_parser::expr(source).unwrap_or_else(|err| panic!("{}: {}", source, err))
+}
+
+fn var(name: &str, id: usize) -> Variable {
+    Variable {
+        name: name.to_owned(),
+        id,
+        span: Span::default(),
+    }
+}
+
+fn add(x: Variable, y: Variable) -> Expr {
+    Expr::BOp(
+        Operator::Add,
+        Box::new(Expr::Var(x)),
+        Box::new(Expr::Var(y)),
+    )
+}
+
+#[test]
+fn shadowing() {
+    assert!(alpha_eq(&parse("\\x. \\x. x"), &parse("\\x. \\y. y")));
+    assert!(!alpha_eq(&parse("\\x. \\x. x"), &parse("\\x. \\y. x")));
+    assert!(alpha_eq(
+        &parse("\\x. (\\x. x) x"),
+        &parse("\\y. (\\z. z) y")
+    ));
+    assert!(!alpha_eq(
+        &parse("\\x. (\\x. x) x"),
+        &parse("\\y. (\\z. y) y")
+    ));
+    assert!(alpha_eq(
+        &parse("try \\e. e with e -> e"),
+        &parse("try \\a. a with b -> b")
+    ));
+    // alpha conversion gives each binder its own id
+    let expr = parse("\\x. \\x. x + (\\x. x) 1");
+    let converted = AlphaConvEnv::new().alpha_conversion(expr.clone()).unwrap();
+    assert_ne!(expr, converted);
+    assert!(alpha_eq(&expr, &converted));
+}
+
+#[test]
+fn match_binders() {
+    assert!(alpha_eq(
+        &parse("\\p. match p with (a, b) -> a"),
+        &parse("\\q. match q with (x, y) -> x")
+    ));
+    assert!(!alpha_eq(
+        &parse("\\p. match p with (a, b) -> a"),
+        &parse("\\q. match q with (x, y) -> y")
+    ));
+    // the binders of an arm end with it
+    assert!(alpha_eq(
+        &parse("\\x. match l with Cons (x, xs) -> x | Nil -> x"),
+        &parse("\\y. match l with Cons (z, zs) -> z | Nil -> y")
+    ));
+    assert!(!alpha_eq(
+        &parse("\\x. match l with Cons (x, xs) -> x | Nil -> x"),
+        &parse("\\y. match l with Cons (z, zs) -> y | Nil -> y")
+    ));
+    assert!(alpha_eq(
+        &parse("match l with Cons (x, l) -> (match l with Cons (x, l) -> x)"),
+        &parse("match l with Cons (a, b) -> (match b with Cons (c, d) -> c)")
+    ));
+    assert!(alpha_eq(
+        &parse("case s of inl x -> x | inr x -> x"),
+        &parse("case s of inl a -> a | inr b -> b")
+    ));
+}
+
+#[test]
+fn free_variables() {
+    assert_eq!(
+        to_debruijn(&parse("\\x. f x")),
+        Term::Abs(
+            None,
+            Box::new(Term::App(
+                Box::new(Term::Free("f".to_owned(), 0)),
+                Box::new(Term::Bound(0))
+            ))
+        )
+    );
+    assert!(!alpha_eq(&parse("f"), &parse("g")));
+    // a free variable of one expression stands for the same one of the other every time
+    assert!(alpha_eq(
+        &add(var("x", 1), var("y", 2)),
+        &add(var("x", 3), var("y", 4))
+    ));
+    assert!(alpha_eq(
+        &add(var("x", 1), var("x", 1)),
+        &add(var("x", 3), var("x", 3))
+    ));
+    assert!(!alpha_eq(
+        &add(var("x", 1), var("x", 2)),
+        &add(var("x", 3), var("x", 3))
+    ));
+    assert!(!alpha_eq(
+        &add(var("x", 1), var("x", 1)),
+        &add(var("x", 3), var("x", 4))
+    ));
+}
+
+fn anfs(anfs: Vec<ANF>, value: Value) -> ANFs {
+    ANFs {
+        anfs,
+        value: Some(value),
+        level: 0,
+    }
+}
+
+/// `let f#id x#id+1 y#id+2 = body in f`
+fn fun(id: usize, body: impl FnOnce(Variable, Variable) -> ANFs) -> ANFs {
+    let (f, x, y) = (var("f", id), var("x", id + 1), var("y", id + 2));
+    let body = body(x.clone(), y.clone());
+    anfs(vec![ANF::Fun(f.clone(), vec![x, y], body)], Value::Var(f))
+}
+
+#[test]
+fn anf_binders() {
+    let first = |x, _| anfs(Vec::new(), Value::Var(x));
+    let second = |_, y| anfs(Vec::new(), Value::Var(y));
+    assert!(anfs_alpha_eq(&fun(1, first), &fun(10, first)));
+    assert!(!anfs_alpha_eq(&fun(1, first), &fun(10, second)));
+    // a binder of a branch is paired like any other
+    let branch = |id, result| {
+        let r = var("r", id);
+        anfs(
+            vec![ANF::BOp(
+                r.clone(),
+                Operator::Add,
+                Value::Number(1),
+                Value::Number(1),
+            )],
+            Value::Var(if result { r } else { var("b", 0) }),
+        )
+    };
+    let switch = |id, result| {
+        let s = var("s", id);
+        anfs(
+            vec![ANF::Switch(
+                s.clone(),
+                Value::Var(var("b", 0)),
+                vec![branch(id + 1, result), branch(id + 2, true)],
+            )],
+            Value::Var(s),
+        )
+    };
+    assert!(anfs_alpha_eq(&switch(1, true), &switch(20, true)));
+    assert!(!anfs_alpha_eq(&switch(1, true), &switch(20, false)));
+}
+
+#[test]
+fn anf_free_variables() {
+    let sum = |r, x, y| {
+        anfs(
+            vec![ANF::BOp(
+                var("r", r),
+                Operator::Add,
+                Value::Var(x),
+                Value::Var(y),
+            )],
+            Value::Var(var("r", r)),
+        )
+    };
+    assert!(anfs_alpha_eq(
+        &sum(0, var("x", 1), var("y", 2)),
+        &sum(5, var("x", 3), var("y", 4))
+    ));
+    assert!(!anfs_alpha_eq(
+        &sum(0, var("x", 1), var("y", 2)),
+        &sum(5, var("z", 1), var("y", 2))
+    ));
+    assert!(!anfs_alpha_eq(
+        &sum(0, var("x", 1), var("x", 2)),
+        &sum(5, var("x", 3), var("x", 3))
+    ));
+    assert!(!anfs_alpha_eq(
+        &sum(0, var("x", 1), var("x", 1)),
+        &sum(5, var("x", 3), var("x", 4))
+    ));
+}
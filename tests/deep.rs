@@ -0,0 +1,63 @@
+//! Programs nested far deeper than the stack of a test thread allows without `stack::grow`.
+
+use simply_typed_lambda_calculus_compiler::{compile, parser::parse_program, Backend, Options};
+
+const DEPTH: usize = 100_000;
+
+/// Each nested function keeps a type as deep as the functions inside it, so their size grows with
+/// the square of the depth.
+const LAMBDA_DEPTH: usize = 1_000;
+
+fn compile_on_both_backends(source: &str) {
+    for backend in [Backend::Llvm, Backend::Wasm] {
+        let options = Options {
+            backend: Some(backend),
+            // keeps the syntax trees around to be printed and dropped too
+            ast: true,
+            alpha: true,
+            verify: true,
+            ..Default::default()
+        };
+        match compile(source, options) {
+            Ok(artifact) => assert!(!artifact.ast.unwrap().to_string().is_empty()),
+            Err(err) => panic!("{:?}: {}", backend, err),
+        }
+    }
+}
+
+#[test]
+fn nested_applications() {
+    let source = format!(
+        "let f x = x + 1;;\n{}1{}",
+        "f (".repeat(DEPTH),
+        ")".repeat(DEPTH)
+    );
+    compile_on_both_backends(&source);
+}
+
+#[test]
+fn sequences() {
+    compile_on_both_backends(&format!("{}0", "print_int 1; ".repeat(DEPTH)));
+}
+
+#[test]
+fn sums() {
+    compile_on_both_backends(&format!("{}1{}", "1 + (".repeat(DEPTH), ")".repeat(DEPTH)));
+}
+
+#[test]
+fn nested_lambdas() {
+    let binders = (0..LAMBDA_DEPTH).map(|i| format!("\\x{}. ", i));
+    compile_on_both_backends(&format!("{}x0 + 1", binders.collect::<String>()));
+}
+
+/// A program takes its expressions apart when it is dropped, an expression on its own would recurse.
+#[test]
+fn debug_printing() {
+    let source = format!("{}1{}", "1 + (".repeat(DEPTH), ")".repeat(DEPTH));
+    let program = parse_program(&source).unwrap();
+    assert_eq!(
+        format!("{:?}", program).matches("Number(1)").count(),
+        DEPTH + 1
+    );
+}
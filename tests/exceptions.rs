@@ -0,0 +1,15 @@
+//! A raise takes whatever type its context expects.
+mod common;
+
+use common::run;
+
+#[test]
+fn raise_typed_by_its_context() {
+    let source = "type flag = Yes | No;;
+exception Stop;;
+let pair f = match f with Yes -> raise Stop | No -> (1, 2);;
+let applied = try (raise Stop) 1 with e -> 10;;
+let projected = try fst (pair Yes) with e -> snd (pair No);;
+applied + projected";
+    assert_eq!(run(source).stdout, "12\n");
+}
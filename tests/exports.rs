@@ -0,0 +1,66 @@
+//! Top-level functions are exported under symbols of their own, whatever they are named.
+mod common;
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{compile, runtime::export_symbol, Backend, Options};
+
+/// Functions named after the entry point, the C library and the runtime.
+const SOURCE: &str = "let main x = x + 1;;
+let malloc x = x + 2;;
+let exit x = x + 3;;
+let printf x = x + 4;;
+let stlc_print_int x = x + 5;;
+main 0 + malloc 0 + exit 0 + printf 0 + stlc_print_int 0";
+
+fn output(backend: Backend) -> String {
+    let options = Options {
+        backend: Some(backend),
+        ..Default::default()
+    };
+    compile(SOURCE, options).unwrap().output.unwrap()
+}
+
+#[test]
+fn names_of_the_c_library_do_not_clash() {
+    assert_eq!(run(SOURCE).stdout, "15\n");
+}
+
+#[test]
+fn exports_are_prefixed() {
+    let llvm = output(Backend::Llvm);
+    let wasm = output(Backend::Wasm);
+    for name in ["main", "malloc", "exit", "printf", "stlc_print_int"] {
+        let symbol = export_symbol(name);
+        assert!(llvm.contains(&format!("define i64 @{}(ptr", symbol)));
+        assert!(wasm.contains(&format!("(export \"{}\"", symbol)));
+    }
+}
+
+#[test]
+fn symbols_are_c_identifiers() {
+    assert_eq!(export_symbol("fold_left"), "stlc_export_fold_uleft");
+    assert_eq!(export_symbol("list.map"), "stlc_export_list__map");
+    assert_eq!(export_symbol("f'"), "stlc_export_f_prime");
+    assert_eq!(export_symbol("my-list.f"), "stlc_export_my_x2d_list__f");
+}
+
+/// Names that would have the same symbol if `_` was not escaped.
+#[test]
+fn different_names_have_different_symbols() {
+    assert_ne!(export_symbol("f'"), export_symbol("f_prime"));
+    assert_ne!(export_symbol("a.b"), export_symbol("a__b"));
+    assert_ne!(export_symbol("a-b"), export_symbol("a_x2db"));
+    assert_ne!(export_symbol("\u{2d}a"), export_symbol("\u{2da}"));
+    let source = "let f' x = x + 1;;
+let f_prime x = x + 2;;
+f' 0 * 10 + f_prime 0";
+    assert_eq!(run(source).stdout, "12\n");
+    let options = Options {
+        backend: Some(Backend::Wasm),
+        ..Default::default()
+    };
+    let wasm = compile(source, options).unwrap().output.unwrap();
+    for name in ["f'", "f_prime"] {
+        assert!(wasm.contains(&format!("(export \"{}\"", export_symbol(name))));
+    }
+}
@@ -0,0 +1,90 @@
+//! Modules are linked into one program, but each has its own types, constructors and values.
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{compile, Options};
+
+/// Writes a module to a directory of the test, since imports are read from files.
+fn module(test: &str, name: &str, source: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("modules-{}", std::process::id()))
+        .join(test);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn modules_declare_the_same_type() {
+    let a = module(
+        "same_type",
+        "a.stlc",
+        "type t = A of int | B;;
+exception Stop;;
+let a_value = A 1;;
+let a_get x = match x with A n -> n | B -> 0;;
+let a_fail u = raise Stop",
+    );
+    let b = module(
+        "same_type",
+        "b.stlc",
+        "type t = A | B of int;;
+exception Stop;;
+let b_value = B 2;;
+let b_get x = match x with A -> 0 | B n -> n;;
+let b_fail u = raise Stop",
+    );
+    let source = format!(
+        "import {:?};;
+import {:?};;
+type t = C;;
+let own = match C with C -> 100;;
+let caught f = try f () with e -> (match e with Stop -> 10 | _ -> 20);;
+a.a_get a.a_value + b.b_get b.b_value + own + caught a.a_fail + caught b.b_fail",
+        a, b
+    );
+    // `Stop` is the exception of the module imported last
+    assert_eq!(run(&source).stdout, "133\n");
+}
+
+#[test]
+fn imported_types_are_shadowed() {
+    let nat = module(
+        "shadowed",
+        "nat.stlc",
+        "type nat = Z | S of nat;;
+let three = S (S (S Z));;
+let rec to_int n = match n with Z -> 0 | S m -> 1 + to_int m",
+    );
+    let source = format!(
+        "import {:?};;
+type nat = S | Z;;
+let own n = match n with S -> 1 | Z -> 2;;
+own Z + nat.to_int nat.three",
+        nat
+    );
+    assert_eq!(run(&source).stdout, "5\n");
+}
+
+#[test]
+fn values_are_qualified_by_their_module() {
+    let a = module("qualified", "a.stlc", "let get x = x + 1;;\nlet one = 1");
+    let b = module(
+        "qualified",
+        "b.stlc",
+        "let get x = x * 10;;\nlet get' x = x",
+    );
+    let source = format!(
+        "import {:?};;
+import {:?};;
+let get x = x + 100;;
+a.get a.one + b.get 2 + b.get' 3 + get 0",
+        a, b
+    );
+    assert_eq!(run(&source).stdout, "125\n");
+    let err = compile(&format!("import {:?};;\nget 1", a), Options::default()).unwrap_err();
+    assert!(err.to_string().ends_with("unbound variable get"), "{}", err);
+}
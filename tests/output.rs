@@ -0,0 +1,44 @@
+//! The JIT, native executables and wasm print the same text and exit with the same status.
+mod common;
+
+use common::{run, Run};
+
+fn ok(stdout: &str) -> Run {
+    Run {
+        stdout: stdout.to_owned(),
+        status: 0,
+    }
+}
+
+#[test]
+fn int_result_is_printed() {
+    assert_eq!(run("1 + 2"), ok("3\n"));
+    assert_eq!(run("0 - 12"), ok("-12\n"));
+}
+
+#[test]
+fn output_comes_before_the_result() {
+    assert_eq!(run("print_int 1; print_newline (); 2"), ok("1\n2\n"));
+}
+
+#[test]
+fn other_results_are_not_printed() {
+    assert_eq!(run("print_int 5"), ok("5"));
+    assert_eq!(run("(print_int 1, 2)"), ok("1"));
+    assert_eq!(run("\\x. x + 1"), ok(""));
+}
+
+#[test]
+fn runtime_errors_exit_with_status_2() {
+    let expected = Run {
+        stdout: "1".to_owned(),
+        status: 2,
+    };
+    assert_eq!(run("print_int 1; 1 / 0"), expected);
+    assert_eq!(run("exception Stop;;\nprint_int 1; raise Stop"), expected);
+    // a match that is not exhaustive only warns, reaching the missing case is an error
+    assert_eq!(
+        run("type t = A | B;;\nprint_int 1; match B with A -> 0"),
+        expected
+    );
+}
@@ -0,0 +1,154 @@
+//! How the parser groups the expressions where that is easy to get wrong.
+use simply_typed_lambda_calculus_compiler::{
+    ast::{Expr, Operator, Pattern, Span, Variable},
+    parser::{expr_parser, parse_program},
+};
+
+fn parse(source: &str) -> Expr {
+    expr_parser::expr(source).unwrap_or_else(|err| panic!("{}: {}", source, err))
+}
+
+fn var(name: &str) -> Expr {
+    Expr::Var(Variable {
+        name: name.to_owned(),
+        id: 0,
+        span: Span::default(),
+    })
+}
+
+fn app(f: Expr, x: Expr) -> Expr {
+    Expr::App(Box::new(f), Box::new(x))
+}
+
+fn constr(name: &str, fields: Vec<Expr>) -> Expr {
+    Expr::Constr(name.to_owned(), fields)
+}
+
+#[test]
+fn nullary_constructors_as_arguments() {
+    let nil = || constr("Nil", Vec::new());
+    assert_eq!(parse("f Nil x"), app(app(var("f"), nil()), var("x")));
+    assert_eq!(parse("f Nil (x)"), app(app(var("f"), nil()), var("x")));
+    assert_eq!(parse("f x Nil"), app(app(var("f"), var("x")), nil()));
+    assert_eq!(
+        parse("g Z (S Z)"),
+        app(
+            app(var("g"), constr("Z", Vec::new())),
+            constr("S", vec![constr("Z", Vec::new())])
+        )
+    );
+}
+
+#[test]
+fn constructor_fields() {
+    assert_eq!(parse("S x"), constr("S", vec![var("x")]));
+    assert_eq!(parse("f (S x)"), app(var("f"), constr("S", vec![var("x")])));
+    assert_eq!(
+        parse("Cons (1, Nil)"),
+        constr("Cons", vec![Expr::Number(1), constr("Nil", Vec::new())])
+    );
+    // a tuple as the only field
+    assert_eq!(
+        parse("Box ((x, y))"),
+        constr("Box", vec![Expr::Tuple(vec![var("x"), var("y")])])
+    );
+    assert_eq!(
+        parse("f (x, y)"),
+        app(var("f"), Expr::Tuple(vec![var("x"), var("y")]))
+    );
+}
+
+#[test]
+fn nullary_constructors_in_patterns() {
+    let arms = match parse("match l with Nil -> 0 | Cons (x, Nil) -> x") {
+        Expr::Match(_, arms) => arms,
+        e => panic!("not a match: {:?}", e),
+    };
+    let patterns = arms.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+    let x = Variable {
+        name: "x".to_owned(),
+        id: 0,
+        span: Span::default(),
+    };
+    assert_eq!(
+        patterns,
+        [
+            Pattern::Constr("Nil".to_owned(), Vec::new()),
+            Pattern::Constr(
+                "Cons".to_owned(),
+                vec![
+                    Pattern::Var(x),
+                    Pattern::Constr("Nil".to_owned(), Vec::new())
+                ]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn uppercase_binder() {
+    for source in [
+        "\\X. X",
+        "let X = 1;;\nX",
+        "let f X = X;;\nf 1",
+        "\\p. case p of inl A -> 1 | inr b -> 2",
+    ] {
+        let errors = parse_program(source).unwrap_err();
+        assert!(
+            errors[0]
+                .message
+                .ends_with("a name starting with an uppercase letter is a constructor"),
+            "{}: {}",
+            source,
+            errors[0]
+        );
+    }
+    assert_eq!(
+        parse_program("\\X. X").unwrap_err()[0].message,
+        "expected one of '(', identifier, found 'X', a name starting with an uppercase letter is a constructor"
+    );
+}
+
+/// A `-` after an operand is the infix operator, an argument never starts with one.
+#[test]
+fn subtraction_is_left_associative() {
+    let op = |op, x, y| Expr::BOp(op, Box::new(x), Box::new(y));
+    assert_eq!(
+        parse("a - b - c"),
+        op(
+            Operator::Sub,
+            op(Operator::Sub, var("a"), var("b")),
+            var("c")
+        )
+    );
+    assert_eq!(
+        parse("x * 2 - 1"),
+        op(
+            Operator::Sub,
+            op(Operator::Mul, var("x"), Expr::Number(2)),
+            Expr::Number(1)
+        )
+    );
+    assert_eq!(
+        parse("f x -1"),
+        op(Operator::Sub, app(var("f"), var("x")), Expr::Number(1))
+    );
+}
+
+#[test]
+fn multi_binder_lambdas() {
+    let abs = |name: &str, body| {
+        Expr::Abs(
+            Variable {
+                name: name.to_owned(),
+                id: 0,
+                span: Span::default(),
+            },
+            None,
+            Box::new(body),
+        )
+    };
+    let expected = abs("x1", abs("y'", app(var("x1"), var("y'"))));
+    assert_eq!(parse("\\x1 y'. x1 y'"), expected);
+    assert_eq!(parse("λx1 y'. x1 y'"), expected);
+}
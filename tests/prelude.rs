@@ -0,0 +1,64 @@
+//! The functions of the prelude, compiled once and used at several types.
+mod common;
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{compile, typeinfer::Type, Options};
+
+/// Polymorphic functions used with ints, tuples and closures.
+#[test]
+fn uses_at_several_types() {
+    let source = "let p = id (1, 2);;
+let f = id (\\x. x * 10);;
+fst p + snd p + f (id 4) + snd (id (id, 0))";
+    assert_eq!(run(source).stdout, "43\n");
+}
+
+/// A use at another type calls a copy of the code made for that type, so the backends can lay out
+/// values by their type.
+#[test]
+fn uses_at_other_types_call_copies() {
+    let hoisted = compile("snd (id (1, 2)) + id 3", Options::default())
+        .unwrap()
+        .hoisted;
+    let params = hoisted
+        .fun_defs
+        .iter()
+        .filter(|(fun, _, _)| fun.name == "id")
+        .map(|(_, args, _)| hoisted.types[&args[1].id].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(params.len(), 3);
+    assert!(params.contains(&Type::Product(vec![Type::Int, Type::Int])));
+    assert!(params.contains(&Type::Int));
+}
+
+/// The list functions at accumulators of several types, including closures.
+#[test]
+fn folds_at_several_types() {
+    let source = "let l = Cons (1, Cons (2, Cons (3, Nil)));;
+let total = fold_left (\\acc x. acc + x) 0 l;;
+let pair = fold_left (\\acc x. (fst acc + x, snd acc * x)) (0, 1) l;;
+let shifted = fold_left (\\f x. \\y. f (y + x)) id l;;
+let digits = fold_right (\\x acc. acc * 10 + x) l 0;;
+total + fst pair + snd pair + shifted 10 + digits + length (rev (map (\\x. x * x) l))";
+    assert_eq!(run(source).stdout, "358\n");
+}
+
+#[test]
+fn combinators_at_several_types() {
+    let source = "let add = uncurry (\\x y. x + y);;
+let product = curry (\\p. fst p * snd p);;
+let two = church_succ (church_succ church_zero);;
+let three = church_add two (church_succ church_zero);;
+fst (fst (swap (1, (2, 3)))) + add (4, 5) + product 6 7 + const 8 () + flip const (\\x. x) 9
+  + compose (\\x. x * 2) (\\p. fst p) (10, ()) + church_to_int (church_mul two three)";
+    assert_eq!(run(source).stdout, "96\n");
+}
+
+/// The prelude's list type and its constructors are shadowed like its functions.
+#[test]
+fn programs_declare_their_own_list() {
+    let source = "type list = Nil | Cons of int * int * list;;
+let rec length l = match l with Nil -> 0 | Cons (x, y, t) -> x * y + length t;;
+length (Cons (2, 3, Cons (4, 5, Nil)))";
+    assert_eq!(run(source).stdout, "26\n");
+}
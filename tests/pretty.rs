@@ -0,0 +1,134 @@
+//! Printed programs parse back to the same syntax tree, where a missing pair of parentheses would
+//! change it.
+use simply_typed_lambda_calculus_compiler::{
+    ast::{Expr, Operator, Span, Variable},
+    parser::parse_program,
+    pretty::format_source,
+};
+
+/// Checks `Display` and `format_source`, at a width every expression fits in and at one that
+/// breaks most of them.
+fn round_trip(source: &str) {
+    let program = parse_program(source).unwrap_or_else(|errors| panic!("{}: {:?}", source, errors));
+    let printed = program.to_string();
+    assert_eq!(
+        parse_program(&printed).as_ref(),
+        Ok(&program),
+        "{} is printed as {}",
+        source,
+        printed
+    );
+    for width in [80, 8] {
+        let formatted = format_source(source, width)
+            .unwrap_or_else(|err| panic!("{} at width {}: {}", source, width, err));
+        assert_eq!(
+            parse_program(&formatted).as_ref(),
+            Ok(&program),
+            "{} is formatted as {}",
+            source,
+            formatted
+        );
+    }
+}
+
+fn var(name: &str) -> Expr {
+    Expr::Var(Variable {
+        name: name.to_owned(),
+        id: 0,
+        span: Span::default(),
+    })
+}
+
+#[test]
+fn negative_literals() {
+    for source in [
+        "-1",
+        "f (-1)",
+        "S (-1)",
+        "1 - -1",
+        "fst (-1, 2)",
+        "(-1).0",
+        "-1 * x",
+    ] {
+        round_trip(source);
+    }
+    // the parser reads `-1` as a negation, so a literal only comes from building the tree
+    let literal = Expr::Number(-1);
+    let app = Expr::App(Box::new(var("f")), Box::new(literal.clone()));
+    assert_eq!(app.to_string(), "f (-1)");
+    assert_eq!(
+        Expr::Constr("S".to_owned(), vec![literal]).to_string(),
+        "S (-1)"
+    );
+}
+
+#[test]
+fn subtraction_from_zero() {
+    for source in [
+        "0 - x",
+        "0 - 1",
+        "0 - (0 - x)",
+        "0 - x * y",
+        "(0 - x) * y",
+        "0 - f x",
+        "0 - x.0",
+        "!(0 - x)",
+        "a - (0 - b)",
+    ] {
+        round_trip(source);
+    }
+    let neg = Expr::BOp(Operator::Sub, Box::new(Expr::Number(0)), Box::new(var("x")));
+    assert_eq!(
+        Expr::App(Box::new(var("f")), Box::new(neg)).to_string(),
+        "f (-x)"
+    );
+}
+
+#[test]
+fn nullary_constructors_as_arguments() {
+    for source in [
+        "f Nil x",
+        "f x Nil",
+        "g Z (S Z)",
+        "S Nil",
+        "Cons (Nil, Nil)",
+        "f (S x) Nil",
+        "fst Nil",
+    ] {
+        round_trip(source);
+    }
+}
+
+/// A lambda, `case`, `match` or `try` extends as far right as it can, so it needs parentheses
+/// before anything that continues the expression.
+#[test]
+fn open_expressions_before_operators() {
+    for source in [
+        "(\\x. x) 1",
+        "(\\x. x) + 1",
+        "(\\x. x); 1",
+        "(\\x. x).0",
+        "(\\x. x) := 1",
+        "(match x with A -> 1) + 2",
+        "(match x with A -> 1); 2",
+        "(match x with A -> 1) x",
+        "match x with A -> (match y with B -> 1) | C -> 2",
+        "(case x of inl a -> 1 | inr b -> 2) + 1",
+        "(try f x with e -> 0) + 1",
+        "(try x with e -> e) y",
+        "f (try x with e -> 1) + 1",
+        "raise (match x with A -> e) + 1",
+    ] {
+        round_trip(source);
+    }
+}
+
+#[test]
+fn declarations() {
+    round_trip(
+        "type t = A | B of int;;
+let f x = (match x with A -> 0 | B n -> n) - 1;;
+let g = \\x. (try f x with e -> 0) * 2;;
+f (B (-1)) - g A - f A",
+    );
+}
@@ -0,0 +1,35 @@
+//! Mutable cells, and the value restriction that keeps their type from being generalized.
+mod common;
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{compile, typeinfer::TypeError, CompileError, Options};
+
+#[test]
+fn cells_are_updated_in_place() {
+    let source = "let counter = ref 0;;
+let incr u = counter := !counter + 1;;
+incr (); incr (); !counter";
+    assert_eq!(run(source).stdout, "2\n");
+}
+
+/// Only a `let` bound to a syntactic lambda is generalized, so a cell holding a polymorphic
+/// function has a single type: storing a function on ints in it and then applying what it holds
+/// to unit would otherwise pass unit to `+`.
+#[test]
+fn cells_are_not_generalized() {
+    let source = "let r = ref (\\x. x);;
+r := (\\x. x + 1);
+(!r) 2";
+    assert_eq!(run(source).stdout, "3\n");
+    for source in [
+        "let r = ref (\\x. x);;\nr := (\\x. x + 1); (!r) ()",
+        "let r = ref (\\x. x);;\n(!r) 1; (!r) ()",
+        // an application is not a value, whatever it evaluates to
+        "let f = (\\g. g) (\\x. x);;\nf 1; f ()",
+    ] {
+        match compile(source, Options::default()) {
+            Err(CompileError::Type(TypeError::Mismatch(..))) => {}
+            result => panic!("{}: {:?}", source, result.map(|_| ())),
+        }
+    }
+}
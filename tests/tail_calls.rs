@@ -0,0 +1,89 @@
+//! Calls in tail position become jumps, so loops written as recursive functions run in constant
+//! stack on every backend.
+mod common;
+
+use common::{run, run_wasm};
+use simply_typed_lambda_calculus_compiler::{compile, Backend, Options};
+
+/// The outer function ends by calling `f`, main still has an addition to do after its call.
+const SOURCE: &str = "(\\f. f 1) (\\x. x + 1) + 1";
+
+/// The code of the program alone, without the calls in the prelude's.
+fn output(source: &str, backend: Backend) -> String {
+    let options = Options {
+        backend: Some(backend),
+        no_prelude: true,
+        ..Default::default()
+    };
+    compile(source, options).unwrap().output.unwrap()
+}
+
+#[test]
+fn wasm_tail_calls_return() {
+    let wat = output(SOURCE, Backend::Wasm);
+    assert_eq!(wat.matches("return_call_indirect").count(), 1, "{}", wat);
+    assert_eq!(run_wasm(SOURCE).stdout, "3\n");
+}
+
+#[test]
+fn llvm_tail_calls_are_marked() {
+    let ir = output(SOURCE, Backend::Llvm);
+    assert_eq!(ir.matches("tail call tailcc").count(), 1, "{}", ir);
+}
+
+/// Each `g 1 2 3 4` allocates closures of two, three and four words, 1024 of them take more than
+/// the single page of memory a wasm program starts with. `h` makes 32 of the calls and is called
+/// 32 times, the sums are balanced, so the program stays short and shallow.
+#[test]
+fn allocating_past_the_first_page() {
+    fn sum(n: usize, term: &str) -> String {
+        if n == 1 {
+            term.to_owned()
+        } else {
+            format!("({} + {})", sum(n / 2, term), sum(n - n / 2, term))
+        }
+    }
+    let source = format!(
+        "(\\g. (\\h. {}) (\\u. {})) (\\a. \\b. \\c. \\d. a + b + c + d)",
+        sum(32, "h 0"),
+        sum(32, "g 1 2 3 4")
+    );
+    assert_eq!(run_wasm(&source).stdout, "10240\n");
+}
+
+/// Declares `k`, the unary number 1000, and a loop counting it down a thousand and one times with
+/// `step` run on each count. `k` is built by multiplying ten by itself, since written out its
+/// thousand nested constructors would take the compiler deeper than its stack.
+fn countdown(step: &str) -> String {
+    format!(
+        "type nat = Z | S of nat
+let rec add m n = match m with Z -> n | S p -> S (add p n);;
+let rec times m n = match m with Z -> Z | S p -> add n (times p n);;
+let ten = S (S (S (S (S (S (S (S (S (S Z)))))))));;
+let k = times ten (times ten ten);;
+let i = ref k;;
+let j = ref k;;
+let count = ref 0;;
+let rec loop u = match !i with
+  | S p -> i := p; {step}; loop ()
+  | Z -> (match !j with Z -> () | S q -> j := q; i := k; loop ());;
+loop (); !count"
+    )
+}
+
+/// A million calls in tail position, far more than the stack has room for as frames. With no
+/// comparisons in the language, the loop counts a unary number down.
+#[test]
+fn million_iteration_loop() {
+    assert_eq!(run(&countdown("count := !count + 1")).stdout, "1001000\n");
+}
+
+/// Each iteration allocates a pair, a thousand times the single page of memory a wasm program
+/// starts with.
+#[test]
+fn allocating_loop() {
+    assert_eq!(
+        run(&countdown("count := (!count + 1, p).0")).stdout,
+        "1001000\n"
+    );
+}
@@ -0,0 +1,31 @@
+//! Projections out of tuples whose width is only known from later uses.
+mod common;
+
+use common::run;
+use simply_typed_lambda_calculus_compiler::{compile, typeinfer::TypeError, CompileError};
+
+#[test]
+fn width_fixed_by_the_argument() {
+    assert_eq!(run("(\\p. p.0) (1, 2, 3)").stdout, "1\n");
+    assert_eq!(run("(\\p. p.2) (1, 2, 3)").stdout, "3\n");
+    assert_eq!(
+        run("let third (p : int * int * int) = p.2;;\nthird (1, 2, 3)").stdout,
+        "3\n"
+    );
+}
+
+#[test]
+fn fst_and_snd_of_a_pair_by_default() {
+    assert_eq!(
+        run("let first p = fst p;;\nfirst (1, 2) + snd (3, 4)").stdout,
+        "5\n"
+    );
+}
+
+#[test]
+fn unknown_width_is_an_error() {
+    match compile("let third p = p.2;;\n()", Default::default()) {
+        Err(CompileError::Type(err)) => assert_eq!(err, TypeError::UnknownWidth(2)),
+        result => panic!("{:?}", result.map(|artifact| artifact.ty)),
+    }
+}